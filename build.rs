@@ -0,0 +1,24 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+/// Builds the example AI script next to the game, so the AI modes work without building it by hand.
+/// Windows uses the prebuilt `ai-example/windows/ai.dll` instead.
+fn main() {
+    println!("cargo:rerun-if-changed=ai-example/ai.rs");
+    if env::var("CARGO_CFG_WINDOWS").is_ok() {
+        return;
+    }
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let target = env::var("TARGET").unwrap();
+    let status = Command::new(rustc)
+        .args(["--edition", "2018", "--crate-type", "cdylib", "-O"])
+        .args(["--target", &target])
+        .arg("ai-example/ai.rs")
+        .arg("-o")
+        .arg(Path::new(&out_dir).join("libai.so"))
+        .status()
+        .expect("could not run rustc to build the example AI");
+    assert!(status.success(), "could not build the example AI");
+}
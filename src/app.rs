@@ -1,10 +1,16 @@
-use crate::game_state::Game;
+use crate::game_state::chat::MAX_CHAT_LEN;
+use crate::game_state::lobby::{LobbyClient, LOBBY_PORT};
+use crate::game_state::protocol::{RoomMode, RoomSettings};
+use crate::game_state::{Game, Mode, Setup, DEFAULT_AI, DEFAULT_PORT, MAX_PLAYERS, MIN_PLAYERS};
 
 use ggez::event::{self, KeyCode, KeyMods};
-use ggez::graphics::{self, Color, DrawMode, Font, Mesh, MeshBuilder, Rect, Scale, Text};
+use ggez::graphics::{
+    self, Align, Color, DrawMode, DrawParam, Font, Mesh, MeshBuilder, Rect, Scale, Text,
+};
 
 use ggez::{Context, GameResult};
 use graphics::TextFragment;
+use std::time::{Duration, Instant};
 
 /// size of the window
 pub const SCREEN_SIZE: (f32, f32) = (800.0, 600.0);
@@ -19,25 +25,16 @@ pub const BLOCK_SIZE: (f32, f32) = (20.0, 20.0);
 /// Size of the scaled-down blocks
 const SMALL_BLOCK_SIZE: (f32, f32) = (BLOCK_SIZE.0 * 0.5, BLOCK_SIZE.1 * 0.5);
 
-/// The top-left corner of the boards
+/// Size of a board at full scale
+pub const BOARD_SIZE: (f32, f32) = (
+    (GRID_SIZE.0 as f32) * BLOCK_SIZE.0,
+    (GRID_SIZE.1 as f32) * BLOCK_SIZE.1,
+);
+
+/// The top-left corner of the boards in a two player match
 pub const P1_BOARD_PLACEMENT: (f32, f32) = (100.0, 100.0);
 pub const P2_BOARD_PLACEMENT: (f32, f32) = (SCREEN_SIZE.0 / 2.0 + 100.0, 100.0);
 
-/// The x y w h of the boards
-pub const P1_BOARD: (f32, f32, f32, f32) = (
-    P1_BOARD_PLACEMENT.0,                // x
-    P1_BOARD_PLACEMENT.1,                // y
-    (GRID_SIZE.0 as f32) * BLOCK_SIZE.0, // width
-    (GRID_SIZE.1 as f32) * BLOCK_SIZE.0, // height
-);
-/// The x y w h of the boards
-pub const P2_BOARD: (f32, f32, f32, f32) = (
-    P2_BOARD_PLACEMENT.0,                // x
-    P2_BOARD_PLACEMENT.1,                // y
-    (GRID_SIZE.0 as f32) * BLOCK_SIZE.0, // width
-    (GRID_SIZE.1 as f32) * BLOCK_SIZE.0, // height
-);
-
 // for the next piece and saved piece boxes
 const INFO_BOX: (f32, f32) = (SMALL_BLOCK_SIZE.0 * 6.0, SMALL_BLOCK_SIZE.1 * 6.0);
 const INFO_BOX_MARGIN: (f32, f32) = (SMALL_BLOCK_SIZE.0, SMALL_BLOCK_SIZE.1);
@@ -45,15 +42,34 @@ const INFO_BOX_MARGIN: (f32, f32) = (SMALL_BLOCK_SIZE.0, SMALL_BLOCK_SIZE.1);
 // size of the attack meter increments
 const ATTACK_METER: (f32, f32) = (BLOCK_SIZE.0 / 2.0, BLOCK_SIZE.1);
 
-// the center of the score text
-const P1_SCORE_PLACEMENT: (f32, f32) = (
-    P1_BOARD.0 + P1_BOARD.2 / 2.0,
-    P1_BOARD.1 + P1_BOARD.3 + 30.0,
+// the center of the score text below a board and the name above it, from the board's corner
+const SCORE_OFFSET: f32 = 30.0;
+const NAME_OFFSET: f32 = 30.0;
+
+/// What a board takes up with its boxes, name and score, from the top-left corner of the boxes and the name
+const BOARD_FOOTPRINT: (f32, f32) = (
+    BOARD_SIZE.0 + 2.0 * INFO_BOX.0,
+    BOARD_SIZE.1 + NAME_OFFSET + SCORE_OFFSET + 20.0,
+);
+/// Where the other players' boards go when there are more than two players, ours is drawn as in a two player match
+const OTHERS_AREA: (f32, f32, f32, f32) = (SCREEN_SIZE.0 / 2.0, 20.0, SCREEN_SIZE.0 / 2.0, 480.0);
+
+// the chat input box, between the bottoms of the boards with the log above it
+const CHAT_BOX: (f32, f32, f32, f32) = (
+    P1_BOARD_PLACEMENT.0 + BOARD_SIZE.0 + 10.0,
+    P1_BOARD_PLACEMENT.1 + BOARD_SIZE.1 - 20.0,
+    P2_BOARD_PLACEMENT.0 - P1_BOARD_PLACEMENT.0 - BOARD_SIZE.0 - 20.0,
+    20.0,
 );
-const P2_SCORE_PLACEMENT: (f32, f32) = (
-    P2_BOARD.0 + P2_BOARD.2 / 2.0,
-    P2_BOARD.1 + P2_BOARD.3 + 30.0,
+// with more than two players it's below the other boards instead
+const OTHERS_CHAT_BOX: (f32, f32, f32, f32) = (
+    OTHERS_AREA.0 + 10.0,
+    SCREEN_SIZE.1 - 30.0,
+    OTHERS_AREA.2 - 20.0,
+    20.0,
 );
+/// Lines of the chat log shown at once
+const CHAT_LINES: usize = 4;
 
 const BACKGROUND_COLOR: Color = Color::new(25.0 / 255.0, 172.0 / 255.0, 244.0 / 255.0, 1.0);
 const BOARD_BACKGROUND: Color = Color::new(0.0, 0.0, 0.0, 0.8);
@@ -80,344 +96,891 @@ pub const GHOST_PALETTE: [Color; 7] = [
     Color::new(255.0 / 255.0, 127.0 / 255.0, 0.0 / 255.0, 0.3), // Orange
 ];
 
-const INIT_LEVEL: usize = 5;
+/// Where a board is drawn and how big
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoardLayout {
+    pub x: f32, // the top-left corner of the board itself
+    pub y: f32,
+    pub scale: f32, // 1.0 is full size
+}
+
+/// The boards of a match between `players`, ours first.
+/// Two players get the boards side by side, with more ours is on the left and the others share the right half.
+pub fn board_layouts(players: usize) -> Vec<BoardLayout> {
+    let full = |(x, y): (f32, f32)| BoardLayout { x, y, scale: 1.0 };
+    let mut layouts = vec![full(P1_BOARD_PLACEMENT)];
+    if players <= 2 {
+        layouts.push(full(P2_BOARD_PLACEMENT));
+        return layouts;
+    }
+    let others = players - 1;
+    let cols = (others as f32).sqrt().ceil() as usize;
+    let rows = others.div_ceil(cols);
+    let scale = (OTHERS_AREA.2 / (cols as f32 * BOARD_FOOTPRINT.0))
+        .min(OTHERS_AREA.3 / (rows as f32 * BOARD_FOOTPRINT.1));
+    for n in 0..others {
+        let (col, row) = ((n % cols) as f32, (n / cols) as f32);
+        layouts.push(BoardLayout {
+            x: OTHERS_AREA.0 + (col * BOARD_FOOTPRINT.0 + INFO_BOX.0) * scale,
+            y: OTHERS_AREA.1 + (row * BOARD_FOOTPRINT.1 + NAME_OFFSET + 20.0) * scale,
+            scale,
+        });
+    }
+    layouts
+}
+
+/// How often the rooms of a lobby are asked for again
+const LOBBY_REFRESH: Duration = Duration::from_secs(2);
+
+/// The modes on the start screen and the keys that pick them
+const MENU: [(KeyCode, &str); 6] = [
+    (KeyCode::Key1, "Two players on one keyboard"),
+    (KeyCode::Key2, "Player vs AI"),
+    (KeyCode::Key3, "AI vs AI"),
+    (KeyCode::Key4, "Host a network match"),
+    (KeyCode::Key5, "Join a network match"),
+    (KeyCode::Key6, "Browse a lobby"),
+];
+
+/// The rooms of a lobby, shown instead of the start screen
+struct LobbyScreen {
+    client: LobbyClient, // kept open while we host a room, it's closed with the connection
+    selected: usize,
+    message: Option<String>, // why the last request failed
+    last_refresh: Instant,
+}
 
 // contains fields like the game struct, ai-script, etc. Basically stores the game-state + resources
 pub struct AppState {
-    game_state: Game,
+    game_state: Option<Game>,     // not started while the start screen is shown
+    menu_message: Option<String>, // why the last picked mode couldn't start
+    setup: Setup,                 // what was given on the command line
     block_palatte: [Mesh; 15],
     grid_mesh: Mesh,
     small_block_palatte: [Mesh; 8],
     font: Font,
+    chat_input: Option<String>, // what's being typed while the chat box is open
+    chat_notice: Option<String>, // why the last line couldn't be sent
+    chat_scroll: usize,         // lines scrolled back from the newest
+    show_net_stats: bool,       // F3 shows the state of the connection over the match
+    lobby: Option<LobbyScreen>, // shown while no game is started
 }
 
 impl AppState {
-    pub fn new(ctx: &mut Context) -> AppState {
-        let state = AppState {
+    /// Shows the game if it's already started, the start screen otherwise
+    pub fn new(ctx: &mut Context, setup: Setup, game: Option<Game>) -> AppState {
+        let mut state = AppState {
             // Load/create resources here: images, fonts, sounds, etc.
-            game_state: Game::new(5),
+            game_state: game,
+            menu_message: None,
+            setup,
             block_palatte: generate_blocks(ctx),
             grid_mesh: generate_grid_mesh(ctx).expect("grid mesh err"),
             small_block_palatte: generate_small_blocks(ctx),
             font: Font::new(ctx, "/Roboto-Regular.ttf").expect("font loading error"),
+            chat_input: None,
+            chat_notice: None,
+            show_net_stats: false,
+            chat_scroll: 0,
+            lobby: None,
         };
+        if state.game_state.is_none() && state.setup.lobby.is_some() {
+            state.open_lobby();
+        }
         state
     }
-}
 
-impl event::EventHandler for AppState {
-    // update the game logic
-    fn update(&mut self, _ctx: &mut Context) -> GameResult {
-        let [p1_lost, p2_lost] = self.game_state.get_losts();
+    /// Connects to the lobby given on the command line, or one on this machine
+    fn open_lobby(&mut self) {
+        let adress = self
+            .setup
+            .lobby
+            .clone()
+            .unwrap_or_else(|| format!("127.0.0.1:{}", LOBBY_PORT));
+        match LobbyClient::connect(&adress, &self.setup.name) {
+            Ok(client) => {
+                self.lobby = Some(LobbyScreen {
+                    client,
+                    selected: 0,
+                    message: None,
+                    last_refresh: Instant::now(),
+                })
+            }
+            Err(err) => {
+                self.menu_message =
+                    Some(format!("could not reach the lobby at {}: {}", adress, err))
+            }
+        }
+    }
 
-        if p1_lost || p2_lost {
-            // if either player has lost
-        } else {
-            self.game_state.update();
+    /// Handles a key on the lobby screen
+    fn lobby_key(&mut self, keycode: KeyCode) {
+        if keycode == KeyCode::Escape {
+            self.lobby = None;
+            return;
         }
-        Ok(())
+        let (setup, game_state) = (&self.setup, &mut self.game_state);
+        let lobby = match &mut self.lobby {
+            Some(lobby) => lobby,
+            None => return,
+        };
+        let rooms = lobby.client.get_rooms().len();
+        let result = match keycode {
+            KeyCode::Up => {
+                lobby.selected = lobby.selected.saturating_sub(1);
+                return;
+            }
+            KeyCode::Down => {
+                lobby.selected = (lobby.selected + 1).min(rooms.saturating_sub(1));
+                return;
+            }
+            KeyCode::R | KeyCode::F5 => {
+                lobby.last_refresh = Instant::now();
+                lobby
+                    .client
+                    .list()
+                    .map(|_| ())
+                    .map_err(|err| err.to_string())
+            }
+            KeyCode::Return if lobby.selected < rooms => {
+                let id = lobby.client.get_rooms()[lobby.selected].id;
+                lobby
+                    .client
+                    .join(id)
+                    .map_err(|err| err.to_string())
+                    .and_then(|adress| Game::start_mode(Mode::Join(Some(adress)), setup))
+                    .map(|game| *game_state = Some(game))
+            }
+            KeyCode::C => {
+                let settings = RoomSettings {
+                    name: format!("{}'s room", setup.name),
+                    mode: RoomMode::Hosted,
+                    port: DEFAULT_PORT,
+                    start_level: setup.start_level as u8,
+                    players: setup.players,
+                };
+                lobby
+                    .client
+                    .create(settings)
+                    .map_err(|err| err.to_string())
+                    .and_then(|_| Game::start_mode(Mode::Host(None), setup))
+                    .map(|game| *game_state = Some(game))
+            }
+            _ => return,
+        };
+        lobby.message = result.err();
     }
 
-    // update the graphics
-    fn draw(&mut self, ctx: &mut Context) -> GameResult {
-        // Clear screen with the background color
-        graphics::clear(ctx, BACKGROUND_COLOR);
+    /// Starts the mode picked on the start screen
+    fn pick_mode(&mut self, keycode: KeyCode) {
+        let mut setup = self.setup.clone();
+        let ours = setup.ai[0].clone();
+        // the AI scripts given on the command line are used, the example otherwise
+        let mut script = |n: usize| {
+            let script = setup.ai[n].take();
+            Some(script.unwrap_or_else(|| DEFAULT_AI.to_string()))
+        };
+        let (mode, ai) = match keycode {
+            KeyCode::Key1 => (Mode::Local, [None, None]),
+            KeyCode::Key2 => (Mode::Local, [None, script(1)]),
+            KeyCode::Key3 => (Mode::Local, [script(0), script(1)]),
+            KeyCode::Key4 => (Mode::Host(None), [ours, None]),
+            KeyCode::Key5 => (Mode::Join(None), [ours, None]),
+            _ => return,
+        };
+        setup.ai = ai;
+        match Game::start_mode(mode, &setup) {
+            Ok(game) => self.game_state = Some(game),
+            Err(err) => self.menu_message = Some(err),
+        }
+    }
 
-        // draw boards
-        let rectangle = Mesh::new_rectangle(
+    /// Whether the chat is shown: between matches, or during them too if it was asked for
+    fn shows_chat(&self) -> bool {
+        match &self.game_state {
+            Some(game) => {
+                !game.is_local()
+                    && game.get_disconnected().is_none()
+                    && (game.is_over() || self.setup.chat_in_game)
+            }
+            None => false,
+        }
+    }
+
+    /// Handles a key while the chat box is open, nothing reaches the game until it's closed
+    fn chat_key(&mut self, keycode: KeyCode) {
+        match keycode {
+            KeyCode::Return => {
+                let text = self.chat_input.take().unwrap_or_default();
+                if let Some(game) = &mut self.game_state {
+                    self.chat_notice = game.send_chat(&text).err();
+                    if self.chat_notice.is_some() {
+                        // kept so it can be sent once the rate limit allows it
+                        self.chat_input = Some(text);
+                    }
+                }
+                self.chat_scroll = 0;
+            }
+            KeyCode::Escape => self.chat_input = None,
+            KeyCode::Back => {
+                if let Some(input) = &mut self.chat_input {
+                    input.pop();
+                }
+            }
+            KeyCode::PageUp => self.chat_scroll += 1,
+            KeyCode::PageDown => self.chat_scroll = self.chat_scroll.saturating_sub(1),
+            _ => (),
+        }
+    }
+
+    /// Draws what's known about the connections in the top left corner
+    fn draw_net_stats(&self, ctx: &mut Context, game: &Game) -> GameResult {
+        let stats = game.get_net_stats();
+        let millis = |time: Option<Duration>| {
+            time.map_or("-".to_string(), |time| format!("{} ms", time.as_millis()))
+        };
+        let traffic = stats.per_second;
+        let check = match stats.last_check {
+            Some(check) if check.matched => format!("in sync at tick {}", check.tick),
+            Some(check) => format!("DESYNC at tick {}", check.tick),
+            None => "not checked yet".to_string(),
+        };
+        let lines = format!(
+            "ping {}  jitter {}
+sent {} msg/s  {} B/s
+received {} msg/s  {} B/s
+input delay {} ticks  queued inputs {}
+checksums {}",
+            millis(stats.ping),
+            millis(stats.jitter),
+            traffic.messages_sent,
+            traffic.bytes_sent,
+            traffic.messages_received,
+            traffic.bytes_received,
+            stats.input_delay,
+            stats.queued_inputs,
+            check
+        );
+        let stats_text = Text::new(
+            TextFragment::new(lines)
+                .font(self.font)
+                .scale(Scale { x: 14.0, y: 14.0 }),
+        );
+        let dimensions = stats_text.dimensions(ctx);
+        let background = Mesh::new_rectangle(
             ctx,
             DrawMode::fill(),
-            Rect::new_i32(0, 0, P1_BOARD.2 as i32, P1_BOARD.3 as i32),
-            BOARD_BACKGROUND,
+            Rect::new(
+                0.0,
+                0.0,
+                dimensions.0 as f32 + 10.0,
+                dimensions.1 as f32 + 10.0,
+            ),
+            Color::new(0.0, 0.0, 0.0, 0.7),
         )?;
+        graphics::draw(ctx, &background, (ggez::mint::Point2 { x: 0.0, y: 0.0 },))?;
+        graphics::draw(ctx, &stats_text, (ggez::mint::Point2 { x: 5.0, y: 5.0 },))
+    }
 
+    /// Draws the last lines of the chat log and the input box below them
+    fn draw_chat(&self, ctx: &mut Context, game: &Game) -> GameResult {
+        let chat_box = if game.get_names().len() > 2 {
+            OTHERS_CHAT_BOX
+        } else {
+            CHAT_BOX
+        };
+        let lines: Vec<_> = game.get_chat().collect();
+        let scroll = self.chat_scroll.min(lines.len().saturating_sub(CHAT_LINES));
+        let end = lines.len() - scroll;
+        let mut log_text = Text::default();
+        for line in &lines[end.saturating_sub(CHAT_LINES)..end] {
+            log_text.add(
+                TextFragment::new(format!("{}: {}\n", line.name, line.text))
+                    .font(self.font)
+                    .scale(Scale { x: 14.0, y: 14.0 }),
+            );
+        }
+        if let Some(notice) = &self.chat_notice {
+            log_text.add(
+                TextFragment::new(notice.as_str())
+                    .font(self.font)
+                    .color(Color::new(1.0, 0.85, 0.85, 1.0))
+                    .scale(Scale { x: 14.0, y: 14.0 }),
+            );
+        }
+        log_text.set_bounds([chat_box.2, f32::INFINITY], Align::Left);
+        let dimensions = log_text.dimensions(ctx);
         graphics::draw(
             ctx,
-            &rectangle,
+            &log_text,
             (ggez::mint::Point2 {
-                x: P1_BOARD.0,
-                y: P1_BOARD.1,
+                x: chat_box.0,
+                y: chat_box.1 - dimensions.1 as f32 - 5.0,
             },),
         )?;
 
+        let input_box = Mesh::new_rectangle(
+            ctx,
+            DrawMode::fill(),
+            Rect::new(chat_box.0, chat_box.1, chat_box.2, chat_box.3),
+            BOARD_BACKGROUND,
+        )?;
+        graphics::draw(ctx, &input_box, (ggez::mint::Point2 { x: 0.0, y: 0.0 },))?;
+        let input = match &self.chat_input {
+            // the end of the line when it doesn't fit
+            Some(input) => {
+                let shown: Vec<char> = input.chars().rev().take(28).collect();
+                format!("> {}_", shown.into_iter().rev().collect::<String>())
+            }
+            None if game.is_spectator() => String::new(),
+            None => "Return to chat".to_string(),
+        };
+        let input_text = Text::new(
+            TextFragment::new(input)
+                .font(self.font)
+                .scale(Scale { x: 14.0, y: 14.0 }),
+        );
+        let dimensions = input_text.dimensions(ctx);
         graphics::draw(
             ctx,
-            &rectangle,
+            &input_text,
             (ggez::mint::Point2 {
-                x: P2_BOARD.0,
-                y: P2_BOARD.1,
+                x: chat_box.0 + 4.0,
+                y: chat_box.1 + (chat_box.3 - dimensions.1 as f32) / 2.0,
             },),
-        )?;
+        )
+    }
+
+    /// Draws the board of the player at `index` with its boxes, attack meter, name and score
+    fn draw_board(
+        &self,
+        ctx: &mut Context,
+        game: &Game,
+        index: usize,
+        layout: BoardLayout,
+    ) -> GameResult {
+        let scale = layout.scale;
+        // draws a mesh with its corner at (x, y) from the board's corner, in full-size pixels
+        let place = |x: f32, y: f32| {
+            DrawParam::new()
+                .dest(ggez::mint::Point2 {
+                    x: layout.x + x * scale,
+                    y: layout.y + y * scale,
+                })
+                .scale([scale, scale])
+        };
 
-        // draw next piece boxes
+        // the board, the next piece box to the right and the saved piece box to the left
+        let rectangle = Mesh::new_rectangle(
+            ctx,
+            DrawMode::fill(),
+            Rect::new_i32(0, 0, BOARD_SIZE.0 as i32, BOARD_SIZE.1 as i32),
+            BOARD_BACKGROUND,
+        )?;
+        graphics::draw(ctx, &rectangle, place(0.0, 0.0))?;
         let info_box = Mesh::new_rectangle(
             ctx,
             DrawMode::fill(),
             Rect::new_i32(0, 0, INFO_BOX.0 as i32, INFO_BOX.1 as i32),
             BOARD_BACKGROUND,
         )?;
+        graphics::draw(ctx, &info_box, place(BOARD_SIZE.0, 0.0))?;
+        graphics::draw(ctx, &info_box, place(-INFO_BOX.0, 0.0))?;
 
-        graphics::draw(
+        // the next and saved pieces
+        let pieces = [
+            (game.get_next_pieces()[index], BOARD_SIZE.0),
+            (game.get_saved_pieces()[index], -INFO_BOX.0),
+        ];
+        for (piece, box_x) in pieces.iter() {
+            for (y, row) in piece.iter().enumerate() {
+                for (x, block) in row.iter().enumerate() {
+                    if *block > 0 {
+                        graphics::draw(
+                            ctx,
+                            &self.small_block_palatte[*block as usize - 1],
+                            place(
+                                x as f32 * SMALL_BLOCK_SIZE.0 + box_x + INFO_BOX_MARGIN.0,
+                                y as f32 * SMALL_BLOCK_SIZE.1 + INFO_BOX_MARGIN.1,
+                            ),
+                        )?
+                    }
+                }
+            }
+        }
+
+        // the blocks
+        let board = game.get_boards()[index];
+        // the rows above the top of the board aren't shown
+        for (y, row) in board.iter().enumerate().take(board.len() - 4) {
+            for (x, block) in row.iter().enumerate() {
+                if *block > 0 {
+                    graphics::draw(
+                        ctx,
+                        &self.block_palatte[*block as usize - 1],
+                        place(
+                            (x as f32) * BLOCK_SIZE.0,
+                            BOARD_SIZE.1 - ((y as f32) + 1.0) * BLOCK_SIZE.1,
+                        ),
+                    )?;
+                }
+            }
+        }
+
+        // the attack meter
+        let meter = Mesh::new_rectangle(
             ctx,
-            &info_box,
-            (ggez::mint::Point2 {
-                x: P1_BOARD.0 + P1_BOARD.2,
-                y: P1_BOARD.1,
-            },),
+            DrawMode::fill(),
+            Rect::new_i32(0, 0, ATTACK_METER.0 as i32, ATTACK_METER.1 as i32),
+            PALETTE[7],
         )?;
+        for i in 1..(game.get_attackbars()[index] + 1) {
+            graphics::draw(
+                ctx,
+                &meter,
+                place(-ATTACK_METER.0, BOARD_SIZE.1 - i as f32 * ATTACK_METER.1),
+            )?;
+        }
 
+        graphics::draw(ctx, &self.grid_mesh, place(0.0, 0.0))?;
+
+        // a board that's out of a match with more players is covered
+        let players = game.get_names().len();
+        if players > 2 && game.get_losts()[index] {
+            let cover = Mesh::new_rectangle(
+                ctx,
+                DrawMode::fill(),
+                Rect::new_i32(0, 0, BOARD_SIZE.0 as i32, BOARD_SIZE.1 as i32),
+                Color::new(0.0, 0.0, 0.0, 0.6),
+            )?;
+            graphics::draw(ctx, &cover, place(0.0, 0.0))?;
+            self.draw_centered(
+                ctx,
+                "KO",
+                80.0 * scale,
+                place(BOARD_SIZE.0 / 2.0, BOARD_SIZE.1 / 2.0),
+            )?;
+        }
+
+        // what the last clear was, over the top of the board
+        if let Some(clear) = game.get_callouts()[index] {
+            self.draw_centered(
+                ctx,
+                &clear.name(),
+                22.0 * scale,
+                place(BOARD_SIZE.0 / 2.0, BOARD_SIZE.1 / 4.0),
+            )?;
+        }
+
+        // the combo and whether the next difficult clear is back-to-back, under the saved piece
+        let combo = game.get_combos()[index];
+        if combo > 0 {
+            self.draw_centered(
+                ctx,
+                &format!("{} COMBO", combo),
+                14.0 * scale,
+                place(-INFO_BOX.0 / 2.0, INFO_BOX.1 + 20.0),
+            )?;
+        }
+        if game.get_back_to_backs()[index] {
+            self.draw_centered(
+                ctx,
+                "B2B",
+                14.0 * scale,
+                place(-INFO_BOX.0 / 2.0, INFO_BOX.1 + 40.0),
+            )?;
+        }
+
+        // the name above and the score below, with the team and the knockouts when there's more than one opponent
+        let mut name = game.get_names()[index].clone();
+        if game.is_team_match() {
+            name = format!("Team {}: {}", game.get_teams()[index] + 1, name);
+        }
+        if players > 2 {
+            name = format!("{}  {} KO", name, game.get_knockouts()[index]);
+        }
+        self.draw_centered(
+            ctx,
+            &name,
+            25.0 * scale,
+            place(BOARD_SIZE.0 / 2.0, -NAME_OFFSET),
+        )?;
+        let score = game.get_scores()[index].to_string();
+        self.draw_centered(
+            ctx,
+            &score,
+            25.0 * scale,
+            place(BOARD_SIZE.0 / 2.0, BOARD_SIZE.1 + SCORE_OFFSET),
+        )
+    }
+
+    /// Draws a line of text centered on the destination of `param`
+    fn draw_centered(
+        &self,
+        ctx: &mut Context,
+        text: &str,
+        size: f32,
+        param: DrawParam,
+    ) -> GameResult {
+        let text = Text::new(
+            TextFragment::new(text)
+                .font(self.font)
+                .scale(Scale { x: size, y: size }),
+        );
+        let dimensions = text.dimensions(ctx);
         graphics::draw(
             ctx,
-            &info_box,
+            &text,
             (ggez::mint::Point2 {
-                x: P2_BOARD.0 + P2_BOARD.2,
-                y: P2_BOARD.1,
+                x: param.dest.x - (dimensions.0 as f32) / 2.0,
+                y: param.dest.y - (dimensions.1 as f32) / 2.0,
             },),
-        )?;
+        )
+    }
 
-        // draw saved piece boxes
+    /// Draws the rooms of the lobby, the selected one marked
+    fn draw_lobby(&self, ctx: &mut Context) -> GameResult {
+        let lobby = match &self.lobby {
+            Some(lobby) => lobby,
+            None => return Ok(()),
+        };
+        let line = |text: String, size: f32| {
+            TextFragment::new(text)
+                .font(self.font)
+                .scale(Scale { x: size, y: size })
+        };
+        let mut text = Text::new(line("Lobby\n".to_string(), 40.0));
+        let rooms = lobby.client.get_rooms();
+        if rooms.is_empty() {
+            text.add(line(
+                "\nNo open rooms, press C to open one".to_string(),
+                20.0,
+            ));
+        }
+        for (n, room) in rooms.iter().enumerate() {
+            let marker = if n == lobby.selected { ">" } else { " " };
+            let mode = match room.settings.mode {
+                RoomMode::Hosted => "hosted by",
+                RoomMode::Server => "server of",
+            };
+            text.add(line(
+                format!(
+                    "\n{} {}  {} {}  {}/{} players, level {}",
+                    marker,
+                    room.settings.name,
+                    mode,
+                    room.owner,
+                    room.joined,
+                    room.settings.players,
+                    room.settings.start_level
+                ),
+                20.0,
+            ));
+        }
+        text.add(line(
+            "\n\nUp/Down to pick, Return to join, C to open a room, R to refresh, Escape to go back"
+                .to_string(),
+            16.0,
+        ));
+        if let Some(message) = &lobby.message {
+            text.add(line(format!("\n\n{}", message), 18.0));
+        }
+        let dimensions = text.dimensions(ctx);
         graphics::draw(
             ctx,
-            &info_box,
+            &text,
             (ggez::mint::Point2 {
-                x: P1_BOARD.0 - INFO_BOX.0,
-                y: P1_BOARD.1,
+                x: SCREEN_SIZE.0 / 2.0 - (dimensions.0 as f32) / 2.0,
+                y: SCREEN_SIZE.1 / 2.0 - (dimensions.1 as f32) / 2.0,
             },),
         )?;
+        graphics::present(ctx)
+    }
+
+    /// Draws the start screen
+    fn draw_menu(&self, ctx: &mut Context) -> GameResult {
+        let mut menu_text = Text::new(
+            TextFragment::new("Tetris\n")
+                .font(self.font)
+                .scale(Scale { x: 60.0, y: 60.0 }),
+        );
+        for (n, (_, mode)) in MENU.iter().enumerate() {
+            menu_text.add(
+                TextFragment::new(format!("\n{}  {}", n + 1, mode))
+                    .font(self.font)
+                    .scale(Scale { x: 25.0, y: 25.0 }),
+            );
+        }
+        let teams = match self.setup.teams {
+            0 | 1 => "off".to_string(),
+            teams if self.setup.split_garbage => format!("{}, garbage split", teams),
+            teams => teams.to_string(),
+        };
+        menu_text.add(
+            TextFragment::new(format!(
+                "\n\nPlayers: {}  (Left/Right to change)\nTeams: {}  (Up/Down to change, G to split garbage)\nPieces: {}  (B to change)",
+                self.setup.players, teams, self.setup.randomizer.name()
+            ))
+            .font(self.font)
+            .scale(Scale { x: 18.0, y: 18.0 }),
+        );
+        if let Some(message) = &self.menu_message {
+            menu_text.add(
+                TextFragment::new(format!("\n\n{}", message))
+                    .font(self.font)
+                    .scale(Scale { x: 18.0, y: 18.0 }),
+            );
+        }
+        let dimensions = menu_text.dimensions(ctx);
 
         graphics::draw(
             ctx,
-            &info_box,
+            &menu_text,
             (ggez::mint::Point2 {
-                x: P2_BOARD.0 - INFO_BOX.0,
-                y: P2_BOARD.1,
+                x: SCREEN_SIZE.0 / 2.0 - (dimensions.0 as f32) / 2.0,
+                y: SCREEN_SIZE.1 / 2.0 - (dimensions.1 as f32) / 2.0,
             },),
         )?;
+        graphics::present(ctx)
+    }
+}
 
-        // draw next pieces
-        let [p1_next_piece, p2_next_piece] = self.game_state.get_next_pieces();
-
-        for y in 0..p1_next_piece.len() {
-            for x in 0..p1_next_piece[y].len() {
-                if p1_next_piece[y][x] > 0 {
-                    graphics::draw(
-                        ctx,
-                        &self.small_block_palatte[p1_next_piece[y][x] as usize - 1],
-                        (ggez::mint::Point2 {
-                            x: x as f32 * SMALL_BLOCK_SIZE.0
-                                + P1_BOARD.0
-                                + P1_BOARD.2
-                                + INFO_BOX_MARGIN.0,
-                            y: y as f32 * SMALL_BLOCK_SIZE.1 + P1_BOARD.1 + INFO_BOX_MARGIN.1,
-                        },),
-                    )?
+impl event::EventHandler for AppState {
+    // update the game logic
+    fn update(&mut self, _ctx: &mut Context) -> GameResult {
+        let game = match (&mut self.game_state, &mut self.lobby) {
+            (Some(game), _) => game,
+            (None, Some(lobby)) => {
+                if lobby.last_refresh.elapsed() >= LOBBY_REFRESH {
+                    lobby.last_refresh = Instant::now();
+                    lobby.message = lobby.client.list().err().map(|err| err.to_string());
+                    let rooms = lobby.client.get_rooms().len();
+                    lobby.selected = lobby.selected.min(rooms.saturating_sub(1));
                 }
+                return Ok(());
             }
+            (None, None) => return Ok(()),
+        };
+        // the game stops by itself when someone has lost, but keeps listening for a rematch
+        if game.get_disconnected().is_none() {
+            game.update();
         }
-
-        for y in 0..p2_next_piece.len() {
-            for x in 0..p2_next_piece[y].len() {
-                if p2_next_piece[y][x] > 0 {
-                    graphics::draw(
-                        ctx,
-                        &self.small_block_palatte[p2_next_piece[y][x] as usize - 1],
-                        (ggez::mint::Point2 {
-                            x: x as f32 * SMALL_BLOCK_SIZE.0
-                                + P2_BOARD.0
-                                + P2_BOARD.2
-                                + INFO_BOX_MARGIN.0,
-                            y: y as f32 * SMALL_BLOCK_SIZE.1 + P2_BOARD.1 + INFO_BOX_MARGIN.1,
-                        },),
-                    )?
-                }
-            }
+        // a match started or the connection is gone, the keys belong to the game again
+        if !self.shows_chat() {
+            self.chat_input = None;
         }
+        Ok(())
+    }
 
-        // draw saved pieces
-        let [p1_saved_piece, p2_saved_piece] = self.game_state.get_saved_pieces();
+    // update the graphics
+    fn draw(&mut self, ctx: &mut Context) -> GameResult {
+        // Clear screen with the background color
+        graphics::clear(ctx, BACKGROUND_COLOR);
 
-        for y in 0..p1_saved_piece.len() {
-            for x in 0..p1_saved_piece[y].len() {
-                if p1_saved_piece[y][x] > 0 {
-                    graphics::draw(
-                        ctx,
-                        &self.small_block_palatte[p1_saved_piece[y][x] as usize - 1],
-                        (ggez::mint::Point2 {
-                            x: x as f32 * SMALL_BLOCK_SIZE.0 + P1_BOARD.0 - INFO_BOX.0
-                                + INFO_BOX_MARGIN.0,
-                            y: y as f32 * SMALL_BLOCK_SIZE.1 + P1_BOARD.1 + INFO_BOX_MARGIN.1,
-                        },),
-                    )?
-                }
-            }
-        }
+        let game = match &self.game_state {
+            Some(game) => game,
+            None if self.lobby.is_some() => return self.draw_lobby(ctx),
+            None => return self.draw_menu(ctx),
+        };
 
-        for y in 0..p2_saved_piece.len() {
-            for x in 0..p2_saved_piece[y].len() {
-                if p2_saved_piece[y][x] > 0 {
-                    graphics::draw(
-                        ctx,
-                        &self.small_block_palatte[p2_saved_piece[y][x] as usize - 1],
-                        (ggez::mint::Point2 {
-                            x: x as f32 * SMALL_BLOCK_SIZE.0 + P2_BOARD.0 - INFO_BOX.0
-                                + INFO_BOX_MARGIN.0,
-                            y: y as f32 * SMALL_BLOCK_SIZE.1 + P2_BOARD.1 + INFO_BOX_MARGIN.1,
-                        },),
-                    )?
-                }
-            }
+        let names = game.get_names();
+        let layouts = board_layouts(names.len());
+        for (index, layout) in layouts.iter().enumerate() {
+            self.draw_board(ctx, game, index, *layout)?;
         }
-        let boards = self.game_state.get_boards();
-        let p1_board = boards[0];
-        let p2_board = boards[1];
 
-        // draw blocks
-        for y in 0..(p1_board.len() - 4) {
-            for x in 0..p1_board[y].len() {
-                if p1_board[y][x] > 0 {
-                    graphics::draw(
-                        ctx,
-                        &self.block_palatte[p1_board[y][x] as usize - 1],
-                        (ggez::mint::Point2 {
-                            x: P1_BOARD.0 + (x as f32) * BLOCK_SIZE.0,
-                            y: P1_BOARD.1 + P1_BOARD.3 - ((y as f32) + 1.0) * BLOCK_SIZE.1,
-                        },),
-                    )
-                    .expect("msg");
-                }
-            }
+        // who won once at most one player, or one team, is left
+        let teams = game.get_teams();
+        let team_match = game.is_team_match();
+        if game.is_over() {
+            let result = match (game.get_winner(), team_match) {
+                (Some(winner), true) => format!("Team {} wins!", teams[winner] + 1),
+                (Some(winner), false) => format!("{} wins!", names[winner]),
+                (None, _) => "Draw!".to_string(),
+            };
+            let result_text = Text::new(
+                TextFragment::new(result)
+                    .font(self.font)
+                    .scale(Scale { x: 100.0, y: 100.0 }),
+            );
+            let dimensions = result_text.dimensions(ctx);
+
+            graphics::draw(
+                ctx,
+                &result_text,
+                (ggez::mint::Point2 {
+                    x: SCREEN_SIZE.0 / 2.0 - (dimensions.0 as f32) / 2.0,
+                    y: SCREEN_SIZE.1 / 2.0 - (dimensions.1 as f32) / 2.0,
+                },),
+            )?;
         }
 
-        for y in 0..(p2_board.len() - 4) {
-            for x in 0..p2_board[y].len() {
-                if p2_board[y][x] > 0 {
-                    graphics::draw(
-                        ctx,
-                        &self.block_palatte[p2_board[y][x] as usize - 1],
-                        (ggez::mint::Point2 {
-                            x: P2_BOARD.0 + (x as f32) * BLOCK_SIZE.0,
-                            y: P2_BOARD.1 + P2_BOARD.3 - ((y as f32) + 1.0) * BLOCK_SIZE.1,
-                        },),
-                    )
-                    .expect("msg");
+        // what happens next, and who won the series if it's over
+        if game.is_over() && !game.is_spectator() {
+            let mut next = String::new();
+            match (game.get_series_winner(), team_match) {
+                (Some(winner), true) => {
+                    next += &format!("Team {} wins the series\n", teams[winner] + 1)
                 }
+                (Some(winner), false) => next += &format!("{} wins the series\n", names[winner]),
+                (None, _) => (),
             }
-        }
-
-        // draw attack meters
-        let [p1_meter, p2_meter] = self.game_state.get_attackbars();
-
-        let rectangle = Mesh::new_rectangle(
-            ctx,
-            DrawMode::fill(),
-            Rect::new_i32(0, 0, ATTACK_METER.0 as i32, ATTACK_METER.1 as i32),
-            PALETTE[7],
-        )?;
+            next += &if game.is_local() {
+                "Press R to play again".to_string()
+            } else {
+                let rematch = game.get_rematch();
+                let others = |wanting: bool| -> Vec<&str> {
+                    (1..names.len())
+                        .filter(|n| rematch[*n] == wanting)
+                        .map(|n| names[n].as_str())
+                        .collect()
+                };
+                match (rematch[0], others(true), others(false)) {
+                    (true, _, waiting) if waiting.is_empty() => {
+                        "Starting the rematch...".to_string()
+                    }
+                    (true, _, waiting) => format!("Waiting for {}...", waiting.join(", ")),
+                    (false, wanting, _) if wanting.is_empty() => {
+                        "Press R for a rematch".to_string()
+                    }
+                    (false, wanting, _) if wanting.len() == 1 => {
+                        format!("{} wants a rematch, press R", wanting[0])
+                    }
+                    (false, wanting, _) => {
+                        format!("{} want a rematch, press R", wanting.join(", "))
+                    }
+                }
+            };
+            let next_text = Text::new(
+                TextFragment::new(next)
+                    .font(self.font)
+                    .scale(Scale { x: 25.0, y: 25.0 }),
+            );
+            let dimensions = next_text.dimensions(ctx);
 
-        for i in 1..(p1_meter + 1) {
             graphics::draw(
                 ctx,
-                &rectangle,
+                &next_text,
                 (ggez::mint::Point2 {
-                    x: P1_BOARD.0 - ATTACK_METER.0,
-                    y: P1_BOARD.1 + P1_BOARD.3 - i as f32 * ATTACK_METER.1,
+                    x: SCREEN_SIZE.0 / 2.0 - (dimensions.0 as f32) / 2.0,
+                    y: SCREEN_SIZE.1 / 2.0 + 60.0,
                 },),
             )?;
         }
 
-        for i in 1..(p2_meter + 1) {
+        // the score of the series at the top, by team in team matches where every member has the same score
+        if game.get_best_of() > 1 {
+            let mut wins: Vec<(u8, u32)> = teams
+                .iter()
+                .copied()
+                .zip(game.get_wins().to_vec())
+                .collect();
+            if team_match {
+                wins.sort_by_key(|(team, _)| *team);
+                wins.dedup_by_key(|(team, _)| *team);
+            }
+            let wins: Vec<String> = wins.iter().map(|(_, wins)| wins.to_string()).collect();
+            let series_text = Text::new(
+                TextFragment::new(format!(
+                    "{}\nbest of {}",
+                    wins.join(" - "),
+                    game.get_best_of()
+                ))
+                .font(self.font)
+                .scale(Scale { x: 20.0, y: 20.0 }),
+            );
+            let dimensions = series_text.dimensions(ctx);
+
             graphics::draw(
                 ctx,
-                &rectangle,
+                &series_text,
                 (ggez::mint::Point2 {
-                    x: P2_BOARD.0 - ATTACK_METER.0,
-                    y: P2_BOARD.1 + P2_BOARD.3 - i as f32 * ATTACK_METER.1,
+                    x: SCREEN_SIZE.0 / 2.0 - (dimensions.0 as f32) / 2.0,
+                    y: P1_BOARD_PLACEMENT.1 - NAME_OFFSET - (dimensions.1 as f32) / 2.0,
                 },),
             )?;
         }
 
-        // draw grids
-        graphics::draw(
-            ctx,
-            &self.grid_mesh,
-            (ggez::mint::Point2 {
-                x: P1_BOARD.0,
-                y: P1_BOARD.1,
-            },),
-        )?;
-        graphics::draw(
-            ctx,
-            &self.grid_mesh,
-            (ggez::mint::Point2 {
-                x: P2_BOARD.0,
-                y: P2_BOARD.1,
-            },),
-        )?;
-
-        // draw text
-        let scores = self.game_state.get_scores();
-        let p1_score = TextFragment::new(scores[0].to_string())
-            .font(self.font)
-            .scale(Scale { x: 25.0, y: 25.0 });
-        let p1_score_text = Text::new(p1_score);
-        let p1_dimensions = p1_score_text.dimensions(ctx);
-        let p2_score = TextFragment::new(scores[1].to_string())
-            .font(self.font)
-            .scale(Scale { x: 25.0, y: 25.0 });
-        let p2_score_text = Text::new(p2_score);
-        let p2_dimensions = p1_score_text.dimensions(ctx);
-
-        graphics::draw(
-            ctx,
-            &p1_score_text,
-            (ggez::mint::Point2 {
-                x: P1_SCORE_PLACEMENT.0 - (p1_dimensions.0 as f32) / 2.0,
-                y: P1_SCORE_PLACEMENT.1 - (p1_dimensions.1 as f32) / 2.0,
-            },),
-        )?;
+        // who our garbage goes to, only a choice with more than one opponent
+        if names.len() > 2 && !game.is_spectator() {
+            let targeting_text = Text::new(
+                TextFragment::new(format!("Targeting: {} (Tab)", game.get_targeting().name()))
+                    .font(self.font)
+                    .scale(Scale { x: 15.0, y: 15.0 }),
+            );
+            let dimensions = targeting_text.dimensions(ctx);
 
-        graphics::draw(
-            ctx,
-            &p2_score_text,
-            (ggez::mint::Point2 {
-                x: P2_SCORE_PLACEMENT.0 - (p2_dimensions.0 as f32) / 2.0,
-                y: P2_SCORE_PLACEMENT.1 - (p2_dimensions.1 as f32) / 2.0,
-            },),
-        )?;
+            graphics::draw(
+                ctx,
+                &targeting_text,
+                (ggez::mint::Point2 {
+                    x: 10.0,
+                    y: SCREEN_SIZE.1 - dimensions.1 as f32 - 5.0,
+                },),
+            )?;
+        }
 
-        // if anyone lost draw
-        let [p1_lost, p2_lost] = self.game_state.get_losts();
+        if self.shows_chat() {
+            self.draw_chat(ctx, game)?;
+        }
+        if self.show_net_stats && !game.is_local() {
+            self.draw_net_stats(ctx, game)?;
+        }
 
-        if p1_lost {
-            let p2_win = TextFragment::new("P2 wins!")
-                .font(self.font)
-                .scale(Scale { x: 100.0, y: 100.0 });
-            let p2_win_text = Text::new(p2_win);
-            let dimensions = p2_win_text.dimensions(ctx);
+        // if the opponent is gone draw why
+        if let Some(reason) = game.get_disconnected() {
+            let mut disconnected_text = Text::new(
+                TextFragment::new("Opponent disconnected")
+                    .font(self.font)
+                    .scale(Scale { x: 50.0, y: 50.0 }),
+            );
+            disconnected_text.add(
+                TextFragment::new(format!("\n{}\nPress Esc to quit", reason))
+                    .font(self.font)
+                    .scale(Scale { x: 25.0, y: 25.0 }),
+            );
+            let dimensions = disconnected_text.dimensions(ctx);
 
             graphics::draw(
                 ctx,
-                &p2_win_text,
+                &disconnected_text,
                 (ggez::mint::Point2 {
                     x: SCREEN_SIZE.0 / 2.0 - (dimensions.0 as f32) / 2.0,
                     y: SCREEN_SIZE.1 / 2.0 - (dimensions.1 as f32) / 2.0,
                 },),
             )?;
-        } else if p2_lost {
-            let p1_win = TextFragment::new("P1 wins!")
-                .font(self.font)
-                .scale(Scale { x: 100.0, y: 100.0 });
-            let p1_win_text = Text::new(p1_win);
-            let dimensions = p1_win_text.dimensions(ctx);
+        } else if game.is_reconnecting() {
+            let reconnecting_text = Text::new(
+                TextFragment::new("Connection lost, reconnecting...")
+                    .font(self.font)
+                    .scale(Scale { x: 40.0, y: 40.0 }),
+            );
+            let dimensions = reconnecting_text.dimensions(ctx);
 
             graphics::draw(
                 ctx,
-                &p1_win_text,
+                &reconnecting_text,
                 (ggez::mint::Point2 {
                     x: SCREEN_SIZE.0 / 2.0 - (dimensions.0 as f32) / 2.0,
                     y: SCREEN_SIZE.1 / 2.0 - (dimensions.1 as f32) / 2.0,
                 },),
             )?;
+        } else if let Some(ping) = game.get_ping() {
+            let ping_text = Text::new(
+                TextFragment::new(format!("ping {} ms", ping.as_millis()))
+                    .font(self.font)
+                    .scale(Scale { x: 15.0, y: 15.0 }),
+            );
+            let dimensions = ping_text.dimensions(ctx);
+
+            graphics::draw(
+                ctx,
+                &ping_text,
+                (ggez::mint::Point2 {
+                    x: SCREEN_SIZE.0 / 2.0 - (dimensions.0 as f32) / 2.0,
+                    y: SCREEN_SIZE.1 - dimensions.1 as f32 - 5.0,
+                },),
+            )?;
         }
 
         // present the graphics to the graphics engine
@@ -433,10 +996,63 @@ impl event::EventHandler for AppState {
         _keymods: KeyMods,
         _repeat: bool,
     ) {
-        if keycode == KeyCode::R {
-            self.game_state.restart(INIT_LEVEL);
+        let shows_chat = self.shows_chat();
+        if self.chat_input.is_some() {
+            self.chat_key(keycode);
+        } else if self.game_state.is_none() && self.lobby.is_some() {
+            self.lobby_key(keycode);
+        } else if keycode == KeyCode::Escape {
+            event::quit(ctx);
+        } else if let Some(game) = &mut self.game_state {
+            if keycode == KeyCode::Return && shows_chat && !game.is_spectator() {
+                self.chat_input = Some(String::new());
+                self.chat_notice = None;
+            } else if keycode == KeyCode::F3 && self.setup.bindings.get(keycode).is_none() {
+                self.show_net_stats = !self.show_net_stats;
+            } else if game.is_spectator() {
+                // spectators only watch
+            } else if keycode == KeyCode::R {
+                game.restart(self.setup.start_level);
+            } else if keycode == KeyCode::Tab
+                && game.get_names().len() > 2
+                && self.setup.bindings.get(keycode).is_none()
+            {
+                game.set_targeting(game.get_targeting().next());
+            } else {
+                game.key_down(keycode);
+            }
+        } else if keycode == KeyCode::Left {
+            self.setup.players = (self.setup.players - 1).max(MIN_PLAYERS as u8);
+            if self.setup.teams > self.setup.players {
+                self.setup.teams = self.setup.players;
+            }
+        } else if keycode == KeyCode::Right {
+            self.setup.players = (self.setup.players + 1).min(MAX_PLAYERS as u8);
+        } else if keycode == KeyCode::Up {
+            // no teams, then 2 teams and more up to one per player
+            self.setup.teams = (self.setup.teams + 1).clamp(2, self.setup.players);
+        } else if keycode == KeyCode::Down {
+            self.setup.teams = if self.setup.teams > 2 {
+                self.setup.teams - 1
+            } else {
+                0
+            };
+        } else if keycode == KeyCode::G {
+            self.setup.split_garbage = !self.setup.split_garbage;
+        } else if keycode == KeyCode::B {
+            self.setup.randomizer = self.setup.randomizer.next();
+        } else if keycode == KeyCode::Key6 {
+            self.open_lobby();
         } else {
-            self.game_state.key_down(keycode);
+            self.pick_mode(keycode);
+        }
+    }
+
+    fn text_input_event(&mut self, _ctx: &mut Context, character: char) {
+        if let Some(input) = &mut self.chat_input {
+            if !character.is_control() && input.chars().count() < MAX_CHAT_LEN {
+                input.push(character);
+            }
         }
     }
 }
@@ -562,7 +1178,7 @@ fn generate_grid_mesh(ctx: &mut Context) -> GameResult<Mesh> {
                 },
                 ggez::mint::Point2 {
                     x: (x as f32) * BLOCK_SIZE.0,
-                    y: P1_BOARD.3,
+                    y: BOARD_SIZE.1,
                 },
             ],
             GRID_LINE_WIDTH,
@@ -578,7 +1194,7 @@ fn generate_grid_mesh(ctx: &mut Context) -> GameResult<Mesh> {
                     y: (y as f32) * BLOCK_SIZE.1,
                 },
                 ggez::mint::Point2 {
-                    x: P1_BOARD.2,
+                    x: BOARD_SIZE.0,
                     y: (y as f32) * BLOCK_SIZE.1,
                 },
             ],
@@ -653,7 +1269,7 @@ fn generate_small_blocks(ctx: &mut Context) -> [Mesh; 8] {
     ]
 }
 mod tests {
-    use super::{AppState, SCREEN_SIZE};
+    use super::{AppState, Setup, SCREEN_SIZE};
     use ggez::event::{self, EventHandler};
     use ggez::graphics;
     use ggez::{Context, ContextBuilder, GameResult};
@@ -673,7 +1289,7 @@ mod tests {
 
         let (contex, event_loop) = &mut context_builder.build().expect("context builder error");
 
-        let state = &mut AppState::new(contex);
+        let state = &mut AppState::new(contex, Setup::default(), None);
 
         event::run(contex, event_loop, state);
     }
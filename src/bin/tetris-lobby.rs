@@ -0,0 +1,26 @@
+use isaklar_maltebl_game::cli::{self, init_logging};
+use isaklar_maltebl_game::game_state::lobby::{Lobby, LOBBY_PORT};
+use log::{info, LevelFilter};
+use std::env;
+use std::process;
+
+/// Keeps a list of rooms that players can browse and join, the matches are played at the hosts
+fn main() {
+    init_logging(LevelFilter::Info);
+    let adress = match env::args().nth(1) {
+        Some(arg) => cli::adress(&arg, LOBBY_PORT).unwrap_or_else(|err| {
+            eprintln!("error: {}\n\nusage: tetris-lobby [adress]", err);
+            process::exit(2);
+        }),
+        None => format!("0.0.0.0:{}", LOBBY_PORT),
+    };
+    let lobby = match Lobby::bind(&adress) {
+        Ok(lobby) => lobby,
+        Err(err) => {
+            eprintln!("error: could not start the lobby on {}: {}", adress, err);
+            process::exit(1);
+        }
+    };
+    info!("Lobby listening on {}", adress);
+    lobby.run();
+}
@@ -0,0 +1,75 @@
+use isaklar_maltebl_game::cli::{self, init_logging};
+use isaklar_maltebl_game::game_state::connection::NetConfig;
+use isaklar_maltebl_game::game_state::lobby::LobbyClient;
+use isaklar_maltebl_game::game_state::protocol::{RoomMode, RoomSettings};
+use isaklar_maltebl_game::game_state::server::Server;
+use isaklar_maltebl_game::game_state::DEFAULT_PORT;
+use log::info;
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+
+/// Runs matches without a window, clients connect to it like to any host
+fn main() {
+    let options = match cli::parse_server(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, cli::SERVER_USAGE);
+            process::exit(2);
+        }
+    };
+    if options.help {
+        print!("{}", cli::SERVER_USAGE);
+        return;
+    }
+    init_logging(options.log_level);
+    let (adress, setup) = (&options.adress, &options.setup);
+    let config = NetConfig {
+        players: setup.players,
+        teams: setup.teams,
+        split_garbage: setup.split_garbage,
+        randomizer: setup.randomizer,
+        attack_table: setup.attack_table,
+        ..setup.config
+    };
+    // the level was checked by the parser
+    let start_level = setup.start_level as u8;
+    let mut server = match Server::bind(adress, start_level, config) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("error: could not start the server on {}: {}", adress, err);
+            process::exit(1);
+        }
+    };
+    server.set_targeting(setup.targeting);
+    info!("Listening on {}", adress);
+    // the room is open for as long as the connection to the lobby is
+    if let Some(lobby) = &options.lobby {
+        let port = adress
+            .parse::<SocketAddr>()
+            .map_or(DEFAULT_PORT, |adress| adress.port());
+        let settings = RoomSettings {
+            name: format!("Server on port {}", port),
+            mode: RoomMode::Server,
+            port,
+            start_level,
+            players: setup.players,
+        };
+        let opened = LobbyClient::connect(lobby, "tetris-server")
+            .and_then(|mut client| client.create(settings).map(|id| (client, id)));
+        match opened {
+            Ok((client, id)) => {
+                info!("Opened room {} in the lobby at {}", id, lobby);
+                server.set_lobby(client, id);
+            }
+            Err(err) => {
+                eprintln!(
+                    "error: could not open a room in the lobby at {}: {}",
+                    lobby, err
+                );
+                process::exit(1);
+            }
+        }
+    }
+    server.run();
+}
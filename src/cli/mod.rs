@@ -0,0 +1,452 @@
+use crate::game_data::{
+    RandomizerKind, COMBO_STEPS, MAX_BONUS_LINES, RANDOMIZER_NAMES, TIME_LEVELS,
+};
+use crate::game_state::auth;
+use crate::game_state::keys::KeyBindings;
+use crate::game_state::lobby::LOBBY_PORT;
+use crate::game_state::targeting::{Targeting, TARGETING_NAMES};
+use crate::game_state::{Mode, Setup, DEFAULT_AI, DEFAULT_PORT, MAX_PLAYERS, MIN_PLAYERS};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+
+#[cfg(test)]
+mod tests;
+
+pub const USAGE: &str = "\
+usage: isaklar-maltebl-game [command] [options]
+
+commands:
+    play                    both players on this machine, P1 on A/D/Q/E/Z/S/W/Space and P2 on J/L/U/O/P/K/I/RShift
+    host [adress]           host a network match, on 0.0.0.0:8787 if no adress is given
+    join [adress]           join a network match, lists the games on the local network if no adress is given
+    spectate [adress]       watch a network match
+    lobby [adress]          browse the rooms of a lobby, on port 8789 if no port is given
+    ai-match                two AI scripts against each other
+    replay <file>           play back a recorded match
+without a command the start screen is shown
+
+options:
+    --level <n>             the level the match starts on (default 5)
+    --ai1 <script>          let an AI script play P1, which is us in network matches
+    --ai2 <script>          let an AI script play P2
+    --seed <n>              the seed the pieces are drawn from
+    --best-of <n>           play a series, whoever wins more than half of the matches wins it
+    --players <n>           2 to 8 players, the last one standing wins. Local players after P2 are AI scripts
+    --targeting <name>      who our garbage goes to: random, attacker, most-garbage or badges (default random)
+    --teams <n>             deal the players into n teams in turn, a team loses once all of its players have topped out
+    --split-garbage         garbage sent to a team is shared between its players still in the match
+    --randomizer <name>     how the pieces are dealt: 7-bag, 14-bag, history or random (default 7-bag)
+    --combo-table <n,n,..>  the lines sent for combo 0, 1, 2 and on, the last one is used for longer combos
+                            (default 0,1,1,2,2,3,3,4,4,4,5,5)
+    --b2b-bonus <n>         the lines added to a back-to-back tetris or T-spin clear (default 1)
+    --keys <file>           key bindings, lines like `p1.hard-drop = Up`
+    --name <name>           the name shown to the opponent
+    --password <text>       a room password, only players who know it can join or watch the match
+    --password-file <file>  the room password from the first line of a file, so it isn't in the process list
+    --rollback              host the match with rollback instead of lockstep
+    --record <file>         save a replay of the match when it's over
+    --chat-in-game          let Return open the chat during a match too, not only once it's over
+    --log-level <level>     off, error, warn, info, debug or trace (default info)
+    -h, --help              show this
+";
+
+/// Everything given on the command line
+pub struct Options {
+    pub mode: Option<Mode>, // the start screen is shown without one
+    pub setup: Setup,
+    pub log_level: LevelFilter,
+    pub help: bool,
+}
+
+/// Reads the arguments, without the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        mode: None,
+        setup: Setup::default(),
+        log_level: LevelFilter::Info,
+        help: false,
+    };
+    let mut command: Option<String> = None;
+    let mut target: Option<String> = None; // the adress or replay file after the command
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            match (&command, &target) {
+                (None, _) => command = Some(arg),
+                (Some(command), None) if command != "play" && command != "ai-match" => {
+                    target = Some(arg)
+                }
+                _ => return Err(format!("unexpected argument `{}`", arg)),
+            }
+            continue;
+        }
+        apply_flag(&arg, &mut args, &mut options)?;
+    }
+
+    let setup = &mut options.setup;
+    let networked = matches!(
+        command.as_deref(),
+        Some("host") | Some("join") | Some("connect")
+    );
+    check_teams(setup)?;
+    if setup.ai[1].is_some() && networked {
+        return Err(
+            "--ai2 only works when both players are on this machine, the opponent plays P2"
+                .to_string(),
+        );
+    }
+    options.mode = match command.as_deref() {
+        None => None,
+        Some("play") => Some(Mode::Local),
+        Some("ai-match") => {
+            for script in &mut setup.ai {
+                script.get_or_insert_with(|| DEFAULT_AI.to_string());
+            }
+            Some(Mode::Local)
+        }
+        Some("host") => Some(Mode::Host(
+            target.map(|arg| adress(&arg, DEFAULT_PORT)).transpose()?,
+        )),
+        // connect is what join used to be called
+        Some("join") | Some("connect") => Some(Mode::Join(
+            target.map(|arg| adress(&arg, DEFAULT_PORT)).transpose()?,
+        )),
+        Some("spectate") => Some(Mode::Spectate(
+            target.map(|arg| adress(&arg, DEFAULT_PORT)).transpose()?,
+        )),
+        // the lobby screen is a start screen of its own
+        Some("lobby") => {
+            let lobby = match target {
+                Some(arg) => adress(&arg, LOBBY_PORT)?,
+                None => format!("127.0.0.1:{}", LOBBY_PORT),
+            };
+            setup.lobby = Some(lobby);
+            None
+        }
+        Some("replay") => match target {
+            Some(file) => Some(Mode::Replay(file)),
+            None => return Err("replay needs the file to play".to_string()),
+        },
+        Some(command) => return Err(format!("unknown command `{}`", command)),
+    };
+    let watching = matches!(
+        options.mode,
+        Some(Mode::Spectate(_)) | Some(Mode::Replay(_))
+    );
+    if watching && setup.ai.iter().any(Option::is_some) {
+        return Err(
+            "nobody plays when watching, so there's nothing for an AI script to do".to_string(),
+        );
+    }
+    Ok(options)
+}
+
+pub const SERVER_USAGE: &str = "\
+usage: tetris-server [adress] [options]
+
+runs matches without a window, on 0.0.0.0:8787 if no adress is given
+
+options:
+    --level <n>             the level the matches start on (default 5)
+    --players <n>           2 to 8 players in a match (default 2)
+    --targeting <name>      who garbage goes to: random, attacker, most-garbage or badges (default random)
+    --teams <n>             deal the players into n teams in turn
+    --split-garbage         garbage sent to a team is shared between its players still in the match
+    --randomizer <name>     how the pieces are dealt: 7-bag, 14-bag, history or random (default 7-bag)
+    --combo-table <n,n,..>  the lines sent for combo 0, 1, 2 and on (default 0,1,1,2,2,3,3,4,4,4,5,5)
+    --b2b-bonus <n>         the lines added to a back-to-back tetris or T-spin clear (default 1)
+    --password <text>       a room password, only players who know it can join or watch
+    --password-file <file>  the room password from the first line of a file, so it isn't in the process list
+    --lobby <adress>        open a room in a lobby, on port 8789 if no port is given
+    --log-level <level>     off, error, warn, info, debug or trace (default info)
+    -h, --help              show this
+";
+
+/// The flags of the game that the match server takes too, besides --lobby
+const SERVER_FLAGS: [&str; 13] = [
+    "-h",
+    "--help",
+    "--level",
+    "--players",
+    "--targeting",
+    "--teams",
+    "--split-garbage",
+    "--randomizer",
+    "--combo-table",
+    "--b2b-bonus",
+    "--password",
+    "--password-file",
+    "--log-level",
+];
+
+/// Everything given to the match server on the command line
+pub struct ServerOptions {
+    pub adress: String,
+    pub setup: Setup, // only the match settings and the password are used
+    pub lobby: Option<String>,
+    pub log_level: LevelFilter,
+    pub help: bool,
+}
+
+/// Reads the arguments of the match server, without the program name
+pub fn parse_server(args: impl IntoIterator<Item = String>) -> Result<ServerOptions, String> {
+    let mut options = Options {
+        mode: None,
+        setup: Setup::default(),
+        log_level: LevelFilter::Info,
+        help: false,
+    };
+    let mut adress_arg = None;
+    let mut lobby = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            match adress_arg {
+                None => adress_arg = Some(arg),
+                Some(_) => return Err(format!("unexpected argument `{}`", arg)),
+            }
+            continue;
+        }
+        let flag = arg.split('=').next().unwrap_or_default();
+        if flag == "--lobby" {
+            let value = match arg.find('=') {
+                Some(i) => Some(arg[i + 1..].to_string()),
+                None => args.next(),
+            };
+            let value = value.ok_or_else(|| "--lobby needs a value".to_string())?;
+            lobby = Some(adress(&value, LOBBY_PORT)?);
+        } else if SERVER_FLAGS.contains(&flag) {
+            apply_flag(&arg, &mut args, &mut options)?;
+        } else {
+            return Err(format!("unknown option `{}`", flag));
+        }
+    }
+    check_teams(&options.setup)?;
+    Ok(ServerOptions {
+        adress: match adress_arg {
+            Some(arg) => adress(&arg, DEFAULT_PORT)?,
+            None => format!("0.0.0.0:{}", DEFAULT_PORT),
+        },
+        setup: options.setup,
+        lobby,
+        log_level: options.log_level,
+        help: options.help,
+    })
+}
+
+fn check_teams(setup: &Setup) -> Result<(), String> {
+    if setup.teams > setup.players {
+        return Err(format!(
+            "{} teams need at least {} players, use --players",
+            setup.teams, setup.teams
+        ));
+    }
+    Ok(())
+}
+
+/// Applies the flag `arg` to the options, its value is after a = or the next of `args`
+fn apply_flag(
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+    options: &mut Options,
+) -> Result<(), String> {
+    // the value of a flag is either the next argument or after a =
+    let (flag, inline) = match arg.find('=') {
+        Some(i) => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+        None => (arg.to_string(), None),
+    };
+    let mut value = || match inline.clone().or_else(|| args.next()) {
+        Some(value) => Ok(value),
+        None => Err(format!("{} needs a value", flag)),
+    };
+    let setup = &mut options.setup;
+    match flag.as_str() {
+        "-h" | "--help" => options.help = true,
+        "--level" => {
+            let level = value()?;
+            setup.start_level = match level.parse() {
+                Ok(level) if level < TIME_LEVELS.len() => level,
+                _ => {
+                    return Err(format!(
+                        "the level has to be between 0 and {}, not `{}`",
+                        TIME_LEVELS.len() - 1,
+                        level
+                    ))
+                }
+            };
+        }
+        "--ai1" => setup.ai[0] = Some(value()?),
+        "--ai2" => setup.ai[1] = Some(value()?),
+        "--seed" => {
+            let seed = value()?;
+            let parsed = seed.parse();
+            setup.seed = Some(
+                parsed.map_err(|_| format!("`{}` is not a seed, it has to be a number", seed))?,
+            );
+        }
+        "--best-of" => {
+            let best_of = value()?;
+            setup.best_of = match best_of.parse() {
+                Ok(best_of) if best_of > 0 => best_of,
+                _ => {
+                    return Err(format!(
+                        "a series is between 1 and 255 matches, not `{}`",
+                        best_of
+                    ))
+                }
+            };
+        }
+        "--players" => {
+            let players = value()?;
+            setup.players = match players.parse::<u8>() {
+                Ok(n) if (MIN_PLAYERS..=MAX_PLAYERS).contains(&(n as usize)) => n,
+                _ => {
+                    return Err(format!(
+                        "a match has {} to {} players, not `{}`",
+                        MIN_PLAYERS, MAX_PLAYERS, players
+                    ))
+                }
+            };
+        }
+        "--targeting" => {
+            let name = value()?;
+            setup.targeting = Targeting::parse(&name).ok_or_else(|| {
+                let names: Vec<&str> = TARGETING_NAMES.iter().map(|(name, _)| *name).collect();
+                format!("unknown targeting `{}`, use {}", name, names.join(", "))
+            })?;
+        }
+        "--teams" => {
+            let teams = value()?;
+            setup.teams = match teams.parse::<u8>() {
+                Ok(n) if (2..=MAX_PLAYERS).contains(&(n as usize)) => n,
+                _ => {
+                    return Err(format!(
+                        "a match has 2 to {} teams, not `{}`",
+                        MAX_PLAYERS, teams
+                    ))
+                }
+            };
+        }
+        "--split-garbage" => setup.split_garbage = true,
+        "--randomizer" => {
+            let name = value()?;
+            setup.randomizer = RandomizerKind::parse(&name).ok_or_else(|| {
+                let names: Vec<&str> = RANDOMIZER_NAMES.iter().map(|(name, _)| *name).collect();
+                format!("unknown randomizer `{}`, use {}", name, names.join(", "))
+            })?;
+        }
+        "--combo-table" => {
+            let table = value()?;
+            let lines: Vec<u8> = table
+                .split(',')
+                .map(|lines| lines.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("`{}` is not a combo table, like 0,1,1,2", table))?;
+            if lines.is_empty() || lines.len() > COMBO_STEPS {
+                return Err(format!(
+                    "a combo table has 1 to {} steps, not {}",
+                    COMBO_STEPS,
+                    lines.len()
+                ));
+            }
+            if lines.iter().any(|lines| *lines > MAX_BONUS_LINES) {
+                return Err(format!(
+                    "a combo sends at most {} lines, not `{}`",
+                    MAX_BONUS_LINES, table
+                ));
+            }
+            let combo = &mut setup.attack_table.combo;
+            combo[..lines.len()].copy_from_slice(&lines);
+            // longer combos send as much as the last step
+            let last = lines[lines.len() - 1];
+            for step in &mut combo[lines.len()..] {
+                *step = last;
+            }
+        }
+        "--b2b-bonus" => {
+            let bonus = value()?;
+            setup.attack_table.back_to_back = match bonus.parse() {
+                Ok(lines) if lines <= MAX_BONUS_LINES => lines,
+                _ => {
+                    return Err(format!(
+                        "the back-to-back bonus is 0 to {} lines, not `{}`",
+                        MAX_BONUS_LINES, bonus
+                    ))
+                }
+            };
+        }
+        "--keys" => setup.bindings = KeyBindings::load(&value()?)?,
+        "--name" => setup.name = value()?,
+        "--password" => setup.config.key = Some(auth::derive_key(&value()?)),
+        "--password-file" => {
+            let path = value()?;
+            let text = fs::read_to_string(&path)
+                .map_err(|err| format!("could not read the password from {}: {}", path, err))?;
+            let password = text.lines().next().unwrap_or_default();
+            setup.config.key = Some(auth::derive_key(password));
+        }
+        "--rollback" => setup.config.rollback = true,
+        "--record" => setup.record = Some(value()?),
+        "--chat-in-game" => setup.chat_in_game = true,
+        "--log-level" => {
+            let level = value()?;
+            options.log_level = level.parse().map_err(|_| {
+                format!(
+                    "unknown log level `{}`, use off, error, warn, info, debug or trace",
+                    level
+                )
+            })?;
+        }
+        _ => return Err(format!("unknown option `{}`", flag)),
+    }
+    Ok(())
+}
+
+/// Checks an adress given on the command line, a bare ip gets the port
+pub fn adress(arg: &str, port: u16) -> Result<String, String> {
+    if arg.parse::<SocketAddr>().is_ok() {
+        return Ok(arg.to_string());
+    }
+    if let Ok(ip) = arg.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port).to_string());
+    }
+    // a host name with a port
+    match arg.rfind(':') {
+        Some(i) if i > 0 && arg[i + 1..].parse::<u16>().is_ok() => Ok(arg.to_string()),
+        _ => Err(format!(
+            "`{}` is not an adress, expected an ip and a port like 192.168.0.2:{}",
+            arg, port
+        )),
+    }
+}
+
+/// Prints log messages in the terminal, everything but info with its level in front
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Info => println!("{}", record.args()),
+            level => eprintln!("{}: {}", level.to_string().to_lowercase(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Shows the log messages up to the level from now on
+pub fn init_logging(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -0,0 +1,111 @@
+use super::{parse, parse_server};
+use crate::game_data::RandomizerKind;
+use crate::game_state::targeting::Targeting;
+use crate::game_state::{Mode, DEFAULT_AI};
+use log::LevelFilter;
+
+fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(String::from).collect()
+}
+
+#[test]
+fn subcommands_and_flags() {
+    let options = parse(args("")).unwrap();
+    assert!(options.mode.is_none());
+
+    let options = parse(args("play --level 3 --seed 42 --ai2 bot.so")).unwrap();
+    assert!(matches!(options.mode, Some(Mode::Local)));
+    assert_eq!(3, options.setup.start_level);
+    assert_eq!(Some(42), options.setup.seed);
+    assert_eq!([None, Some("bot.so".to_string())], options.setup.ai);
+
+    let options = parse(args("ai-match --log-level=debug")).unwrap();
+    assert_eq!(
+        [Some(DEFAULT_AI.to_string()), Some(DEFAULT_AI.to_string())],
+        options.setup.ai
+    );
+    assert_eq!(LevelFilter::Debug, options.log_level);
+
+    let options = parse(args("host 127.0.0.1 --name Isak --rollback --best-of 5")).unwrap();
+    assert!(matches!(options.mode, Some(Mode::Host(Some(ref ip))) if ip == "127.0.0.1:8787"));
+    assert_eq!("Isak", options.setup.name);
+    assert!(options.setup.config.rollback);
+    assert_eq!(5, options.setup.best_of);
+
+    let options = parse(args("host --players 4 --targeting most-garbage")).unwrap();
+    assert_eq!(4, options.setup.players);
+    assert_eq!(Targeting::MostGarbage, options.setup.targeting);
+
+    let options = parse(args(
+        "play --players 4 --teams 2 --split-garbage --randomizer history",
+    ))
+    .unwrap();
+    assert_eq!(2, options.setup.teams);
+    assert!(options.setup.split_garbage);
+    assert_eq!(RandomizerKind::History, options.setup.randomizer);
+
+    let options = parse(args("play --combo-table 0,1,2 --b2b-bonus 2")).unwrap();
+    let table = options.setup.attack_table;
+    assert_eq!([0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2], table.combo);
+    assert_eq!(2, table.back_to_back);
+
+    let options = parse(args("join --chat-in-game")).unwrap();
+    assert!(matches!(options.mode, Some(Mode::Join(None))));
+    assert!(options.setup.chat_in_game);
+    let options = parse(args("lobby 10.0.0.5")).unwrap();
+    assert!(options.mode.is_none());
+    assert_eq!(Some("10.0.0.5:8789".to_string()), options.setup.lobby);
+    let options = parse(args("replay match.ttr")).unwrap();
+    assert!(matches!(options.mode, Some(Mode::Replay(ref file)) if file == "match.ttr"));
+}
+
+#[test]
+fn bad_arguments_are_explained() {
+    let error = |line: &str| parse(args(line)).err().unwrap();
+    assert!(error("dance").contains("unknown command"));
+    assert!(error("join not-an-adress").contains("not an adress"));
+    assert!(error("play --level").contains("needs a value"));
+    assert!(error("play --level 99").contains("level"));
+    assert!(error("play --seed abc").contains("seed"));
+    assert!(error("host --best-of 0").contains("series"));
+    assert!(error("host --players 9").contains("players"));
+    assert!(error("play --targeting nearest").contains("targeting"));
+    assert!(error("play --teams 3").contains("teams"));
+    assert!(error("play --combo-table 1,x").contains("combo table"));
+    assert!(error("play --combo-table 0,1,1,2,2,3,3,4,4,4,5,5,6").contains("steps"));
+    assert!(error("play --combo-table 0,1,99").contains("at most"));
+    assert!(error("play --b2b-bonus 255").contains("back-to-back"));
+    assert!(error("play --log-level loud").contains("log level"));
+    assert!(error("play --fast").contains("unknown option"));
+    assert!(error("play extra").contains("unexpected argument"));
+    assert!(error("replay").contains("file"));
+    assert!(error("host --ai2 bot.so").contains("--ai2"));
+    assert!(error("play --keys missing-bindings.txt").contains("missing-bindings.txt"));
+}
+
+#[test]
+fn server_arguments() {
+    let options = parse_server(args("")).unwrap();
+    assert_eq!("0.0.0.0:8787", options.adress);
+    assert_eq!(None, options.lobby);
+
+    let options = parse_server(args(
+        "127.0.0.1 --level 7 --players 4 --teams 2 --targeting attacker --lobby 10.0.0.5",
+    ))
+    .unwrap();
+    assert_eq!("127.0.0.1:8787", options.adress);
+    assert_eq!(7, options.setup.start_level);
+    assert_eq!(4, options.setup.players);
+    assert_eq!(2, options.setup.teams);
+    assert_eq!(Targeting::Attacker, options.setup.targeting);
+    assert_eq!(Some("10.0.0.5:8789".to_string()), options.lobby);
+    assert!(options.setup.config.key.is_none());
+
+    let error = |line: &str| parse_server(args(line)).err().unwrap();
+    assert!(error("--level 25").contains("level"));
+    assert!(error("--level 300").contains("level"));
+    assert!(error("--players many").contains("players"));
+    assert!(error("--lobby").contains("needs a value"));
+    assert!(error("--rollback").contains("unknown option"));
+    assert!(error("0.0.0.0:8787 extra").contains("unexpected argument"));
+}
@@ -1,9 +1,16 @@
 use rand::Rng;
-use std::time::Instant;
 
+mod randomizer;
+mod rng;
+mod snapshot;
+mod srs;
 #[cfg(test)]
 mod tests;
 
+pub use randomizer::{Randomizer, RandomizerKind, RANDOMIZER_NAMES};
+pub use rng::GameRng;
+pub use srs::Rotation;
+
 #[derive(Copy, Clone)]
 pub enum Color {
     Void = 0,
@@ -33,32 +40,174 @@ pub const GRACE_DELAY: u8 = 4;
 pub const ROWS: usize = 24;
 pub const COLS: usize = 10;
 
+/// The pieces as they spawn, up is +y like on the board
 pub const SHAPES: [Shape; 7] = [
     //I
-    [[-2, 0], [-1, 0], [0, 0], [1, 0]],
+    [[-1, 0], [0, 0], [1, 0], [2, 0]],
     //O
-    [[-1, 0], [-1, -1], [0, -1], [0, 0]],
+    [[0, 0], [1, 0], [0, 1], [1, 1]],
     //T
-    [[-1, 0], [0, 0], [0, -1], [1, 0]],
+    [[-1, 0], [0, 0], [1, 0], [0, 1]],
     //S
-    [[-1, 0], [0, 0], [0, -1], [1, -1]],
+    [[-1, 0], [0, 0], [0, 1], [1, 1]],
     //Z
-    [[-1, -1], [0, -1], [0, 0], [1, 0]],
+    [[-1, 1], [0, 1], [0, 0], [1, 0]],
     //J
-    [[-1, -1], [-1, 0], [0, 0], [1, 0]],
+    [[-1, 1], [-1, 0], [0, 0], [1, 0]],
     //L
-    [[-1, 0], [0, 0], [1, 0], [1, -1]],
+    [[-1, 0], [0, 0], [1, 0], [1, 1]],
 ];
 
+/// Index of the T piece in `SHAPES`
+const T: usize = 2;
+
+/// Where pieces spawn, in the hidden rows above the board with JLSTZ over the fourth to sixth columns
+const SPAWN: Point = [(COLS as i32 - 1) / 2, ROWS as i32 - 2];
+
 pub const TIME_LEVELS: [f64; 20] = [
     1.0, 0.79300, 0.61780, 0.47273, 0.35520, 0.26200, 0.18968, 0.13473, 0.09388, 0.06415, 0.04298,
     0.02822, 0.01815, 0.01144, 0.00706, 0.00426, 0.00252, 0.00146, 0.00082, 0.00046,
 ];
+
+/// The moves a player can make, numbered the same way as the ai-script output
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Move {
+    Left = 1,
+    Right = 2,
+    RotateCw = 3,
+    RotateCcw = 4,
+    SoftDrop = 5,
+    HardDrop = 6,
+    Save = 7,
+    Rotate180 = 8,
+}
+
+impl Move {
+    pub fn from_u8(input: u8) -> Option<Move> {
+        match input {
+            1 => Some(Move::Left),
+            2 => Some(Move::Right),
+            3 => Some(Move::RotateCw),
+            4 => Some(Move::RotateCcw),
+            5 => Some(Move::SoftDrop),
+            6 => Some(Move::HardDrop),
+            7 => Some(Move::Save),
+            8 => Some(Move::Rotate180),
+            _ => None,
+        }
+    }
+}
+
+/// Garbage sent to the opponent, `lines` rows with a hole in column `hole`.
+/// They rise after `delay` more gravity steps of the receiver.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Attack {
+    pub lines: u8,
+    pub hole: u8,
+    pub delay: u8,
+}
+
+/// A T-spin: a T that was turned into a spot where three of the four corners around its center are filled
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Spin {
+    /// Only one of the corners the T points to is filled
+    Mini = 1,
+    /// Both of them are, or the T got there with the last kick of a quarter turn
+    Full = 2,
+}
+
+/// What a locked piece did, kept for a while so it can be called out
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Clear {
+    pub lines: u8,
+    pub spin: Option<Spin>,
+}
+
+impl Clear {
+    /// The callout, like "T-SPIN DOUBLE"
+    pub fn name(&self) -> String {
+        let lines = ["", "SINGLE", "DOUBLE", "TRIPLE", "TETRIS"][self.lines.min(4) as usize];
+        let spin = match self.spin {
+            Some(Spin::Mini) => "MINI T-SPIN",
+            Some(Spin::Full) => "T-SPIN",
+            None => "",
+        };
+        format!("{} {}", spin, lines).trim().to_string()
+    }
+}
+
+/// Score and attack for clearing `lines` lines at once, with or without a T-spin
+pub fn reward(lines: usize, spin: Option<Spin>) -> (usize, u8) {
+    match (spin, lines) {
+        (None, 0) => (0, 0),
+        (None, 1) => (1, 0),
+        (None, 2) => (3, 1),
+        (None, 3) => (5, 2),
+        (None, _) => (8, 4),
+        (Some(Spin::Mini), 0) => (1, 0),
+        (Some(Spin::Mini), 1) => (2, 0),
+        (Some(Spin::Mini), _) => (4, 1),
+        (Some(Spin::Full), 0) => (4, 0),
+        (Some(Spin::Full), 1) => (8, 2),
+        (Some(Spin::Full), 2) => (12, 4),
+        (Some(Spin::Full), _) => (16, 6),
+    }
+}
+
+/// Steps in a combo table, longer combos send as much as the last step
+pub const COMBO_STEPS: usize = 12;
+/// Most lines a step of the combo table or the back-to-back bonus can add, more than that would fill a board
+pub const MAX_BONUS_LINES: u8 = ROWS as u8;
+
+/// The extra lines sent for combos and back-to-back clears, decided by the host for the whole match
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AttackTable {
+    pub combo: [u8; COMBO_STEPS], // by combo, the second clear in a row is combo 1
+    pub back_to_back: u8,         // for a tetris or T-spin clear right after another one
+}
+
+impl Default for AttackTable {
+    /// The tables of the guideline games
+    fn default() -> AttackTable {
+        AttackTable {
+            combo: [0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5, 5],
+            back_to_back: 1,
+        }
+    }
+}
+
+impl AttackTable {
+    /// The lines sent for a combo
+    pub fn combo_lines(&self, combo: u8) -> u8 {
+        self.combo[(combo as usize).min(COMBO_STEPS - 1)]
+    }
+
+    /// Whether every bonus is at most `MAX_BONUS_LINES`
+    pub fn is_valid(&self) -> bool {
+        self.combo
+            .iter()
+            .chain(Some(&self.back_to_back))
+            .all(|lines| *lines <= MAX_BONUS_LINES)
+    }
+}
+
+/// Whether a clear keeps a back-to-back going: tetrises and T-spins that clear lines
+fn is_difficult(lines: usize, spin: Option<Spin>) -> bool {
+    lines >= 4 || (lines > 0 && spin.is_some())
+}
+
+/// Converts the seconds per row of a level to game ticks, levels past the last one are as fast as it
+pub fn gravity_ticks(level: usize, tick_rate: u32) -> u32 {
+    let level = level.min(TIME_LEVELS.len() - 1);
+    let ticks = (TIME_LEVELS[level] * tick_rate as f64).round() as u32;
+    ticks.max(1)
+}
+
 #[derive(Clone)]
 pub struct Player {
     board: [[u32; COLS]; ROWS],
-    incoming: Vec<(u8, u8)>,
-    outgoing: Option<(u8, u8)>,
+    incoming: Vec<Attack>,
+    outgoing: Option<Attack>,
     pub current_piece: Piece,
     piece_shadow: Option<Piece>,
     saved_piece: Option<Piece>,
@@ -66,47 +215,102 @@ pub struct Player {
     pub next_piece: Piece,
     score: usize,
     lost: bool,
-    gravity: f64,
-    update_timer: Instant,
+    rng: GameRng,
+    randomizer: Randomizer, // deals the pieces
+    tick_rate: u32,
+    gravity: u32, // ticks per row
+    gravity_timer: u32,
     grace_count: u8,
+    attacker: Option<u8>, // the side whose garbage came in last, it gets the knockout if this player tops out
+    knockouts: u8,        // players this one knocked out
+    last_turn: Option<bool>, // Some if the current piece was turned since it last moved, true if that took the T-spin kick
+    last_clear: Option<Clear>, // what the last piece that cleared lines or spun did
+    callout_timer: u32,      // ticks left to show `last_clear`
+    attack_table: AttackTable,
+    streak: u8,         // pieces in a row that cleared lines
+    back_to_back: bool, // the last clear was a tetris or a T-spin, the next one of those gets the bonus
 }
 
 impl Player {
-    pub fn new(level: usize) -> Player {
+    /// Creates a player whose pieces and garbage are drawn from `seed`, the pieces dealt by a randomizer of `kind`,
+    /// so two players created with the same arguments play out identically
+    pub fn new(level: usize, seed: u64, tick_rate: u32, kind: RandomizerKind) -> Player {
+        let rng = GameRng::new(seed);
+        let mut randomizer = Randomizer::new(kind, seed);
+        let current_piece = Piece::spawn(randomizer.next_shape());
+        let next_piece = Piece::spawn(randomizer.next_shape());
         Player {
             board: [[0; COLS]; ROWS],
             incoming: Vec::new(),
             outgoing: None,
-            current_piece: Piece::random_piece(),
+            current_piece,
             piece_shadow: None,
             saved_piece: None,
             has_saved: false,
-            next_piece: Piece::random_piece(),
+            next_piece,
             score: 0,
             lost: false,
-            gravity: TIME_LEVELS[level],
-            update_timer: Instant::now(),
+            rng,
+            randomizer,
+            tick_rate,
+            gravity: gravity_ticks(level, tick_rate),
+            gravity_timer: 0,
             grace_count: 0,
+            attacker: None,
+            knockouts: 0,
+            last_turn: None,
+            last_clear: None,
+            callout_timer: 0,
+            attack_table: AttackTable::default(),
+            streak: 0,
+            back_to_back: false,
         }
     }
 
+    /// Changes the combo and back-to-back bonuses, for matches whose host picked other ones
+    pub fn set_attack_table(&mut self, table: AttackTable) {
+        self.attack_table = table;
+    }
+
+    /// Advances the player one game tick
     pub fn update(&mut self) {
         self.shadow_piece();
-        if !self.lost && self.update_timer.elapsed().as_secs_f64() >= self.gravity {
-            self.process_attacks();
-            self.move_tick();
-            self.update_timer = Instant::now();
+        self.callout_timer = self.callout_timer.saturating_sub(1);
+        if !self.lost {
+            self.gravity_timer += 1;
+            if self.gravity_timer >= self.gravity {
+                self.process_attacks();
+                self.move_tick();
+                self.gravity_timer = 0;
+            }
+        }
+    }
+
+    pub fn apply_move(&mut self, mv: Move) {
+        match mv {
+            Move::Left => self.move_current(-1, 0),
+            Move::Right => self.move_current(1, 0),
+            Move::RotateCw => self.rotate_current(true),
+            Move::RotateCcw => self.rotate_current(false),
+            Move::Rotate180 => self.turn_current(2),
+            Move::SoftDrop => self.move_current(0, -1),
+            Move::HardDrop => self.drop_current(),
+            Move::Save => self.save_piece(),
         }
     }
 
     pub fn move_tick(&mut self) {
         if !self.lost {
             self.current_piece.mov(0, -1);
-            if !self.valid_pos(&self.current_piece) {
+            if self.valid_pos(&self.current_piece) {
+                self.last_turn = None;
+            } else {
                 self.current_piece.mov(0, 1);
                 if self.grace_count >= GRACE_DELAY {
+                    let spin = self.spin();
                     self.place_piece(None);
-                    self.process_lines();
+                    let lines = self.process_lines();
+                    self.process_score(lines, spin);
                     self.next_piece();
                     self.grace_count = 0;
                 } else {
@@ -116,7 +320,8 @@ impl Player {
         }
     }
 
-    fn process_lines(&mut self) {
+    /// Removes the full rows, returns how many there were
+    fn process_lines(&mut self) -> usize {
         let mut full_rows: Vec<usize> = Vec::new();
         for i in 0..self.board.len() {
             if !self.board[i].contains(&0) {
@@ -136,34 +341,37 @@ impl Player {
                 *row = self.board[r];
                 r += 1;
             }
-            self.process_score(full_rows.len());
             self.board = board;
         }
+        full_rows.len()
     }
 
     fn process_attacks(&mut self) {
-        let mut rows = 0;
-        for (attack, count) in &mut self.incoming {
-            if *count == 1 {
-                rows += *attack;
+        // the hole of every garbage row that rises now, each attack keeps the hole its sender picked
+        let mut holes = Vec::new();
+        for attack in &mut self.incoming {
+            if attack.delay <= 1 {
+                for _ in 0..attack.lines {
+                    holes.push(attack.hole as usize);
+                }
             }
-            *count -= 1;
+            attack.delay = attack.delay.saturating_sub(1);
         }
+        let mut rows = holes.len();
         if rows > 0 {
             let mut i = 0;
             let mut lost = false;
-            for point in &self.board[ROWS - 4 - (rows as usize)] {
+            for point in &self.board[ROWS.saturating_sub(4 + rows)] {
                 if *point != 0 {
                     lost = true;
                 }
             }
             let mut board = [[0; COLS]; ROWS];
-            let rng = rand::thread_rng().gen_range(0, COLS);
             for row in &mut board {
                 if rows > 0 {
-                    *row = [Color::Fixed as u32; COLS];
-                    row[rng] = 0;
                     rows -= 1;
+                    *row = [Color::Fixed as u32; COLS];
+                    row[holes[rows] % COLS] = 0;
                 } else {
                     *row = self.board[i];
                     i += 1;
@@ -175,20 +383,19 @@ impl Player {
                 self.lose_game();
             }
         }
-        self.incoming.retain(|(_, time)| time > &0);
+        self.incoming.retain(|attack| attack.delay > 0);
     }
 
     pub fn save_piece(&mut self) {
         if !self.has_saved {
+            // a saved piece comes back the way it spawned
+            let pc = Piece::spawn(self.current_piece.get_index());
+            self.last_turn = None;
             if let Some(piece) = &mut self.saved_piece {
                 let p = piece.clone();
-                let mut pc = self.current_piece.clone();
-                pc.set_position([COLS as i32 / 2, ROWS as i32 - 1]);
                 *piece = pc;
                 self.current_piece = p;
             } else {
-                let mut pc = self.current_piece.clone();
-                pc.set_position([COLS as i32 / 2, ROWS as i32 - 1]);
                 self.saved_piece = Some(pc);
                 self.next_piece();
             }
@@ -197,28 +404,54 @@ impl Player {
     }
 
     fn next_piece(&mut self) {
+        self.last_turn = None;
         self.current_piece = self.next_piece.clone();
-        self.next_piece = Piece::random_piece();
+        self.next_piece = Piece::spawn(self.randomizer.next_shape());
     }
 
-    fn process_score(&mut self, lines_cleared: usize) {
-        let (mut score, mut attack) = (0, 0);
-        if lines_cleared >= 4 {
-            score = 8;
-            attack = 4;
+    fn process_score(&mut self, lines_cleared: usize, spin: Option<Spin>) {
+        // a piece that clears nothing ends the combo, but only another kind of clear ends a back-to-back
+        if lines_cleared == 0 {
+            self.streak = 0;
         } else {
-            score = lines_cleared * 2 - 1;
-            attack = lines_cleared as u8 - 1;
-        };
+            self.streak = self.streak.saturating_add(1);
+        }
+        if lines_cleared == 0 && spin.is_none() {
+            return;
+        }
+        self.last_clear = Some(Clear {
+            lines: lines_cleared as u8,
+            spin,
+        });
+        self.callout_timer = self.tick_rate * 2;
+        let (mut score, mut attack) = reward(lines_cleared, spin);
+        if lines_cleared > 0 {
+            let combo = self.get_combo();
+            score += combo as usize;
+            attack = attack.saturating_add(self.attack_table.combo_lines(combo));
+            let difficult = is_difficult(lines_cleared, spin);
+            if difficult && self.back_to_back {
+                score += score / 2;
+                attack = attack.saturating_add(self.attack_table.back_to_back);
+            }
+            self.back_to_back = difficult;
+        }
         self.score += score;
         let level = self.score / 5;
 
-        let gravity = TIME_LEVELS[level];
+        let gravity = gravity_ticks(level, self.tick_rate);
         if gravity < self.gravity {
             self.gravity = gravity;
         }
+        // our own garbage that hasn't risen yet is cancelled first, only the rest is sent
+        let attack = self.cancel_incoming(attack);
         if attack > 0 {
-            self.outgoing = Some((attack, ATTACK_DELAY));
+            // the hole is picked here so the receiver gets exactly the garbage we sent
+            self.outgoing = Some(Attack {
+                lines: attack,
+                hole: self.rng.gen_range(0, COLS) as u8,
+                delay: ATTACK_DELAY,
+            });
         }
     }
 
@@ -261,7 +494,7 @@ impl Player {
         board
     }
 
-    pub fn get_incoming(&self) -> &Vec<(u8, u8)> {
+    pub fn get_incoming(&self) -> &Vec<Attack> {
         &self.incoming
     }
 
@@ -277,15 +510,63 @@ impl Player {
         self.score
     }
 
+    pub fn get_randomizer(&self) -> &Randomizer {
+        &self.randomizer
+    }
+
     pub fn get_lost(&self) -> bool {
         self.lost
     }
 
-    pub fn add_incoming(&mut self, attack: (u8, u8)) {
+    pub fn add_incoming(&mut self, attack: Attack) {
         self.incoming.push(attack);
     }
 
-    pub fn take_outgoing(&mut self) -> Option<(u8, u8)> {
+    /// Takes `lines` off the queued garbage, oldest first, and returns the lines left over
+    pub fn cancel_incoming(&mut self, mut lines: u8) -> u8 {
+        for attack in &mut self.incoming {
+            let cancelled = attack.lines.min(lines);
+            attack.lines -= cancelled;
+            lines -= cancelled;
+        }
+        self.incoming.retain(|attack| attack.lines > 0);
+        lines
+    }
+
+    /// Garbage sent by the player on `side`, who is credited if this player tops out before someone else attacks
+    pub fn add_incoming_from(&mut self, attack: Attack, side: u8) {
+        self.add_incoming(attack);
+        self.attacker = Some(side);
+    }
+
+    pub fn get_attacker(&self) -> Option<u8> {
+        self.attacker
+    }
+
+    pub fn add_knockout(&mut self) {
+        self.knockouts = self.knockouts.saturating_add(1);
+    }
+
+    /// Clears in a row after the first, 0 while there is no combo
+    pub fn get_combo(&self) -> u8 {
+        self.streak.saturating_sub(1)
+    }
+
+    /// Whether the next tetris or T-spin clear gets the back-to-back bonus
+    pub fn get_back_to_back(&self) -> bool {
+        self.back_to_back
+    }
+
+    /// What the last piece that cleared lines or spun did, while it's still shown
+    pub fn get_callout(&self) -> Option<Clear> {
+        self.last_clear.filter(|_| self.callout_timer > 0)
+    }
+
+    pub fn get_knockouts(&self) -> u8 {
+        self.knockouts
+    }
+
+    pub fn take_outgoing(&mut self) -> Option<Attack> {
         let outgoing = self.outgoing;
         self.outgoing = None;
         outgoing
@@ -316,16 +597,50 @@ impl Player {
 
     pub fn move_current(&mut self, x: i32, y: i32) {
         self.current_piece.mov(x, y);
-        if !self.valid_pos(&self.current_piece) {
+        if self.valid_pos(&self.current_piece) {
+            self.last_turn = None;
+        } else {
             self.current_piece.mov(-x, -y);
         }
     }
 
     pub fn drop_current(&mut self) {
-        self.current_piece = self.fast_drop(self.current_piece.clone());
+        let dropped = self.fast_drop(self.current_piece.clone());
+        if dropped.position != self.current_piece.position {
+            self.last_turn = None;
+        }
+        self.current_piece = dropped;
         self.grace_count = GRACE_DELAY;
     }
 
+    /// Whether the current piece would lock as a T-spin where it is. The walls and the floor count as filled corners.
+    fn spin(&self) -> Option<Spin> {
+        let piece = &self.current_piece;
+        let last_kick = self.last_turn?;
+        if piece.get_index() != T {
+            return None;
+        }
+        let [x, y] = piece.position;
+        let filled = |[dx, dy]: Point| {
+            let (x, y) = (x + dx, y + dy);
+            x < 0
+                || y < 0
+                || x >= COLS as i32
+                || (y < ROWS as i32 && self.board[y as usize][x as usize] != 0)
+        };
+        // clockwise from the top left, so the corners a T points to are `rotation` and the one after it
+        let corners = [[-1, 1], [1, 1], [1, -1], [-1, -1]];
+        if corners.iter().filter(|corner| filled(**corner)).count() < 3 {
+            return None;
+        }
+        let front = piece.rotation as usize;
+        if last_kick || (filled(corners[front]) && filled(corners[(front + 1) % 4])) {
+            Some(Spin::Full)
+        } else {
+            Some(Spin::Mini)
+        }
+    }
+
     fn fast_drop(&self, mut piece: Piece) -> Piece {
         loop {
             piece.mov(0, -1);
@@ -352,13 +667,27 @@ impl Player {
     }
 
     pub fn rotate_current(&mut self, clockwise: bool) {
-        let old_shape = self.current_piece.shape;
-        self.current_piece.rotate(clockwise);
-        if !self.valid_pos(&self.current_piece) {
-            self.adjust_current();
-            if !self.valid_pos(&self.current_piece) {
-                self.current_piece.shape = old_shape;
+        self.turn_current(if clockwise { 1 } else { 3 });
+    }
+
+    /// Turns the current piece `quarters` quarter turns clockwise with the SRS kicks,
+    /// it stays as it was if none of the kicks fit
+    fn turn_current(&mut self, quarters: u8) {
+        let mut turned = self.current_piece.clone();
+        turned.rotate(quarters);
+        let kicks = srs::kicks(
+            turned.get_index(),
+            self.current_piece.rotation,
+            turned.rotation,
+        );
+        for (i, [x, y]) in kicks.iter().enumerate() {
+            turned.mov(*x, *y);
+            if self.valid_pos(&turned) {
+                self.current_piece = turned;
+                self.last_turn = Some(quarters != 2 && i == srs::T_SPIN_KICK);
+                return;
             }
+            turned.mov(-x, -y);
         }
     }
 
@@ -375,62 +704,40 @@ impl Player {
         }
         true
     }
-
-    fn adjust_current(&mut self) {
-        let mut y_adj = 0;
-        let mut x_adj = 0;
-        for [x, y] in &self.current_piece.pos_on_board() {
-            if *y < -y_adj && *y < 0 {
-                y_adj = -*y;
-            } else if (ROWS as i32 - 1 - *y) < y_adj {
-                y_adj = ROWS as i32 - 1 - *y;
-            }
-            if *x < -x_adj && *x < 0 {
-                x_adj = -*x;
-            } else if (COLS as i32 - 1 - *x) < x_adj {
-                x_adj = COLS as i32 - 1 - *x;
-            }
-            loop {
-                if (*y + y_adj) < ROWS as i32                                           //UNSURE IF THIS IS A GOOD BUGFIX!
-                    && self.board[(*y + y_adj) as usize][(*x + x_adj) as usize] != 0
-                {
-                    y_adj += 1;
-                } else {
-                    break;
-                }
-            }
-        }
-        self.current_piece.position[0] += x_adj;
-        self.current_piece.position[1] += y_adj as i32;
-    }
 }
 
 #[derive(Clone)]
 pub struct Piece {
+    index: usize, // which of `SHAPES` the piece is, it picks the pivot and kicks of the piece
     shape: Shape,
     display_shape: [[u32; 4]; 4],
     pub color: Color,
     position: Point,
+    rotation: Rotation,
 }
 
 impl Piece {
-    pub fn new(shape: Shape, color: Color, position: Point) -> Piece {
+    /// The piece `SHAPES[index]` as it spawns, at `position`
+    pub fn new(index: usize, color: Color, position: Point) -> Piece {
+        let shape = SHAPES[index];
+        // drawn from the top, with up as +y the rows of the shape are flipped
         let mut display_shape = [[0; 4]; 4];
         for [x, y] in &shape {
-            display_shape[(y + 2) as usize][(x + 2) as usize] = color as u32;
+            display_shape[(2 - y) as usize][(x + 1) as usize] = color as u32;
         }
         Piece {
+            index,
             shape,
             display_shape,
             color,
             position,
+            rotation: Rotation::Spawn,
         }
     }
 
-    pub fn random_piece() -> Piece {
-        let rng = rand::thread_rng().gen_range(0, SHAPES.len());
-        let shape = SHAPES[rng];
-        let color = match rng {
+    /// The piece `SHAPES[index]` at the top of the board
+    pub fn spawn(index: usize) -> Piece {
+        let color = match index {
             0 => Color::Color1,
             1 => Color::Color2,
             2 => Color::Color3,
@@ -439,16 +746,16 @@ impl Piece {
             5 => Color::Color6,
             _ => Color::Color7,
         };
-        let mut display_shape = [[0; 4]; 4];
-        for [x, y] in &shape {
-            display_shape[(y + 2) as usize][(x + 2) as usize] = color as u32;
-        }
-        Piece {
-            shape: SHAPES[rng],
-            display_shape,
-            color,
-            position: [COLS as i32 / 2, ROWS as i32 - 1],
-        }
+        Piece::new(index, color, SPAWN)
+    }
+
+    /// Which of `SHAPES` the piece is
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    pub fn get_rotation(&self) -> Rotation {
+        self.rotation
     }
 
     pub fn get_shape(&self) -> Shape {
@@ -464,21 +771,17 @@ impl Piece {
         self.position[1] += y;
     }
 
-    fn set_position(&mut self, position: Point) {
-        self.position = position;
-    }
-
-    fn rotate(&mut self, clockwise: bool) {
-        if self.shape != SHAPES[1] {
+    /// Turns the blocks `quarters` quarter turns clockwise around the pivot of the piece, without any kicks
+    fn rotate(&mut self, quarters: u8) {
+        let [px, py] = srs::pivot(self.get_index());
+        for _ in 0..quarters % 4 {
             for block in &mut self.shape {
-                block.swap(0, 1);
-                if clockwise {
-                    block[1] *= -1
-                } else {
-                    block[0] *= -1
-                }
+                // (x, y) turns to (y, -x) around the pivot, in doubled coordinates
+                let (x, y) = (2 * block[0] - px, 2 * block[1] - py);
+                *block = [(y + px) / 2, (-x + py) / 2];
             }
         }
+        self.rotation = self.rotation.turn(quarters);
     }
 
     pub fn pos_on_board(&self) -> Shape {
@@ -0,0 +1,127 @@
+use super::{GameRng, SHAPES};
+use rand::{Rng, RngCore};
+
+/// How the pieces of a player are drawn
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum RandomizerKind {
+    /// Every piece once in a shuffled bag of seven, then a new bag
+    #[default]
+    Bag7 = 0,
+    /// Every piece twice in a bag of fourteen, some repeats but still no long droughts
+    Bag14 = 1,
+    /// Rerolls pieces that were among the last four dealt, like TGM
+    History = 2,
+    /// Any piece at any time
+    Random = 3,
+}
+
+/// The randomizers by the names used on the command line, in the order they're cycled through
+pub const RANDOMIZER_NAMES: [(&str, RandomizerKind); 4] = [
+    ("7-bag", RandomizerKind::Bag7),
+    ("14-bag", RandomizerKind::Bag14),
+    ("history", RandomizerKind::History),
+    ("random", RandomizerKind::Random),
+];
+
+/// Times the history randomizer rolls before it settles for a piece it dealt recently
+const HISTORY_ROLLS: usize = 6;
+/// What the history starts out as, S and Z so the first pieces are less likely to be those
+const FIRST_HISTORY: [u8; 4] = [4, 3, 4, 3];
+/// The pieces the history randomizer starts with, never S, Z or O: I, T, J and L
+const FIRST_PIECES: [u8; 4] = [0, 2, 5, 6];
+
+impl RandomizerKind {
+    pub fn parse(name: &str) -> Option<RandomizerKind> {
+        RANDOMIZER_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, kind)| *kind)
+    }
+
+    pub fn name(self) -> &'static str {
+        RANDOMIZER_NAMES[self as usize].0
+    }
+
+    pub fn from_u8(value: u8) -> Option<RandomizerKind> {
+        RANDOMIZER_NAMES.get(value as usize).map(|(_, kind)| *kind)
+    }
+
+    /// The randomizer after this one
+    pub fn next(self) -> RandomizerKind {
+        RANDOMIZER_NAMES[(self as usize + 1) % RANDOMIZER_NAMES.len()].1
+    }
+}
+
+/// Deals the pieces of one player as indices into `SHAPES`.
+/// It draws from its own seeded rng, so the same seed always gives the same pieces, whatever else the player rolls for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Randomizer {
+    pub(super) kind: RandomizerKind,
+    pub(super) rng: GameRng,
+    pub(super) bag: Vec<u8>, // pieces left in the bag, dealt from the back
+    pub(super) history: [u8; 4], // the last pieces dealt, newest first
+    pub(super) dealt_first: bool, // the history randomizer deals its first piece differently
+}
+
+impl Randomizer {
+    pub fn new(kind: RandomizerKind, seed: u64) -> Randomizer {
+        Randomizer {
+            kind,
+            // a different stream than the one the player rolls its garbage holes from
+            rng: GameRng::new(seed ^ 0x7069_6563_6573),
+            bag: Vec::new(),
+            history: FIRST_HISTORY,
+            dealt_first: false,
+        }
+    }
+
+    pub fn get_kind(&self) -> RandomizerKind {
+        self.kind
+    }
+
+    /// The index of the next piece
+    pub fn next_shape(&mut self) -> usize {
+        let shape = match self.kind {
+            RandomizerKind::Bag7 => self.bag_piece(1),
+            RandomizerKind::Bag14 => self.bag_piece(2),
+            RandomizerKind::History => self.history_piece(),
+            RandomizerKind::Random => self.rng.gen_range(0, SHAPES.len()) as u8,
+        };
+        self.dealt_first = true;
+        shape as usize
+    }
+
+    /// Deals from a bag holding every piece `copies` times, a new one is shuffled once it's empty
+    fn bag_piece(&mut self, copies: usize) -> u8 {
+        if self.bag.is_empty() {
+            self.bag = (0..SHAPES.len() as u8)
+                .cycle()
+                .take(SHAPES.len() * copies)
+                .collect();
+            // Fisher-Yates with our own rng, so every machine shuffles the same way
+            for i in (1..self.bag.len()).rev() {
+                let j = (self.rng.next_u64() % (i as u64 + 1)) as usize;
+                self.bag.swap(i, j);
+            }
+        }
+        self.bag.pop().unwrap_or(0)
+    }
+
+    fn history_piece(&mut self) -> u8 {
+        let shape = if self.dealt_first {
+            let mut shape = 0;
+            for _ in 0..HISTORY_ROLLS {
+                shape = self.rng.gen_range(0, SHAPES.len()) as u8;
+                if !self.history.contains(&shape) {
+                    break;
+                }
+            }
+            shape
+        } else {
+            FIRST_PIECES[self.rng.gen_range(0, FIRST_PIECES.len())]
+        };
+        self.history.rotate_right(1);
+        self.history[0] = shape;
+        shape
+    }
+}
@@ -0,0 +1,331 @@
+use super::{
+    Attack, AttackTable, Clear, Color, GameRng, Piece, Player, Randomizer, RandomizerKind,
+    Rotation, Spin, COLS, ROWS, SHAPES,
+};
+use std::io::Read;
+
+/// Player state as bytes, used to bring a reconnecting peer back to the current state.
+/// The shadow piece isn't included, it's recalculated when the player is read.
+impl Player {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for row in &self.board {
+            buf.extend(row.iter().map(|block| *block as u8));
+        }
+        buf.push(self.incoming.len() as u8);
+        for attack in &self.incoming {
+            put_attack(&mut buf, attack);
+        }
+        match &self.outgoing {
+            Some(attack) => {
+                buf.push(1);
+                put_attack(&mut buf, attack);
+            }
+            None => buf.extend_from_slice(&[0, 0, 0, 0]),
+        }
+        put_piece(&mut buf, &self.current_piece);
+        match &self.saved_piece {
+            Some(piece) => {
+                buf.push(1);
+                put_piece(&mut buf, piece);
+            }
+            None => buf.push(0),
+        }
+        buf.push(self.has_saved as u8);
+        put_piece(&mut buf, &self.next_piece);
+        buf.extend_from_slice(&(self.score as u32).to_be_bytes());
+        buf.push(self.lost as u8);
+        buf.extend_from_slice(&self.rng.get_state().to_be_bytes());
+        put_randomizer(&mut buf, &self.randomizer);
+        buf.extend_from_slice(&self.tick_rate.to_be_bytes());
+        buf.extend_from_slice(&self.gravity.to_be_bytes());
+        buf.extend_from_slice(&self.gravity_timer.to_be_bytes());
+        buf.push(self.grace_count);
+        match self.attacker {
+            Some(side) => buf.extend_from_slice(&[1, side]),
+            None => buf.extend_from_slice(&[0, 0]),
+        }
+        buf.push(self.knockouts);
+        buf.push(self.last_turn.map_or(0, |kick| 1 + kick as u8));
+        match self.last_clear {
+            Some(clear) => {
+                buf.extend_from_slice(&[1, clear.lines, clear.spin.map_or(0, |spin| spin as u8)])
+            }
+            None => buf.extend_from_slice(&[0, 0, 0]),
+        }
+        buf.extend_from_slice(&self.callout_timer.to_be_bytes());
+        buf.extend_from_slice(&self.attack_table.combo);
+        buf.push(self.attack_table.back_to_back);
+        buf.push(self.streak);
+        buf.push(self.back_to_back as u8);
+        buf
+    }
+
+    /// FNV-1a hash of everything in `to_bytes`, the same on every machine so peers can compare their states
+    pub fn checksum(&self) -> u64 {
+        self.to_bytes()
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            })
+    }
+
+    /// The whole state as text, one value per line and the board last, so two dumps can be compared with diff
+    pub fn dump(&self) -> String {
+        let mut text = format!("checksum {:016x}\n", self.checksum());
+        text += &format!("score {}\n", self.score);
+        text += &format!("lost {}\n", self.lost);
+        text += &format!("rng {:016x}\n", self.rng.get_state());
+        text += &format!("randomizer {}\n", dump_randomizer(&self.randomizer));
+        text += &format!(
+            "gravity {} ticks, timer {}\n",
+            self.gravity, self.gravity_timer
+        );
+        text += &format!("grace count {}\n", self.grace_count);
+        text += &format!("attacker {:?}\n", self.attacker);
+        text += &format!("knockouts {}\n", self.knockouts);
+        text += &format!("last turn {:?}\n", self.last_turn);
+        text += &format!(
+            "last clear {:?} shown {} more ticks\n",
+            self.last_clear, self.callout_timer
+        );
+        text += &format!("attack table {:?}\n", self.attack_table);
+        text += &format!(
+            "streak {} back to back {}\n",
+            self.streak, self.back_to_back
+        );
+        text += &format!("current {}\n", dump_piece(&self.current_piece));
+        text += &format!("next {}\n", dump_piece(&self.next_piece));
+        match &self.saved_piece {
+            Some(piece) => text += &format!("saved {}\n", dump_piece(piece)),
+            None => text += "saved none\n",
+        }
+        text += &format!("has saved {}\n", self.has_saved);
+        text += &format!("incoming {:?}\n", self.incoming);
+        text += &format!("outgoing {:?}\n", self.outgoing);
+        for row in &self.board {
+            text.extend(row.iter().map(|block| match block {
+                0 => '.',
+                _ => std::char::from_digit(*block, 16).unwrap_or('?'),
+            }));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Reads a player written by `to_bytes`, returns None if the bytes don't make sense
+    pub fn from_bytes(mut bytes: &[u8]) -> Option<Player> {
+        let r = &mut bytes;
+        let mut board = [[0; COLS]; ROWS];
+        for row in &mut board {
+            for block in row.iter_mut() {
+                *block = read_u8(r)? as u32;
+            }
+        }
+        let mut incoming = Vec::new();
+        for _ in 0..read_u8(r)? {
+            incoming.push(read_attack(r)?);
+        }
+        let outgoing = match read_u8(r)? {
+            0 => {
+                read_attack(r)?;
+                None
+            }
+            _ => Some(read_attack(r)?),
+        };
+        let current_piece = read_piece(r)?;
+        let saved_piece = match read_u8(r)? {
+            0 => None,
+            _ => Some(read_piece(r)?),
+        };
+        let has_saved = read_u8(r)? != 0;
+        let next_piece = read_piece(r)?;
+        let score = read_u32(r)? as usize;
+        let lost = read_u8(r)? != 0;
+        let rng = GameRng::new(read_u64(r)?);
+        let randomizer = read_randomizer(r)?;
+        let tick_rate = read_u32(r)?;
+        let gravity = read_u32(r)?;
+        let gravity_timer = read_u32(r)?;
+        let grace_count = read_u8(r)?;
+        let attacker = match (read_u8(r)?, read_u8(r)?) {
+            (0, _) => None,
+            (_, side) => Some(side),
+        };
+        let knockouts = read_u8(r)?;
+        let last_turn = match read_u8(r)? {
+            0 => None,
+            kick => Some(kick == 2),
+        };
+        let last_clear = match (read_u8(r)?, read_u8(r)?, read_u8(r)?) {
+            (0, _, _) => None,
+            (_, lines, spin) => Some(Clear {
+                lines,
+                spin: match spin {
+                    0 => None,
+                    1 => Some(Spin::Mini),
+                    _ => Some(Spin::Full),
+                },
+            }),
+        };
+        let callout_timer = read_u32(r)?;
+        let mut attack_table = AttackTable::default();
+        for lines in &mut attack_table.combo {
+            *lines = read_u8(r)?;
+        }
+        attack_table.back_to_back = read_u8(r)?;
+        let streak = read_u8(r)?;
+        let back_to_back = read_u8(r)? != 0;
+        if !r.is_empty() {
+            return None;
+        }
+        let mut player = Player {
+            board,
+            incoming,
+            outgoing,
+            current_piece,
+            piece_shadow: None,
+            saved_piece,
+            has_saved,
+            next_piece,
+            score,
+            lost,
+            rng,
+            randomizer,
+            tick_rate,
+            gravity,
+            gravity_timer,
+            grace_count,
+            attacker,
+            knockouts,
+            last_turn,
+            last_clear,
+            callout_timer,
+            attack_table,
+            streak,
+            back_to_back,
+        };
+        player.shadow_piece();
+        Some(player)
+    }
+}
+
+/// Pieces are sent as their color, since that decides the shape it spawned with, then their rotation state,
+/// rotated blocks and position
+fn put_piece(buf: &mut Vec<u8>, piece: &Piece) {
+    buf.push(piece.index as u8);
+    buf.push(piece.color as u8);
+    buf.push(piece.rotation as u8);
+    for [x, y] in &piece.shape {
+        buf.push(*x as i8 as u8);
+        buf.push(*y as i8 as u8);
+    }
+    buf.push(piece.position[0] as i8 as u8);
+    buf.push(piece.position[1] as i8 as u8);
+}
+
+/// Randomizers are sent as [kind: u8][rng: u64][bag length: u8][bag: u8...][history: 4 bytes][dealt first: u8]
+fn put_randomizer(buf: &mut Vec<u8>, randomizer: &Randomizer) {
+    buf.push(randomizer.kind as u8);
+    buf.extend_from_slice(&randomizer.rng.get_state().to_be_bytes());
+    buf.push(randomizer.bag.len() as u8);
+    buf.extend_from_slice(&randomizer.bag);
+    buf.extend_from_slice(&randomizer.history);
+    buf.push(randomizer.dealt_first as u8);
+}
+
+fn put_attack(buf: &mut Vec<u8>, attack: &Attack) {
+    buf.extend_from_slice(&[attack.lines, attack.hole, attack.delay]);
+}
+
+fn dump_piece(piece: &Piece) -> String {
+    format!(
+        "shape {} color {} at {:?} turned {:?} blocks {:?}",
+        piece.index, piece.color as u8, piece.position, piece.rotation, piece.shape
+    )
+}
+
+fn dump_randomizer(randomizer: &Randomizer) -> String {
+    format!(
+        "{} rng {:016x} bag {:?} history {:?}",
+        randomizer.kind.name(),
+        randomizer.rng.get_state(),
+        randomizer.bag,
+        randomizer.history
+    )
+}
+
+fn read_piece(r: &mut &[u8]) -> Option<Piece> {
+    let index = read_u8(r)? as usize;
+    if index >= SHAPES.len() {
+        return None;
+    }
+    let color = match read_u8(r)? {
+        1 => Color::Color1,
+        2 => Color::Color2,
+        3 => Color::Color3,
+        4 => Color::Color4,
+        5 => Color::Color5,
+        6 => Color::Color6,
+        7 => Color::Color7,
+        _ => return None,
+    };
+    let mut piece = Piece::new(index, color, [0, 0]);
+    piece.rotation = Rotation::from_u8(read_u8(r)?)?;
+    for block in &mut piece.shape {
+        *block = [read_u8(r)? as i8 as i32, read_u8(r)? as i8 as i32];
+    }
+    piece.position = [read_u8(r)? as i8 as i32, read_u8(r)? as i8 as i32];
+    Some(piece)
+}
+
+fn read_randomizer(r: &mut &[u8]) -> Option<Randomizer> {
+    let kind = RandomizerKind::from_u8(read_u8(r)?)?;
+    let rng = GameRng::new(read_u64(r)?);
+    let mut bag = Vec::new();
+    for _ in 0..read_u8(r)? {
+        let shape = read_u8(r)?;
+        if shape as usize >= SHAPES.len() {
+            return None;
+        }
+        bag.push(shape);
+    }
+    let mut history = [0; 4];
+    for shape in &mut history {
+        *shape = read_u8(r)?;
+    }
+    let dealt_first = read_u8(r)? != 0;
+    Some(Randomizer {
+        kind,
+        rng,
+        bag,
+        history,
+        dealt_first,
+    })
+}
+
+fn read_attack(r: &mut &[u8]) -> Option<Attack> {
+    Some(Attack {
+        lines: read_u8(r)?,
+        hole: read_u8(r)?,
+        delay: read_u8(r)?,
+    })
+}
+
+fn read_u8(r: &mut &[u8]) -> Option<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf).ok()?;
+    Some(buf[0])
+}
+
+fn read_u32(r: &mut &[u8]) -> Option<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf).ok()?;
+    Some(u32::from_be_bytes(buf))
+}
+
+fn read_u64(r: &mut &[u8]) -> Option<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf).ok()?;
+    Some(u64::from_be_bytes(buf))
+}
@@ -0,0 +1,106 @@
+use super::Point;
+
+/// The four rotation states of the Super Rotation System, named after how far a piece is turned from the way it spawned
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rotation {
+    /// As spawned, "0" in the SRS tables
+    Spawn = 0,
+    /// A quarter turn clockwise, "R"
+    Right = 1,
+    /// Half a turn, "2"
+    Flipped = 2,
+    /// A quarter turn counterclockwise, "L"
+    Left = 3,
+}
+
+const ROTATIONS: [Rotation; 4] = [
+    Rotation::Spawn,
+    Rotation::Right,
+    Rotation::Flipped,
+    Rotation::Left,
+];
+
+impl Rotation {
+    /// The state after `quarters` quarter turns clockwise
+    pub fn turn(self, quarters: u8) -> Rotation {
+        ROTATIONS[(self as usize + quarters as usize) % 4]
+    }
+
+    pub fn from_u8(value: u8) -> Option<Rotation> {
+        ROTATIONS.get(value as usize).copied()
+    }
+}
+
+/// Index of the I and O pieces in `SHAPES`, every other piece uses the JLSTZ tables
+const I: usize = 0;
+const O: usize = 1;
+
+/// The point a piece turns around, doubled so the I and O pieces can turn around the corner between four blocks.
+/// The blocks of a piece are relative to the block at its position, so JLSTZ turn around that block.
+pub fn pivot(shape: usize) -> Point {
+    match shape {
+        I => [1, -1],
+        O => [1, 1],
+        _ => [0, 0],
+    }
+}
+
+/// The last kick of a quarter turn, a T that needed it to get in is always a full T-spin
+pub const T_SPIN_KICK: usize = 4;
+
+/// The offsets tried in order when a piece turned from `from` to `to` doesn't fit where it is, the first one that fits is used.
+/// Up is +y, like on the board.
+pub fn kicks(shape: usize, from: Rotation, to: Rotation) -> &'static [Point] {
+    use Rotation::*;
+    match (shape, from, to) {
+        (O, _, _) => &[[0, 0]],
+        (_, Spawn, Flipped) => &HALF_TURN_KICKS[0],
+        (_, Right, Left) => &HALF_TURN_KICKS[1],
+        (_, Flipped, Spawn) => &HALF_TURN_KICKS[2],
+        (_, Left, Right) => &HALF_TURN_KICKS[3],
+        (I, _, _) => quarter_turn(&I_KICKS, from, to),
+        _ => quarter_turn(&JLSTZ_KICKS, from, to),
+    }
+}
+
+/// Looks up a quarter turn in a table ordered 0->R, R->0, R->2, 2->R, 2->L, L->2, L->0, 0->L
+fn quarter_turn(table: &'static [[Point; 5]; 8], from: Rotation, to: Rotation) -> &'static [Point] {
+    let row = if to == from.turn(1) {
+        // clockwise, 0->R is row 0, R->2 row 2, 2->L row 4 and L->0 row 6
+        2 * from as usize
+    } else {
+        // counterclockwise, R->0 is row 1, 2->R row 3, L->2 row 5 and 0->L row 7
+        (2 * from as usize + 7) % 8
+    };
+    &table[row]
+}
+
+const JLSTZ_KICKS: [[Point; 5]; 8] = [
+    [[0, 0], [-1, 0], [-1, 1], [0, -2], [-1, -2]], // 0->R
+    [[0, 0], [1, 0], [1, -1], [0, 2], [1, 2]],     // R->0
+    [[0, 0], [1, 0], [1, -1], [0, 2], [1, 2]],     // R->2
+    [[0, 0], [-1, 0], [-1, 1], [0, -2], [-1, -2]], // 2->R
+    [[0, 0], [1, 0], [1, 1], [0, -2], [1, -2]],    // 2->L
+    [[0, 0], [-1, 0], [-1, -1], [0, 2], [-1, 2]],  // L->2
+    [[0, 0], [-1, 0], [-1, -1], [0, 2], [-1, 2]],  // L->0
+    [[0, 0], [1, 0], [1, 1], [0, -2], [1, -2]],    // 0->L
+];
+
+const I_KICKS: [[Point; 5]; 8] = [
+    [[0, 0], [-2, 0], [1, 0], [-2, -1], [1, 2]], // 0->R
+    [[0, 0], [2, 0], [-1, 0], [2, 1], [-1, -2]], // R->0
+    [[0, 0], [-1, 0], [2, 0], [-1, 2], [2, -1]], // R->2
+    [[0, 0], [1, 0], [-2, 0], [1, -2], [-2, 1]], // 2->R
+    [[0, 0], [2, 0], [-1, 0], [2, 1], [-1, -2]], // 2->L
+    [[0, 0], [-2, 0], [1, 0], [-2, -1], [1, 2]], // L->2
+    [[0, 0], [1, 0], [-2, 0], [1, -2], [-2, 1]], // L->0
+    [[0, 0], [-1, 0], [2, 0], [-1, 2], [2, -1]], // 0->L
+];
+
+/// Half turns aren't part of SRS, these are the kicks most modern games use for them, the same for every piece
+const HALF_TURN_KICKS: [[Point; 6]; 4] = [
+    [[0, 0], [0, 1], [1, 1], [-1, 1], [1, 0], [-1, 0]], // 0->2
+    [[0, 0], [1, 0], [1, 2], [1, 1], [0, 2], [0, 1]],   // R->L
+    [[0, 0], [0, -1], [-1, -1], [1, -1], [-1, 0], [1, 0]], // 2->0
+    [[0, 0], [-1, 0], [-1, 2], [-1, 1], [0, 2], [0, 1]], // L->R
+];
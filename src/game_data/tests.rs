@@ -1,36 +1,114 @@
-use super::{Color, Piece, Player, COLS, ROWS, SHAPES};
+use super::{
+    Attack, AttackTable, Clear, Color, Move, Piece, Player, Randomizer, RandomizerKind, Rotation,
+    Spin, COLS, ROWS, SHAPES,
+};
 use std::thread;
 
 #[test]
 fn rotation() {
-    let mut player = Player::new(0);
-    player.current_piece = Piece::new(SHAPES[2], Color::Color1, [2, 2]);
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
+    player.current_piece = Piece::new(2, Color::Color1, [2, 2]);
     assert_eq!(
-        [[1, 2], [2, 2], [2, 1], [3, 2]],
+        [[1, 2], [2, 2], [3, 2], [2, 3]],
         player.current_piece.pos_on_board()
     );
-    player.current_piece.rotate(true);
+    player.current_piece.rotate(1);
+    assert_eq!(Rotation::Right, player.current_piece.get_rotation());
     assert_eq!(
-        [[2, 3], [2, 2], [1, 2], [2, 1]],
+        [[2, 3], [2, 2], [2, 1], [3, 2]],
         player.current_piece.pos_on_board()
     );
-    player.current_piece.rotate(true);
+    player.current_piece.rotate(1);
+    assert_eq!(Rotation::Flipped, player.current_piece.get_rotation());
     assert_eq!(
-        [[3, 2], [2, 2], [2, 3], [1, 2]],
+        [[3, 2], [2, 2], [1, 2], [2, 1]],
         player.current_piece.pos_on_board()
     );
-    player.current_piece.rotate(true);
+    player.current_piece.rotate(1);
+    assert_eq!(Rotation::Left, player.current_piece.get_rotation());
     assert_eq!(
-        [[2, 1], [2, 2], [3, 2], [2, 3]],
+        [[2, 1], [2, 2], [2, 3], [1, 2]],
+        player.current_piece.pos_on_board()
+    );
+    player.current_piece.rotate(1);
+    assert_eq!(Rotation::Spawn, player.current_piece.get_rotation());
+
+    // the I piece turns around the middle of its blocks and the O piece doesn't move at all
+    let mut i = Piece::spawn(0);
+    i.rotate(1);
+    assert_eq!([[5, 23], [5, 22], [5, 21], [5, 20]], i.pos_on_board());
+    i.rotate(1);
+    assert_eq!([[6, 21], [5, 21], [4, 21], [3, 21]], i.pos_on_board());
+    let mut o = Piece::spawn(1);
+    let cells = o.pos_on_board();
+    o.rotate(3);
+    let mut turned = o.pos_on_board();
+    turned.sort_unstable();
+    let mut cells = cells.to_vec();
+    cells.sort_unstable();
+    assert_eq!(cells, turned);
+}
+
+#[test]
+fn wall_kicks() {
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
+    // a T against the left wall is kicked one to the right when it can't turn in place
+    player.current_piece = Piece::new(2, Color::Color3, [0, 5]);
+    player.rotate_current(true);
+    assert_eq!(
+        [[0, 6], [0, 5], [0, 4], [1, 5]],
+        player.current_piece.pos_on_board()
+    );
+    player.rotate_current(true);
+    assert_eq!(Rotation::Flipped, player.current_piece.get_rotation());
+    assert_eq!(
+        [[2, 5], [1, 5], [0, 5], [1, 4]],
+        player.current_piece.pos_on_board()
+    );
+
+    // a vertical I on the right wall turns back to flat inside the board
+    player.current_piece = Piece::new(0, Color::Color1, [8, 5]);
+    player.rotate_current(true);
+    assert_eq!(
+        [[9, 6], [9, 5], [9, 4], [9, 3]],
+        player.current_piece.pos_on_board()
+    );
+    player.rotate_current(true);
+    assert_eq!(Rotation::Flipped, player.current_piece.get_rotation());
+    assert_eq!(
+        [[9, 4], [8, 4], [7, 4], [6, 4]],
+        player.current_piece.pos_on_board()
+    );
+
+    // half a turn on the floor lifts the T up a row
+    player.current_piece = Piece::new(2, Color::Color3, [4, 0]);
+    player.apply_move(Move::Rotate180);
+    assert_eq!(Rotation::Flipped, player.current_piece.get_rotation());
+    assert_eq!(
+        [[5, 1], [4, 1], [3, 1], [4, 0]],
+        player.current_piece.pos_on_board()
+    );
+
+    // with no room for any of the kicks the piece stays as it was
+    player.board = [[Color::Fixed as u32; COLS]; ROWS];
+    for x in 3..6 {
+        player.board[1][x] = 0;
+    }
+    player.board[2][4] = 0;
+    player.current_piece = Piece::new(2, Color::Color3, [4, 1]);
+    player.rotate_current(false);
+    assert_eq!(Rotation::Spawn, player.current_piece.get_rotation());
+    assert_eq!(
+        [[3, 1], [4, 1], [5, 1], [4, 2]],
         player.current_piece.pos_on_board()
     );
 }
 
 #[test]
 fn line_clear() {
-    let mut player = Player::new(0);
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
     for i in 0..COLS {
-        player.current_piece = Piece::new(SHAPES[0], Color::Color1, [i as i32, 1]);
+        player.current_piece = Piece::new(0, Color::Color1, [i as i32, 1]);
         player.rotate_current(true);
         let mut loop_var = 0;
         println!("-------------------------------------------");
@@ -86,7 +164,7 @@ fn line_clear() {
 
 #[test] //not real test!
 fn console_debug() {
-    let mut player = Player::new(0);
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
     loop {
         print!("{}[2J", 27 as char);
         let mut loop_var = 0;
@@ -118,3 +196,217 @@ fn console_debug() {
     println!("game lost!");
     assert_eq!(true, player.lost);
 }
+
+#[test]
+fn same_seed_same_game() {
+    let mut p1 = Player::new(5, 1234, 60, RandomizerKind::Bag7);
+    let mut p2 = Player::new(5, 1234, 60, RandomizerKind::Bag7);
+    for tick in 0..2000 {
+        if tick % 7 == 0 {
+            p1.apply_move(Move::HardDrop);
+            p2.apply_move(Move::HardDrop);
+        }
+        if tick % 50 == 0 {
+            let attack = Attack {
+                lines: 2,
+                hole: (tick / 50 % 10) as u8,
+                delay: 1,
+            };
+            p1.add_incoming(attack);
+            p2.add_incoming(attack);
+        }
+        p1.update();
+        p2.update();
+    }
+    assert_eq!(p1.get_board(), p2.get_board());
+    assert_eq!(p1.get_score(), p2.get_score());
+    assert_eq!(
+        p1.get_next_piece().get_shape(),
+        p2.get_next_piece().get_shape()
+    );
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let mut player = Player::new(5, 99, 60, RandomizerKind::Bag7);
+    player.apply_move(Move::HardDrop);
+    player.apply_move(Move::Save);
+    player.add_incoming(Attack {
+        lines: 3,
+        hole: 4,
+        delay: 2,
+    });
+    for _ in 0..100 {
+        player.update();
+    }
+    let copy = Player::from_bytes(&player.to_bytes()).unwrap();
+    assert_eq!(player.to_bytes(), copy.to_bytes());
+    assert_eq!(player.get_board(), copy.get_board());
+    assert!(Player::from_bytes(&player.to_bytes()[1..]).is_none());
+}
+
+#[test]
+fn randomizers() {
+    let deal = |kind, seed, count| {
+        let mut randomizer = Randomizer::new(kind, seed);
+        (0..count)
+            .map(|_| randomizer.next_shape())
+            .collect::<Vec<usize>>()
+    };
+    // every bag holds each piece as often as the others
+    for (kind, size) in &[(RandomizerKind::Bag7, 7), (RandomizerKind::Bag14, 14)] {
+        let pieces = deal(*kind, 5, size * 20);
+        for bag in pieces.chunks(*size) {
+            for shape in 0..SHAPES.len() {
+                let count = bag.iter().filter(|piece| **piece == shape).count();
+                assert_eq!(size / 7, count);
+            }
+        }
+    }
+    // the history randomizer never starts with S, Z or O and rarely repeats a recent piece
+    for seed in 0..50 {
+        assert!(![1, 3, 4].contains(&deal(RandomizerKind::History, seed, 1)[0]));
+    }
+    let pieces = deal(RandomizerKind::History, 5, 1000);
+    let repeats = pieces.windows(2).filter(|pair| pair[0] == pair[1]).count();
+    assert!(repeats < 20);
+    // the same seed deals the same pieces
+    for kind in &[RandomizerKind::Random, RandomizerKind::History] {
+        assert_eq!(deal(*kind, 7, 100), deal(*kind, 7, 100));
+        assert_ne!(deal(*kind, 7, 100), deal(*kind, 8, 100));
+    }
+}
+
+#[test]
+fn t_spins() {
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
+    // a T-spin double slot: the two bottom rows are full but for the T, with a block hanging over it
+    for x in 0..COLS {
+        player.board[0][x] = Color::Fixed as u32;
+        player.board[1][x] = Color::Fixed as u32;
+    }
+    player.board[0][4] = 0;
+    for x in 3..6 {
+        player.board[1][x] = 0;
+    }
+    player.board[2][3] = Color::Fixed as u32;
+    player.current_piece = Piece::new(2, Color::Color3, [4, 1]);
+    player.apply_move(Move::Rotate180);
+    player.drop_current();
+    player.move_tick();
+    let tsd = Clear {
+        lines: 2,
+        spin: Some(Spin::Full),
+    };
+    assert_eq!(Some(tsd), player.get_callout());
+    assert_eq!("T-SPIN DOUBLE", tsd.name());
+    assert_eq!(4, player.take_outgoing().unwrap().lines);
+    assert_eq!(12, player.get_score());
+
+    // kicked against the wall with only one of the corners it points to filled is a mini
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
+    player.board[0][0] = Color::Fixed as u32;
+    player.board[1][1] = Color::Fixed as u32;
+    player.current_piece = Piece::new(2, Color::Color3, [1, 2]);
+    player.rotate_current(true);
+    assert_eq!(
+        [[0, 3], [0, 2], [0, 1], [1, 2]],
+        player.current_piece.pos_on_board()
+    );
+    player.drop_current();
+    player.move_tick();
+    assert_eq!(Some(Spin::Mini), player.get_callout().unwrap().spin);
+    assert_eq!(1, player.get_score());
+
+    // a T that moved after it was turned didn't spin, even when it ends up in the same spot
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
+    player.board[0][0] = Color::Fixed as u32;
+    player.board[1][1] = Color::Fixed as u32;
+    player.current_piece = Piece::new(2, Color::Color3, [1, 4]);
+    player.rotate_current(true);
+    player.move_current(-1, 0);
+    player.drop_current();
+    assert_eq!(
+        [[0, 3], [0, 2], [0, 1], [1, 2]],
+        player.current_piece.pos_on_board()
+    );
+    player.move_tick();
+    assert_eq!(None, player.get_callout());
+}
+
+#[test]
+fn combos_and_back_to_back() {
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
+    player.process_score(4, None);
+    assert_eq!(4, player.take_outgoing().unwrap().lines);
+    assert_eq!((0, true), (player.get_combo(), player.get_back_to_back()));
+
+    // a second tetris in a row is combo 1 and back-to-back
+    player.process_score(4, None);
+    assert_eq!(4 + 1 + 1, player.take_outgoing().unwrap().lines);
+    assert_eq!(8 + 13, player.get_score());
+
+    // a single keeps the combo going but ends the back-to-back
+    player.process_score(1, None);
+    assert_eq!(1, player.take_outgoing().unwrap().lines);
+    assert_eq!((2, false), (player.get_combo(), player.get_back_to_back()));
+
+    // a piece that clears nothing ends the combo
+    player.process_score(0, None);
+    assert_eq!(0, player.get_combo());
+    player.process_score(4, None);
+    assert_eq!(4, player.take_outgoing().unwrap().lines);
+
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
+    player.set_attack_table(AttackTable {
+        combo: [0, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3],
+        back_to_back: 0,
+    });
+    player.process_score(2, Some(Spin::Full));
+    player.take_outgoing();
+    player.process_score(2, Some(Spin::Full));
+    assert_eq!(4 + 3, player.take_outgoing().unwrap().lines);
+
+    // a table past the limits only sends as much as fits in an attack
+    let table = AttackTable {
+        combo: [255; 12],
+        back_to_back: 255,
+    };
+    assert!(!table.is_valid());
+    player.set_attack_table(table);
+    player.process_score(2, Some(Spin::Full));
+    assert_eq!(255, player.take_outgoing().unwrap().lines);
+    // a score past the last level keeps the fastest gravity
+    for _ in 0..20 {
+        player.process_score(4, None);
+    }
+    assert!(player.get_score() >= 100);
+}
+
+#[test]
+fn garbage_cancels_incoming() {
+    let mut player = Player::new(0, 0, 60, RandomizerKind::Bag7);
+    for (lines, hole) in [(2, 1), (3, 2)].iter() {
+        player.add_incoming(Attack {
+            lines: *lines,
+            hole: *hole,
+            delay: 10,
+        });
+    }
+    // a tetris cancels the 2 lines of the oldest attack and 2 of the 3 of the next, nothing is left to send
+    player.process_score(4, None);
+    assert_eq!(None, player.take_outgoing());
+    assert_eq!(1, player.get_incoming().len());
+    assert_eq!(
+        (1, 2),
+        (
+            player.get_incoming()[0].lines,
+            player.get_incoming()[0].hole
+        )
+    );
+
+    // the back-to-back tetris with combo 1 sends 6, one of them goes to what's left
+    player.process_score(4, None);
+    assert_eq!(5, player.take_outgoing().unwrap().lines);
+    assert!(player.get_incoming().is_empty());
+}
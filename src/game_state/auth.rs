@@ -0,0 +1,218 @@
+use super::protocol::{Message, ProtocolError};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::{Read, Write};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A key derived from a room password, the password itself never goes over the wire
+pub type Key = [u8; 32];
+/// Random bytes each side adds to the handshake, so no two connections are sealed with the same key
+pub type Nonce = [u8; 16];
+/// Length of the MAC after every sealed message
+pub const TAG_LEN: usize = 32;
+/// Rounds the password is hashed for, so guessing it from a recorded handshake is slow
+const KEY_ROUNDS: u32 = 10_000;
+
+/// Turns a room password into the key both sides prove they know, PBKDF2 with a fixed salt
+pub fn derive_key(password: &str) -> Key {
+    let mut round = mac(password.as_bytes(), &[b"TTRS room", &1u32.to_be_bytes()]);
+    let mut key = round;
+    for _ in 1..KEY_ROUNDS {
+        round = mac(password.as_bytes(), &[&round]);
+        for (byte, other) in key.iter_mut().zip(&round) {
+            *byte ^= other;
+        }
+    }
+    key
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Checks a MAC in constant time
+fn verify(key: &[u8], parts: &[&[u8]], tag: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.verify_slice(tag).is_ok()
+}
+
+/// Host side of the handshake, after the first message of a connection: asks the peer to prove it knows the password
+/// and proves we know it too. Returns the seal for everything sent after it.
+pub fn challenge(stream: &mut (impl Read + Write), key: &Key) -> Result<Seal, ProtocolError> {
+    let host_nonce: Nonce = rand::random();
+    Message::Challenge { nonce: host_nonce }.write_to(stream)?;
+    let (client_nonce, proof) = match Message::read_from(stream)? {
+        Message::Proof { nonce, mac } => (nonce, mac),
+        message => return Err(ProtocolError::Unexpected(message)),
+    };
+    if !verify(key, &[b"client", &host_nonce, &client_nonce], &proof) {
+        let reason = "wrong password".to_string();
+        Message::Reject { reason }.write_to(stream).ok();
+        return Err(ProtocolError::Unauthorized(
+            "the client gave the wrong password",
+        ));
+    }
+    Message::Proof {
+        nonce: host_nonce,
+        mac: mac(key, &[b"host", &host_nonce, &client_nonce]),
+    }
+    .write_to(stream)?;
+    Ok(Seal::new(key, &host_nonce, &client_nonce, true))
+}
+
+/// Client side of the handshake, after our first message: answers the host's challenge and checks its proof
+pub fn answer(stream: &mut (impl Read + Write), key: &Key) -> Result<Seal, ProtocolError> {
+    let host_nonce = match Message::read_from(stream)? {
+        Message::Challenge { nonce } => nonce,
+        Message::Reject { reason } => return Err(ProtocolError::Rejected(reason)),
+        _ => {
+            return Err(ProtocolError::Unauthorized(
+                "the host doesn't use a password",
+            ))
+        }
+    };
+    let client_nonce: Nonce = rand::random();
+    Message::Proof {
+        nonce: client_nonce,
+        mac: mac(key, &[b"client", &host_nonce, &client_nonce]),
+    }
+    .write_to(stream)?;
+    match Message::read_from(stream)? {
+        Message::Proof { nonce, mac }
+            if nonce == host_nonce && verify(key, &[b"host", &host_nonce, &client_nonce], &mac) =>
+        {
+            Ok(Seal::new(key, &host_nonce, &client_nonce, false))
+        }
+        Message::Proof { .. } => Err(ProtocolError::Unauthorized(
+            "the host doesn't know the password",
+        )),
+        Message::Reject { reason } => Err(ProtocolError::Rejected(reason)),
+        message => Err(ProtocolError::Unexpected(message)),
+    }
+}
+
+/// Authenticates the messages of one connection once the handshake is done.
+/// A sealed message is the encoded message followed by [counter: u64][MAC: 32 bytes], the MAC covers who sent it,
+/// the counter and the message. The counter goes up with every message, so one can't be replayed, reordered or reflected back.
+#[derive(Clone)]
+pub struct Seal {
+    key: Key,
+    host: bool,    // which end we are, the MAC says who sent a message
+    sent: u64,     // the counter of our next message
+    received: u64, // messages with a lower counter than this have been seen already
+}
+
+impl Seal {
+    fn new(key: &Key, host_nonce: &Nonce, client_nonce: &Nonce, host: bool) -> Seal {
+        Seal {
+            key: mac(key, &[b"session", host_nonce, client_nonce]),
+            host,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// A seal with its own key for the packets sent over UDP next to the connection, which can be lost or reordered
+    pub fn datagrams(&self) -> Seal {
+        Seal {
+            key: mac(&self.key, &[b"datagrams"]),
+            ..self.clone()
+        }
+    }
+
+    /// Encodes the message with our next counter and a MAC after it
+    pub fn seal(&mut self, message: &Message) -> Vec<u8> {
+        let mut bytes = message.encode();
+        let counter = self.sent.to_be_bytes();
+        self.sent += 1;
+        let tag = mac(&self.key, &[&[self.host as u8], &counter, &bytes]);
+        bytes.extend_from_slice(&counter);
+        bytes.extend_from_slice(&tag);
+        bytes
+    }
+
+    /// Checks a sealed message from the other end, messages with a counter that's already been seen are rejected
+    pub fn open(&mut self, bytes: &[u8]) -> Result<Message, ProtocolError> {
+        if bytes.len() < 8 + TAG_LEN {
+            return Err(ProtocolError::Malformed);
+        }
+        let (message, rest) = bytes.split_at(bytes.len() - 8 - TAG_LEN);
+        let (counter, tag) = rest.split_at(8);
+        let sender = !self.host as u8;
+        if !verify(&self.key, &[&[sender], counter, message], tag) {
+            return Err(ProtocolError::Unauthorized("a message was tampered with"));
+        }
+        let mut buf = [0; 8];
+        buf.copy_from_slice(counter);
+        let counter = u64::from_be_bytes(buf);
+        if counter < self.received {
+            return Err(ProtocolError::Unauthorized("a message was sent again"));
+        }
+        self.received = counter + 1;
+        Message::read_from(&mut &message[..])
+    }
+
+    /// Writes the sealed message and flushes the stream
+    pub fn write_to(
+        &mut self,
+        message: &Message,
+        stream: &mut impl Write,
+    ) -> Result<(), ProtocolError> {
+        stream.write_all(&self.seal(message))?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Blocks until a whole sealed message has been read and checked
+    pub fn read_from(&mut self, stream: &mut impl Read) -> Result<Message, ProtocolError> {
+        let mut bytes = Message::read_frame(stream)?;
+        let mut rest = [0; 8 + TAG_LEN];
+        stream.read_exact(&mut rest)?;
+        bytes.extend_from_slice(&rest);
+        self.open(&bytes)
+    }
+}
+
+/// `challenge` if the room has a password
+pub fn check_password(
+    stream: &mut (impl Read + Write),
+    key: Option<&Key>,
+) -> Result<Option<Seal>, ProtocolError> {
+    key.map(|key| challenge(stream, key)).transpose()
+}
+
+/// `answer` if we were given a password
+pub fn give_password(
+    stream: &mut (impl Read + Write),
+    key: Option<&Key>,
+) -> Result<Option<Seal>, ProtocolError> {
+    key.map(|key| answer(stream, key)).transpose()
+}
+
+/// Writes a message, sealed if the connection has a seal
+pub fn write(
+    message: &Message,
+    stream: &mut impl Write,
+    seal: &mut Option<Seal>,
+) -> Result<(), ProtocolError> {
+    match seal {
+        Some(seal) => seal.write_to(message, stream),
+        None => message.write_to(stream),
+    }
+}
+
+/// Reads a message, checking it if the connection has a seal
+pub fn read(stream: &mut impl Read, seal: &mut Option<Seal>) -> Result<Message, ProtocolError> {
+    match seal {
+        Some(seal) => seal.read_from(stream),
+        None => Message::read_from(stream),
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Longest chat message in characters, longer ones are cut off
+pub const MAX_CHAT_LEN: usize = 120;
+/// Most messages one player may send within `RATE_WINDOW`
+pub const RATE_LIMIT: usize = 5;
+pub const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// Lines kept in the log, the oldest are dropped first
+const LOG_LENGTH: usize = 100;
+
+/// One line in the chat log
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatLine {
+    pub name: String,
+    pub text: String,
+}
+
+/// The chat between the players of a network match.
+/// Both what we send and what we receive is rate limited, so a modified client can't flood the log.
+#[derive(Default)]
+pub struct Chat {
+    log: VecDeque<ChatLine>,
+    sent: HashMap<usize, VecDeque<Instant>>, // when the recent messages of each side arrived
+}
+
+impl Chat {
+    /// Counts a message from the player on `side`, returns false if they've sent too many lately
+    pub fn allow(&mut self, side: usize, now: Instant) -> bool {
+        let sent = self.sent.entry(side).or_default();
+        sent.retain(|time| now.duration_since(*time) < RATE_WINDOW);
+        if sent.len() >= RATE_LIMIT {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+
+    pub fn add(&mut self, name: &str, text: String) {
+        self.log.push_back(ChatLine {
+            name: name.to_string(),
+            text,
+        });
+        if self.log.len() > LOG_LENGTH {
+            self.log.pop_front();
+        }
+    }
+
+    /// The log, oldest line first
+    pub fn lines(&self) -> &VecDeque<ChatLine> {
+        &self.log
+    }
+}
+
+/// Drops control characters and surrounding whitespace and cuts the text to `MAX_CHAT_LEN`
+pub fn clean(text: &str) -> String {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    text.trim().chars().take(MAX_CHAT_LEN).collect()
+}
@@ -0,0 +1,471 @@
+use super::auth::{self, Key, Seal, TAG_LEN};
+use super::protocol::{Message, ProtocolError, PROTOCOL_VERSION};
+use crate::game_data::{AttackTable, RandomizerKind};
+use log::warn;
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::ops::Add;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often pings are sent, how long the peer may stay silent
+/// and how long a lost connection is waited on before the match is given up.
+/// Also how often the peers compare checksums of their states, and whether the client asks the host for its state when they differ.
+/// `rollback`, `seed`, `best_of`, `players`, `teams` and `split_garbage` are only read by the host, they decide whether the match is
+/// played with rollback over UDP, which pieces come up, how many matches a series has, how many players the host waits for
+/// and how they're split into teams.
+/// With a `key` only those who know the room password can connect, and every message after the handshake is sealed with a MAC.
+#[derive(Copy, Clone, Debug)]
+pub struct NetConfig {
+    pub heartbeat_interval: Duration,
+    pub timeout: Duration,
+    pub grace_period: Duration,
+    pub checksum_interval: u64, // in ticks, 0 turns the checks off
+    pub resync_on_desync: bool,
+    pub rollback: bool,
+    pub seed: Option<u64>, // random when not given
+    pub best_of: u8,
+    pub players: u8, // everyone in the match, the host included
+    pub teams: u8,   // fewer than two is every player for themselves
+    pub split_garbage: bool,
+    pub randomizer: RandomizerKind,
+    pub attack_table: AttackTable,
+    pub key: Option<Key>, // derived from the room password with `auth::derive_key`
+}
+
+impl Default for NetConfig {
+    fn default() -> NetConfig {
+        NetConfig {
+            heartbeat_interval: Duration::from_millis(500),
+            timeout: Duration::from_secs(5),
+            grace_period: Duration::from_secs(30),
+            checksum_interval: 60,
+            resync_on_desync: true,
+            rollback: false,
+            seed: None,
+            best_of: 1,
+            players: 2,
+            teams: 0,
+            split_garbage: false,
+            randomizer: RandomizerKind::Bag7,
+            attack_table: AttackTable::default(),
+            key: None,
+        }
+    }
+}
+
+/// Messages and bytes sent and received, pings and sealed messages included
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Traffic {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+impl Add for Traffic {
+    type Output = Traffic;
+
+    fn add(self, other: Traffic) -> Traffic {
+        Traffic {
+            messages_sent: self.messages_sent + other.messages_sent,
+            bytes_sent: self.bytes_sent + other.bytes_sent,
+            messages_received: self.messages_received + other.messages_received,
+            bytes_received: self.bytes_received + other.bytes_received,
+        }
+    }
+}
+
+impl Traffic {
+    /// What was added since `earlier`, nothing for counters that started over in between
+    pub fn since(self, earlier: Traffic) -> Traffic {
+        Traffic {
+            messages_sent: self.messages_sent.saturating_sub(earlier.messages_sent),
+            bytes_sent: self.bytes_sent.saturating_sub(earlier.bytes_sent),
+            messages_received: self
+                .messages_received
+                .saturating_sub(earlier.messages_received),
+            bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+        }
+    }
+
+    pub(super) fn count_sent(&mut self, bytes: usize) {
+        self.messages_sent += 1;
+        self.bytes_sent += bytes as u64;
+    }
+
+    pub(super) fn count_received(&mut self, bytes: usize) {
+        self.messages_received += 1;
+        self.bytes_received += bytes as u64;
+    }
+}
+
+/// The traffic of a connection, counted by its reading and writing threads
+#[derive(Default)]
+struct Counters {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl Counters {
+    fn sent(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn received(&self, bytes: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> Traffic {
+        Traffic {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The size of a message on the wire, with the counter and MAC after it if it's sealed
+fn wire_len(message: &Message, seal: &Option<Seal>) -> usize {
+    message.encode().len() + seal.as_ref().map_or(0, |_| 8 + TAG_LEN)
+}
+
+/// What the network threads report back to the game
+enum NetEvent {
+    Message(Message),
+    Rtt(Duration),
+    Disconnected(String),
+}
+
+/// A connection to the opponent, the reading and writing is done on two separate threads.
+/// Messages are sealed and checked with the seal from the handshake if the room has a password.
+pub struct Connection {
+    events: mpsc::Receiver<NetEvent>,
+    outgoing: mpsc::Sender<Message>,
+    rtt: Option<Duration>,
+    jitter: Option<Duration>, // how much the round-trip time varies, smoothed like RFC 3550 does
+    counters: Arc<Counters>,
+    disconnected: Option<String>,
+}
+
+impl Connection {
+    pub fn start(stream: TcpStream, config: NetConfig, seal: Option<Seal>) -> Connection {
+        stream.set_nodelay(true).expect("set_nodelay call failed");
+        let (event_sender, events) = mpsc::channel();
+        let (outgoing, outgoing_reciever) = mpsc::channel();
+        // ping ids are the time they were sent, in microseconds since this moment
+        let start = Instant::now();
+
+        let read_stream = stream.try_clone().expect("could not clone stream");
+        let pong_sender = outgoing.clone();
+        let reader_events = event_sender.clone();
+        // each thread only uses its own counter
+        let read_seal = seal.clone();
+        let counters = Arc::new(Counters::default());
+        let read_counters = counters.clone();
+        let write_counters = counters.clone();
+        thread::spawn(move || {
+            Connection::read_loop(
+                read_stream,
+                config,
+                read_seal,
+                start,
+                read_counters,
+                reader_events,
+                pong_sender,
+            );
+        });
+        thread::spawn(move || {
+            Connection::write_loop(
+                stream,
+                config,
+                seal,
+                start,
+                write_counters,
+                event_sender,
+                outgoing_reciever,
+            );
+        });
+
+        Connection {
+            events,
+            outgoing,
+            rtt: None,
+            jitter: None,
+            counters,
+            disconnected: None,
+        }
+    }
+
+    /// Queues a message, messages sent after a disconnect are dropped
+    pub fn send(&self, message: Message) {
+        self.outgoing.send(message).ok();
+    }
+
+    /// Returns the next message from the opponent, if there is one
+    pub fn try_recv(&mut self) -> Option<Message> {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                NetEvent::Message(message) => return Some(message),
+                NetEvent::Rtt(rtt) => {
+                    if let Some(last) = self.rtt {
+                        let change = rtt.abs_diff(last);
+                        let jitter = self.jitter.unwrap_or(change);
+                        // a sixteenth of the way towards the latest change
+                        self.jitter = Some(if change > jitter {
+                            jitter + (change - jitter) / 16
+                        } else {
+                            jitter - (jitter - change) / 16
+                        });
+                    }
+                    self.rtt = Some(rtt);
+                }
+                NetEvent::Disconnected(reason) => {
+                    if self.disconnected.is_none() {
+                        warn!("Connection closed! ({})", reason);
+                        self.disconnected = Some(reason);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// The latest measured round-trip time
+    pub fn get_rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// How much the round-trip time varies between pings
+    pub fn get_jitter(&self) -> Option<Duration> {
+        self.jitter
+    }
+
+    /// Everything sent and received since the connection started
+    pub fn get_traffic(&self) -> Traffic {
+        self.counters.load()
+    }
+
+    /// Why the connection was lost, if it has been
+    pub fn get_disconnected(&self) -> Option<&str> {
+        self.disconnected.as_deref()
+    }
+
+    fn read_loop(
+        stream: TcpStream,
+        config: NetConfig,
+        mut seal: Option<Seal>,
+        start: Instant,
+        counters: Arc<Counters>,
+        events: mpsc::Sender<NetEvent>,
+        pong_sender: mpsc::Sender<Message>,
+    ) {
+        stream
+            .set_read_timeout(Some(config.timeout))
+            .expect("set_read_timeout call failed");
+        let mut reader = BufReader::new(&stream);
+        let reason = loop {
+            let message = auth::read(&mut reader, &mut seal);
+            if let Ok(message) = &message {
+                counters.received(wire_len(message, &seal));
+            }
+            let event = match message {
+                Ok(Message::Ping { id }) => {
+                    pong_sender.send(Message::Pong { id }).ok();
+                    continue;
+                }
+                Ok(Message::Pong { id }) => {
+                    let now = start.elapsed().as_micros() as u64;
+                    NetEvent::Rtt(Duration::from_micros(now.saturating_sub(id)))
+                }
+                Ok(message) => NetEvent::Message(message),
+                Err(ProtocolError::Io(err))
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    break format!("no response in {} seconds", config.timeout.as_secs_f32());
+                }
+                Err(ProtocolError::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                    break "the opponent left".to_string();
+                }
+                Err(err) => break err.to_string(),
+            };
+            if events.send(event).is_err() {
+                // the game is gone
+                return;
+            }
+        };
+        events.send(NetEvent::Disconnected(reason)).ok();
+        // wakes up the writer if it's blocked on the socket
+        stream.shutdown(Shutdown::Both).ok();
+    }
+
+    fn write_loop(
+        stream: TcpStream,
+        config: NetConfig,
+        mut seal: Option<Seal>,
+        start: Instant,
+        counters: Arc<Counters>,
+        events: mpsc::Sender<NetEvent>,
+        outgoing: mpsc::Receiver<Message>,
+    ) {
+        let mut writer = BufWriter::new(&stream);
+        let mut next_ping = Instant::now();
+        loop {
+            let now = Instant::now();
+            let message = if now >= next_ping {
+                next_ping = now + config.heartbeat_interval;
+                Message::Ping {
+                    id: start.elapsed().as_micros() as u64,
+                }
+            } else {
+                match outgoing.recv_timeout(next_ping - now) {
+                    Ok(message) => message,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            };
+            if let Err(err) = auth::write(&message, &mut writer, &mut seal) {
+                events.send(NetEvent::Disconnected(err.to_string())).ok();
+                break;
+            }
+            counters.sent(wire_len(&message, &seal));
+        }
+        stream.shutdown(Shutdown::Both).ok();
+    }
+}
+
+/// Someone who connected to the host after the match started, with the seal of the connection if the room has a password
+pub enum Incoming {
+    /// A client coming back after losing its connection, and its session
+    Resume(TcpStream, u64, Option<Seal>),
+    /// Someone who wants to watch, and their name
+    Spectator(TcpStream, String, Option<Seal>),
+}
+
+/// Keeps accepting connections on the host's listener for the whole match
+pub struct Acceptor {
+    incoming: mpsc::Receiver<Incoming>,
+}
+
+impl Acceptor {
+    /// Lets the clients with the given sessions back in, and anyone watch
+    pub fn start(listener: TcpListener, sessions: Vec<u64>, config: NetConfig) -> Acceptor {
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                stream.set_read_timeout(Some(config.timeout)).ok();
+                let reason = match Message::read_from(&mut stream) {
+                    Ok(Message::Resume { version, .. }) | Ok(Message::Spectate { version, .. })
+                        if version != PROTOCOL_VERSION =>
+                    {
+                        format!(
+                            "protocol version {} is not supported, the host runs version {}",
+                            version, PROTOCOL_VERSION
+                        )
+                    }
+                    Ok(Message::Resume { session, .. }) if sessions.contains(&session) => {
+                        let seal = match auth::check_password(&mut stream, config.key.as_ref()) {
+                            Ok(seal) => seal,
+                            Err(err) => {
+                                warn!("Ignored a client: {}", err);
+                                continue;
+                            }
+                        };
+                        if sender
+                            .send(Incoming::Resume(stream, session, seal))
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                    Ok(Message::Spectate { name, .. }) => {
+                        let seal = match auth::check_password(&mut stream, config.key.as_ref()) {
+                            Ok(seal) => seal,
+                            Err(err) => {
+                                warn!("Ignored a spectator: {}", err);
+                                continue;
+                            }
+                        };
+                        if sender
+                            .send(Incoming::Spectator(stream, name, seal))
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                    Ok(_) => "the match has already started".to_string(),
+                    Err(err) => {
+                        warn!("Ignored a client: {}", err);
+                        continue;
+                    }
+                };
+                warn!("Rejected a client: {}", reason);
+                Message::Reject { reason }.write_to(&mut stream).ok();
+            }
+        });
+        Acceptor { incoming }
+    }
+
+    pub fn try_recv(&self) -> Option<Incoming> {
+        self.incoming.try_recv().ok()
+    }
+}
+
+/// The client side of getting a lost connection back, keeps trying to connect to the host and resume the session
+pub struct Reconnect {
+    streams: mpsc::Receiver<(TcpStream, Option<Seal>)>,
+}
+
+impl Reconnect {
+    pub fn connect(adress: String, session: u64, config: NetConfig) -> Reconnect {
+        let (sender, streams) = mpsc::channel();
+        let deadline = Instant::now() + config.grace_period;
+        thread::spawn(move || {
+            while Instant::now() < deadline {
+                if let Ok(mut stream) = TcpStream::connect(&adress) {
+                    stream.set_read_timeout(Some(config.timeout)).ok();
+                    let resume = Message::Resume {
+                        version: PROTOCOL_VERSION,
+                        session,
+                    };
+                    let seal = resume
+                        .write_to(&mut stream)
+                        .and_then(|_| auth::give_password(&mut stream, config.key.as_ref()));
+                    match seal {
+                        Ok(seal) => {
+                            sender.send((stream, seal)).ok();
+                            break;
+                        }
+                        Err(err @ ProtocolError::Unauthorized(_)) => {
+                            warn!("Could not reconnect: {}", err);
+                            break;
+                        }
+                        Err(_) => (),
+                    }
+                }
+                thread::sleep(Duration::from_millis(500));
+            }
+        });
+        Reconnect { streams }
+    }
+
+    /// The new connection and its seal, if it's been made
+    pub fn try_get(&self) -> Option<(TcpStream, Option<Seal>)> {
+        self.streams.try_recv().ok()
+    }
+}
@@ -0,0 +1,166 @@
+use super::protocol::{Message, PROTOCOL_VERSION};
+use std::io::{self, BufRead, Write};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The UDP port hosts announce their games on
+pub const DISCOVERY_PORT: u16 = 8788;
+/// How often a host sends its beacon
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+/// A game that hasn't been heard from in this long is taken off the list
+const GAME_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Announces a hosted game on the local network until it's dropped
+pub struct Beacon {
+    free_slots: mpsc::Sender<u8>,
+}
+
+impl Beacon {
+    pub fn start(name: String, port: u16, free_slots: u8) -> io::Result<Beacon> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        let (sender, updates) = mpsc::channel();
+        thread::spawn(move || {
+            let mut free_slots = free_slots;
+            loop {
+                let beacon = Message::Beacon {
+                    version: PROTOCOL_VERSION,
+                    name: name.clone(),
+                    port,
+                    free_slots,
+                };
+                // a lost beacon doesn't matter, the next one is sent soon
+                socket
+                    .send_to(&beacon.encode(), (Ipv4Addr::BROADCAST, DISCOVERY_PORT))
+                    .ok();
+                match updates.recv_timeout(BEACON_INTERVAL) {
+                    Ok(slots) => free_slots = slots,
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        Ok(Beacon { free_slots: sender })
+    }
+
+    /// Changes how many players the beacon says can still join
+    pub fn set_free_slots(&self, free_slots: u8) {
+        self.free_slots.send(free_slots).ok();
+    }
+}
+
+/// A game found on the local network
+#[derive(Clone, Debug, PartialEq)]
+pub struct FoundGame {
+    pub name: String,
+    pub adress: SocketAddr,
+    pub version: u16,
+    pub free_slots: u8,
+}
+
+impl FoundGame {
+    /// Whether we can take part in the game, as a player or only as a spectator
+    pub fn can_join(&self, spectating: bool) -> bool {
+        self.version == PROTOCOL_VERSION && (spectating || self.free_slots > 0)
+    }
+}
+
+/// Listens for beacons and keeps a list of the games that are still announced
+pub struct Discovery {
+    found: mpsc::Receiver<FoundGame>,
+    games: Vec<(FoundGame, Instant)>,
+}
+
+impl Discovery {
+    pub fn start() -> io::Result<Discovery> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+        let (sender, found) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if let Ok(Message::Beacon {
+                    version,
+                    name,
+                    port,
+                    free_slots,
+                }) = Message::read_from(&mut &buf[..len])
+                {
+                    let game = FoundGame {
+                        name,
+                        adress: SocketAddr::new(from.ip(), port),
+                        version,
+                        free_slots,
+                    };
+                    if sender.send(game).is_err() {
+                        // nobody is looking anymore
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Discovery {
+            found,
+            games: Vec::new(),
+        })
+    }
+
+    /// The games heard from recently, in the order they were found
+    pub fn get_games(&mut self) -> Vec<FoundGame> {
+        let now = Instant::now();
+        while let Ok(game) = self.found.try_recv() {
+            match self.games.iter_mut().find(|(g, _)| g.adress == game.adress) {
+                Some(entry) => *entry = (game, now),
+                None => self.games.push((game, now)),
+            }
+        }
+        self.games
+            .retain(|(_, last_seen)| now.duration_since(*last_seen) < GAME_TIMEOUT);
+        self.games.iter().map(|(game, _)| game.clone()).collect()
+    }
+}
+
+/// Lists the games on the local network in the terminal and lets the player pick one by its number
+pub fn choose_game(spectating: bool) -> io::Result<SocketAddr> {
+    let mut discovery = Discovery::start()?;
+    println!("Looking for games on the local network...");
+    let stdin = io::stdin();
+    loop {
+        // give every host time to send a beacon
+        thread::sleep(2 * BEACON_INTERVAL);
+        let games = discovery.get_games();
+        if games.is_empty() {
+            println!("No games found");
+        }
+        for (i, game) in games.iter().enumerate() {
+            let status = if game.version != PROTOCOL_VERSION {
+                format!("version {}, can't join", game.version)
+            } else if game.free_slots == 0 {
+                "full".to_string()
+            } else {
+                format!("{} free slot(s)", game.free_slots)
+            };
+            println!("{}) {} at {} ({})", i + 1, game.name, game.adress, status);
+        }
+        print!("Pick a game, or press enter to look again: ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no game picked",
+            ));
+        }
+        let picked = line
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| games.get(n.checked_sub(1)?));
+        match picked {
+            Some(game) if game.can_join(spectating) => return Ok(game.adress),
+            Some(game) => println!("Can't join {}", game.name),
+            None => (),
+        }
+    }
+}
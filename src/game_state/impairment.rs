@@ -0,0 +1,304 @@
+use super::protocol::Message;
+use crate::game_data::GameRng;
+use log::warn;
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How the simulated network treats the traffic, the same in both directions
+#[derive(Copy, Clone, Debug)]
+pub struct Impairment {
+    pub latency: Duration,      // one way
+    pub jitter: Duration,       // up to this much extra delay, drawn for every message
+    pub loss: f64, // chance a message is lost, on TCP it arrives after a retransmission timeout instead
+    pub reorder: f64, // chance a UDP packet is held back behind the ones after it
+    pub bandwidth: Option<u32>, // bytes per second
+    pub seed: u64,
+}
+
+impl Default for Impairment {
+    fn default() -> Impairment {
+        Impairment {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            reorder: 0.0,
+            bandwidth: None,
+            seed: 0,
+        }
+    }
+}
+
+/// One direction of the simulated network, decides when every message arrives
+struct Link {
+    impairment: Impairment,
+    rng: GameRng,
+    free_at: Instant,      // when the link is done sending what it has been given
+    last_arrival: Instant, // TCP never delivers out of order
+}
+
+impl Link {
+    fn new(impairment: Impairment, seed: u64) -> Link {
+        Link {
+            impairment,
+            rng: GameRng::new(seed),
+            free_at: Instant::now(),
+            last_arrival: Instant::now(),
+        }
+    }
+
+    /// When a message of `len` bytes sent now arrives, None if it's lost
+    fn schedule(&mut self, len: usize, reliable: bool) -> Option<Instant> {
+        let impairment = self.impairment;
+        let now = Instant::now();
+        let mut sent = now.max(self.free_at);
+        if let Some(bandwidth) = impairment.bandwidth {
+            sent += Duration::from_secs_f64(len as f64 / bandwidth as f64);
+        }
+        self.free_at = sent;
+
+        let jitter = impairment.jitter.mul_f64(self.rng.gen::<f64>());
+        let mut arrival = sent + impairment.latency + jitter;
+        let lost = self.rng.gen::<f64>() < impairment.loss;
+        if reliable {
+            if lost {
+                // resent after a round trip and a bit
+                arrival += 2 * impairment.latency + Duration::from_millis(20);
+            }
+            arrival = arrival.max(self.last_arrival);
+            self.last_arrival = arrival;
+        } else if lost {
+            return None;
+        } else if self.rng.gen::<f64>() < impairment.reorder {
+            arrival += impairment.latency + impairment.jitter + Duration::from_millis(5);
+        }
+        Some(arrival)
+    }
+}
+
+/// A TCP proxy on a local port that forwards every connection to `target` through a simulated network.
+/// It reads the game's messages, so it can also put itself between the peers' UDP sockets when they announce their ports.
+/// Matches with a room password can't go through it, changing messages on the way is what their seals are there to stop.
+pub struct Proxy {
+    adress: SocketAddr,
+}
+
+impl Proxy {
+    pub fn start(target: &str, impairment: Impairment) -> io::Result<Proxy> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let adress = listener.local_addr()?;
+        let target = target.to_string();
+        thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let seed = impairment.seed.wrapping_add(n as u64 * 4);
+                let pair = stream.and_then(|client| {
+                    let server = TcpStream::connect(&target)?;
+                    client.set_nodelay(true)?;
+                    server.set_nodelay(true)?;
+                    Ok((client, server))
+                });
+                match pair {
+                    Ok((client, server)) => {
+                        if let Err(err) = Proxy::forward(client, server, impairment, seed) {
+                            warn!("Proxy could not forward a connection: {}", err);
+                        }
+                    }
+                    Err(err) => warn!("Proxy could not connect: {}", err),
+                }
+            }
+        });
+        Ok(Proxy { adress })
+    }
+
+    /// Where to connect instead of the target
+    pub fn get_adress(&self) -> String {
+        self.adress.to_string()
+    }
+
+    fn forward(
+        client: TcpStream,
+        server: TcpStream,
+        impairment: Impairment,
+        seed: u64,
+    ) -> io::Result<()> {
+        // the port each peer is told to send UDP to, packets arriving there are meant for the other peer
+        let to_client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let to_server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let client_udp = Arc::new(Mutex::new(None));
+        let server_udp = Arc::new(Mutex::new(None));
+
+        // a peer only takes packets from the port it was told about, so they're forwarded from the other socket
+        Proxy::forward_udp(
+            to_client.try_clone()?,
+            to_server.try_clone()?,
+            client_udp.clone(),
+            Link::new(impairment, seed),
+        );
+        Proxy::forward_udp(
+            to_server.try_clone()?,
+            to_client.try_clone()?,
+            server_udp.clone(),
+            Link::new(impairment, seed + 1),
+        );
+
+        // what each peer is told the other one's port is
+        let client_port = to_client.local_addr()?.port();
+        let server_port = to_server.local_addr()?.port();
+        let client_ip = client.peer_addr()?.ip();
+        let server_ip = server.peer_addr()?.ip();
+        Proxy::forward_tcp(
+            client.try_clone()?,
+            server.try_clone()?,
+            (client_udp, client_ip, client_port),
+            Link::new(impairment, seed + 2),
+        );
+        Proxy::forward_tcp(
+            server,
+            client,
+            (server_udp, server_ip, server_port),
+            Link::new(impairment, seed + 3),
+        );
+        Ok(())
+    }
+
+    /// Forwards messages from one end to the other. When `from` announces its UDP port the real one is written to
+    /// `udp.0` and the other end is told to use `udp.2` on the proxy instead.
+    fn forward_tcp(
+        from: TcpStream,
+        mut to: TcpStream,
+        udp: (Arc<Mutex<Option<SocketAddr>>>, IpAddr, u16),
+        mut link: Link,
+    ) {
+        let (real_udp, ip, proxy_port) = udp;
+        let shutdown = to.try_clone().ok();
+        let line = delay_line(move |message: Option<Message>| match message {
+            Some(message) => message.write_to(&mut to).is_ok(),
+            None => {
+                if let Some(stream) = &shutdown {
+                    stream.shutdown(Shutdown::Both).ok();
+                }
+                false
+            }
+        });
+        thread::spawn(move || {
+            let mut reader = BufReader::new(&from);
+            loop {
+                let message = match Message::read_from(&mut reader) {
+                    Ok(Message::UdpPort { port }) => {
+                        *real_udp.lock().unwrap() = Some(SocketAddr::new(ip, port));
+                        Message::UdpPort { port: proxy_port }
+                    }
+                    Ok(message) => message,
+                    Err(_) => break,
+                };
+                let arrival = link.schedule(message.encode().len(), true).unwrap();
+                if line.send((arrival, Some(message))).is_err() {
+                    break;
+                }
+            }
+            let arrival = link.schedule(0, true).unwrap();
+            line.send((arrival, None)).ok();
+            from.shutdown(Shutdown::Both).ok();
+        });
+    }
+
+    /// Forwards the packets arriving at `socket` to `target` once it's known, sent from `sender`
+    fn forward_udp(
+        socket: UdpSocket,
+        sender: UdpSocket,
+        target: Arc<Mutex<Option<SocketAddr>>>,
+        mut link: Link,
+    ) {
+        let line = delay_line(move |packet: Vec<u8>| {
+            if let Some(target) = *target.lock().unwrap() {
+                sender.send_to(&packet, target).ok();
+            }
+            true
+        });
+        thread::spawn(move || {
+            let mut buf = [0; 2048];
+            while let Ok(len) = socket.recv(&mut buf) {
+                if let Some(arrival) = link.schedule(len, false) {
+                    if line.send((arrival, buf[..len].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// An item in a delay line, the earliest arrival comes out first and ties keep the order they were sent in
+struct Delayed<T> {
+    arrival: Instant,
+    n: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Delayed<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.arrival, self.n) == (other.arrival, other.n)
+    }
+}
+
+impl<T> Eq for Delayed<T> {}
+
+impl<T> PartialOrd for Delayed<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Delayed<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed, the heap pops the largest
+        (other.arrival, other.n).cmp(&(self.arrival, self.n))
+    }
+}
+
+/// Starts a thread that hands every item to `deliver` at its arrival, until `deliver` returns false
+fn delay_line<T: Send + 'static>(
+    mut deliver: impl FnMut(T) -> bool + Send + 'static,
+) -> mpsc::Sender<(Instant, T)> {
+    let (sender, items) = mpsc::channel::<(Instant, T)>();
+    thread::spawn(move || {
+        let mut queue: BinaryHeap<Delayed<T>> = BinaryHeap::new();
+        let mut n = 0;
+        let mut open = true;
+        while open || !queue.is_empty() {
+            let now = Instant::now();
+            while let Some(next) = queue.pop() {
+                if next.arrival > now {
+                    queue.push(next);
+                    break;
+                }
+                if !deliver(next.item) {
+                    return;
+                }
+            }
+            let wait = match queue.peek() {
+                Some(next) => next.arrival.saturating_duration_since(now),
+                None => Duration::from_secs(1),
+            };
+            if !open {
+                thread::sleep(wait);
+                continue;
+            }
+            match items.recv_timeout(wait) {
+                Ok((arrival, item)) => {
+                    queue.push(Delayed { arrival, n, item });
+                    n += 1;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => open = false,
+            }
+        }
+    });
+    sender
+}
@@ -0,0 +1,183 @@
+use crate::game_data::Move;
+use ggez::event::KeyCode;
+use std::collections::HashMap;
+use std::fs;
+
+/// The moves by the names used in key-binding files
+const MOVE_NAMES: [(&str, Move); 8] = [
+    ("left", Move::Left),
+    ("right", Move::Right),
+    ("rotate-cw", Move::RotateCw),
+    ("rotate-ccw", Move::RotateCcw),
+    ("rotate-180", Move::Rotate180),
+    ("soft-drop", Move::SoftDrop),
+    ("hard-drop", Move::HardDrop),
+    ("save", Move::Save),
+];
+
+/// The keys that can be bound, by name
+const KEY_NAMES: [(&str, KeyCode); 64] = [
+    ("A", KeyCode::A),
+    ("B", KeyCode::B),
+    ("C", KeyCode::C),
+    ("D", KeyCode::D),
+    ("E", KeyCode::E),
+    ("F", KeyCode::F),
+    ("G", KeyCode::G),
+    ("H", KeyCode::H),
+    ("I", KeyCode::I),
+    ("J", KeyCode::J),
+    ("K", KeyCode::K),
+    ("L", KeyCode::L),
+    ("M", KeyCode::M),
+    ("N", KeyCode::N),
+    ("O", KeyCode::O),
+    ("P", KeyCode::P),
+    ("Q", KeyCode::Q),
+    ("R", KeyCode::R),
+    ("S", KeyCode::S),
+    ("T", KeyCode::T),
+    ("U", KeyCode::U),
+    ("V", KeyCode::V),
+    ("W", KeyCode::W),
+    ("X", KeyCode::X),
+    ("Y", KeyCode::Y),
+    ("Z", KeyCode::Z),
+    ("0", KeyCode::Key0),
+    ("1", KeyCode::Key1),
+    ("2", KeyCode::Key2),
+    ("3", KeyCode::Key3),
+    ("4", KeyCode::Key4),
+    ("5", KeyCode::Key5),
+    ("6", KeyCode::Key6),
+    ("7", KeyCode::Key7),
+    ("8", KeyCode::Key8),
+    ("9", KeyCode::Key9),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Space", KeyCode::Space),
+    ("Return", KeyCode::Return),
+    ("Tab", KeyCode::Tab),
+    ("Back", KeyCode::Back),
+    ("LShift", KeyCode::LShift),
+    ("RShift", KeyCode::RShift),
+    ("LControl", KeyCode::LControl),
+    ("RControl", KeyCode::RControl),
+    ("LAlt", KeyCode::LAlt),
+    ("RAlt", KeyCode::RAlt),
+    ("Comma", KeyCode::Comma),
+    ("Period", KeyCode::Period),
+    ("Slash", KeyCode::Slash),
+    ("Semicolon", KeyCode::Semicolon),
+    ("Numpad0", KeyCode::Numpad0),
+    ("Numpad1", KeyCode::Numpad1),
+    ("Numpad2", KeyCode::Numpad2),
+    ("Numpad3", KeyCode::Numpad3),
+    ("Numpad4", KeyCode::Numpad4),
+    ("Numpad5", KeyCode::Numpad5),
+    ("Numpad6", KeyCode::Numpad6),
+    ("Numpad7", KeyCode::Numpad7),
+    ("Numpad8", KeyCode::Numpad8),
+    ("Numpad9", KeyCode::Numpad9),
+];
+
+/// Which key makes which player do which move, players are numbered from 0
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyBindings {
+    keys: HashMap<KeyCode, (usize, Move)>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        let keys = [
+            // P1 controlls
+            (KeyCode::A, (0, Move::Left)),
+            (KeyCode::E, (0, Move::RotateCw)),
+            (KeyCode::D, (0, Move::Right)),
+            (KeyCode::Q, (0, Move::RotateCcw)),
+            (KeyCode::Z, (0, Move::Rotate180)),
+            (KeyCode::S, (0, Move::SoftDrop)),
+            (KeyCode::W, (0, Move::HardDrop)),
+            (KeyCode::Space, (0, Move::Save)),
+            // P2 controlls
+            (KeyCode::J, (1, Move::Left)),
+            (KeyCode::O, (1, Move::RotateCw)),
+            (KeyCode::L, (1, Move::Right)),
+            (KeyCode::U, (1, Move::RotateCcw)),
+            (KeyCode::P, (1, Move::Rotate180)),
+            (KeyCode::K, (1, Move::SoftDrop)),
+            (KeyCode::I, (1, Move::HardDrop)),
+            (KeyCode::RShift, (1, Move::Save)),
+        ];
+        KeyBindings {
+            keys: keys.iter().cloned().collect(),
+        }
+    }
+}
+
+impl KeyBindings {
+    /// The player and move a key is bound to
+    pub fn get(&self, key: KeyCode) -> Option<(usize, Move)> {
+        self.keys.get(&key).cloned()
+    }
+
+    /// Reads a key-binding file, see `parse`
+    pub fn load(path: &str) -> Result<KeyBindings, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("could not read the key bindings {}: {}", path, err))?;
+        KeyBindings::parse(&text).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    /// Changes the default bindings with lines like `p1.hard-drop = Up`, everything after a # is ignored.
+    /// A move bound in the file loses its default key.
+    pub fn parse(text: &str) -> Result<KeyBindings, String> {
+        let mut bindings = KeyBindings::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |reason: String| format!("line {}: {}", n + 1, reason);
+            let (action, key) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => {
+                    return Err(error(format!(
+                        "expected `player.move = key`, got `{}`",
+                        line
+                    )))
+                }
+            };
+            let (player, mv) = match action.find('.') {
+                Some(i) => (&action[..i], &action[i + 1..]),
+                None => return Err(error(format!("expected `player.move`, got `{}`", action))),
+            };
+            let player = match player {
+                "p1" => 0,
+                "p2" => 1,
+                _ => return Err(error(format!("unknown player `{}`, use p1 or p2", player))),
+            };
+            let mv = MOVE_NAMES
+                .iter()
+                .find(|(name, _)| *name == mv)
+                .map(|(_, mv)| *mv)
+                .ok_or_else(|| {
+                    let names: Vec<&str> = MOVE_NAMES.iter().map(|(name, _)| *name).collect();
+                    error(format!(
+                        "unknown move `{}`, use one of {}",
+                        mv,
+                        names.join(", ")
+                    ))
+                })?;
+            let key = KEY_NAMES
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, key)| *key)
+                .ok_or_else(|| error(format!("unknown key `{}`", key)))?;
+            bindings.keys.retain(|_, action| *action != (player, mv));
+            bindings.keys.insert(key, (player, mv));
+        }
+        Ok(bindings)
+    }
+}
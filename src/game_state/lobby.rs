@@ -0,0 +1,277 @@
+use super::protocol::{Message, ProtocolError, Room, RoomMode, RoomSettings, PROTOCOL_VERSION};
+use super::{MAX_PLAYERS, MIN_PLAYERS};
+use crate::game_data::TIME_LEVELS;
+use log::{info, warn};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The port a lobby listens on when no adress is given
+pub const LOBBY_PORT: u16 = 8789;
+/// How long a client waits for the lobby to answer
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest room name, longer ones are cut
+const MAX_ROOM_NAME: usize = 32;
+
+/// A room and where its match is played
+struct OpenRoom {
+    room: Room,
+    adress: String,
+    client: u64, // the connection that opened it
+}
+
+/// Every open room, shared between the threads of the clients
+#[derive(Default)]
+struct Rooms {
+    next_id: u32,
+    open: Vec<OpenRoom>,
+}
+
+impl Rooms {
+    fn list(&self) -> Vec<Room> {
+        self.open.iter().map(|open| open.room.clone()).collect()
+    }
+}
+
+/// Keeps the list of rooms players can browse and join. The matches themselves aren't played through the lobby,
+/// joining a room hands the player off to its host or match server.
+pub struct Lobby {
+    listener: TcpListener,
+    rooms: Arc<Mutex<Rooms>>,
+}
+
+impl Lobby {
+    pub fn bind(adress: &str) -> io::Result<Lobby> {
+        Ok(Lobby {
+            listener: TcpListener::bind(adress)?,
+            rooms: Arc::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves every client on a thread of its own, until the listener fails
+    pub fn run(&self) {
+        for (client, stream) in self.listener.incoming().enumerate() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Could not accept a client: {}", err);
+                    continue;
+                }
+            };
+            let rooms = Arc::clone(&self.rooms);
+            let client = client as u64;
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(err) = serve(stream, &rooms, client) {
+                    info!("Lost a lobby client: {}", err);
+                }
+                // the rooms of a client who left can't be played anymore
+                let mut rooms = rooms.lock().unwrap();
+                rooms.open.retain(|open| open.client != client);
+                if let Some(peer) = peer {
+                    info!("{} left the lobby", peer);
+                }
+            });
+        }
+    }
+}
+
+/// Answers the requests of one client until it leaves
+fn serve(mut stream: TcpStream, rooms: &Mutex<Rooms>, client: u64) -> Result<(), ProtocolError> {
+    let name = match Message::read_from(&mut stream)? {
+        Message::LobbyHello { version, .. } if version != PROTOCOL_VERSION => {
+            let reason = format!(
+                "protocol version {} is not supported, the lobby runs version {}",
+                version, PROTOCOL_VERSION
+            );
+            Message::Reject { reason }.write_to(&mut stream)?;
+            return Ok(());
+        }
+        Message::LobbyHello { name, .. } => name,
+        message => return Err(ProtocolError::Unexpected(message)),
+    };
+    let ip = stream.peer_addr()?.ip();
+    info!("{} joined the lobby from {}", name, ip);
+    let rooms_list = rooms.lock().unwrap().list();
+    Message::Rooms { rooms: rooms_list }.write_to(&mut stream)?;
+    loop {
+        let answer = match Message::read_from(&mut stream)? {
+            Message::ListRooms => Message::Rooms {
+                rooms: rooms.lock().unwrap().list(),
+            },
+            Message::CreateRoom { mut settings } => match check(&settings) {
+                Err(reason) => Message::Reject { reason },
+                Ok(()) => {
+                    settings.name = settings.name.chars().take(MAX_ROOM_NAME).collect();
+                    let mut rooms = rooms.lock().unwrap();
+                    let id = rooms.next_id;
+                    rooms.next_id = rooms.next_id.wrapping_add(1);
+                    info!("{} opened room {}: {}", name, id, settings.name);
+                    let adress = SocketAddr::new(ip, settings.port).to_string();
+                    let joined = match settings.mode {
+                        RoomMode::Hosted => 1,
+                        RoomMode::Server => 0,
+                    };
+                    rooms.open.push(OpenRoom {
+                        room: Room {
+                            id,
+                            owner: name.clone(),
+                            settings,
+                            joined,
+                        },
+                        adress,
+                        client,
+                    });
+                    Message::RoomCreated { id }
+                }
+            },
+            Message::JoinRoom { id } => {
+                let mut rooms = rooms.lock().unwrap();
+                match rooms.open.iter().position(|open| open.room.id == id) {
+                    None => Message::Reject {
+                        reason: "the room is closed".to_string(),
+                    },
+                    Some(i) => {
+                        let open = &mut rooms.open[i];
+                        let room = &mut open.room;
+                        room.joined = (room.joined + 1).min(room.settings.players);
+                        let adress = open.adress.clone();
+                        // a match server plays one match after another and keeps its room, it says how full it is
+                        if room.joined >= room.settings.players
+                            && room.settings.mode == RoomMode::Hosted
+                        {
+                            rooms.open.remove(i);
+                        }
+                        Message::HandOff { adress }
+                    }
+                }
+            }
+            Message::UpdateRoom { id, joined } => {
+                let mut rooms = rooms.lock().unwrap();
+                let open = rooms
+                    .open
+                    .iter_mut()
+                    .find(|open| open.room.id == id && open.client == client);
+                match open {
+                    None => Message::Reject {
+                        reason: "only whoever opened a room can update it".to_string(),
+                    },
+                    Some(open) => {
+                        open.room.joined = joined.min(open.room.settings.players);
+                        Message::Rooms {
+                            rooms: rooms.list(),
+                        }
+                    }
+                }
+            }
+            message => return Err(ProtocolError::Unexpected(message)),
+        };
+        answer.write_to(&mut stream)?;
+    }
+}
+
+/// Why a room can't be opened with the settings, if it can't
+fn check(settings: &RoomSettings) -> Result<(), String> {
+    if !(MIN_PLAYERS..=MAX_PLAYERS).contains(&(settings.players as usize)) {
+        return Err(format!(
+            "a match has {} to {} players, not {}",
+            MIN_PLAYERS, MAX_PLAYERS, settings.players
+        ));
+    }
+    if settings.start_level as usize >= TIME_LEVELS.len() {
+        return Err(format!(
+            "the level has to be between 0 and {}, not {}",
+            TIME_LEVELS.len() - 1,
+            settings.start_level
+        ));
+    }
+    if settings.name.trim().is_empty() {
+        return Err("a room needs a name".to_string());
+    }
+    Ok(())
+}
+
+/// A connection to a lobby, every request blocks until the lobby answers.
+/// Rooms opened through it stay open as long as it does.
+pub struct LobbyClient {
+    stream: TcpStream,
+    rooms: Vec<Room>, // as of the last answer
+}
+
+impl LobbyClient {
+    pub fn connect(adress: &str, name: &str) -> Result<LobbyClient, ProtocolError> {
+        let mut stream = TcpStream::connect(adress)?;
+        stream.set_read_timeout(Some(ANSWER_TIMEOUT))?;
+        stream.set_nodelay(true).ok();
+        Message::LobbyHello {
+            version: PROTOCOL_VERSION,
+            name: name.to_string(),
+        }
+        .write_to(&mut stream)?;
+        let mut client = LobbyClient {
+            stream,
+            rooms: Vec::new(),
+        };
+        client.answer()?;
+        Ok(client)
+    }
+
+    /// The rooms as of the last answer of the lobby
+    pub fn get_rooms(&self) -> &[Room] {
+        &self.rooms
+    }
+
+    /// Asks for the rooms that are open now
+    pub fn list(&mut self) -> Result<&[Room], ProtocolError> {
+        Message::ListRooms.write_to(&mut self.stream)?;
+        match self.answer()? {
+            Message::Rooms { .. } => Ok(&self.rooms),
+            message => Err(ProtocolError::Unexpected(message)),
+        }
+    }
+
+    /// Opens a room, returns its id
+    pub fn create(&mut self, settings: RoomSettings) -> Result<u32, ProtocolError> {
+        Message::CreateRoom { settings }.write_to(&mut self.stream)?;
+        match self.answer()? {
+            Message::RoomCreated { id } => Ok(id),
+            message => Err(ProtocolError::Unexpected(message)),
+        }
+    }
+
+    /// Takes a place in a room, returns the adress to connect to for its match
+    pub fn join(&mut self, id: u32) -> Result<String, ProtocolError> {
+        Message::JoinRoom { id }.write_to(&mut self.stream)?;
+        match self.answer()? {
+            Message::HandOff { adress } => Ok(adress),
+            message => Err(ProtocolError::Unexpected(message)),
+        }
+    }
+
+    /// Says how many players are in a room we opened
+    pub fn update(&mut self, id: u32, joined: u8) -> Result<(), ProtocolError> {
+        Message::UpdateRoom { id, joined }.write_to(&mut self.stream)?;
+        match self.answer()? {
+            Message::Rooms { .. } => Ok(()),
+            message => Err(ProtocolError::Unexpected(message)),
+        }
+    }
+
+    /// Reads the next answer, a refusal is turned into an error and a room list is kept
+    fn answer(&mut self) -> Result<Message, ProtocolError> {
+        match Message::read_from(&mut self.stream)? {
+            Message::Reject { reason } => Err(ProtocolError::Rejected(reason)),
+            Message::Rooms { rooms } => {
+                self.rooms = rooms.clone();
+                Ok(Message::Rooms { rooms })
+            }
+            message => Ok(message),
+        }
+    }
+}
@@ -1,176 +1,1504 @@
-use crate::game_data::{Color, Piece, Player, COLS, ROWS, SHAPES};
-use ggez::{event::KeyCode, graphics::pipe::new};
-use rand::distributions::uniform;
-use std::env;
+use crate::game_data::{
+    Attack, AttackTable, Clear, GameRng, Move, Player, RandomizerKind, COLS, ROWS,
+};
+use ggez::event::KeyCode;
+use log::{info, warn};
+use rand::RngCore;
+use std::collections::HashMap;
 
 use libloading::{Library, Symbol};
 
-use std::io::prelude::*;
+use std::fs;
+use std::mem;
 use std::net::TcpListener;
-use std::net::TcpStream;
-use std::sync::mpsc;
-use std::thread;
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
 
-pub const PLAYER_AMOUNT: usize = 2;
+/// Fewest and most players in a match
+pub const MIN_PLAYERS: usize = 2;
+pub const MAX_PLAYERS: usize = 8;
+/// The port a host listens on when no adress is given
+pub const DEFAULT_PORT: u16 = 8787;
+/// Game ticks per second, decided by the host
+pub const DEFAULT_TICK_RATE: u32 = 60;
+/// Ticks between a local move and the tick it is applied on, gives the opponent's moves time to arrive
+pub const INPUT_DELAY: u64 = 3;
+/// Max ticks simulated in one update, so a stall doesn't turn into a long fast-forward
+const MAX_CATCH_UP: u32 = 5;
 /// Function signature for the ai-script
 type AIFunc = unsafe fn(*const [[u32; 10]; 24], *const [[i32; 2]; 4], *const [[i32; 2]; 4]) -> u32;
-type Packet = [u8; 2];
+/// The AI script used when none is given, `ai-example/ai.rs` built by `build.rs` except on Windows
+#[cfg(windows)]
+pub const DEFAULT_AI: &str = "ai-example/windows/ai.dll";
+#[cfg(not(windows))]
+pub const DEFAULT_AI: &str = concat!(env!("OUT_DIR"), "/libai.so");
+
+pub mod auth;
+pub mod chat;
+pub mod connection;
+pub mod discovery;
+pub mod impairment;
+pub mod keys;
+pub mod lobby;
+pub mod protocol;
+pub mod replay;
+mod rollback;
+pub mod server;
+pub mod stats;
+pub mod targeting;
+pub mod teams;
 #[cfg(test)]
 mod tests;
 
+use auth::Seal;
+use chat::{Chat, ChatLine};
+use connection::{Acceptor, Connection, Incoming, NetConfig, Reconnect, Traffic};
+use discovery::Beacon;
+use keys::KeyBindings;
+use protocol::{Frame, Message, ProtocolError, Settings, TimedAttack, PROTOCOL_VERSION};
+use replay::Replay;
+use rollback::Rollback;
+use stats::{Check, NetStats, RateMeter};
+use targeting::Targeting;
+
+/// Frames are kept this many ticks after they're played, in case the host resends an older state
+const FRAME_HISTORY: u64 = 2 * (INPUT_DELAY + 1);
+/// Ticks skipped between the host's last tick of a match and the first of the next.
+/// More than either side can have run ahead, so every move sent during the old match is for a tick before the new one.
+const NEW_MATCH_GAP: u64 = 64;
+
+/// What kind of match to play
+pub enum Mode {
+    /// Every player on this machine, the first two played from the keyboard or by an AI script and the rest by AI scripts
+    Local,
+    /// Plays back the replay file at the path
+    Replay(String),
+    /// Hosts a networked match, on every interface if no adress is given
+    Host(Option<String>),
+    /// Joins a networked match, lets the player pick one on the local network if no adress is given
+    Join(Option<String>),
+    /// Watches a networked match
+    Spectate(Option<String>),
+}
+
+/// What the player picked before the match starts
+#[derive(Clone, Debug)]
+pub struct Setup {
+    pub name: String,
+    pub start_level: usize,
+    pub seed: Option<u64>,          // random when not given
+    pub best_of: u8,                // matches in a series, decided by the host in network matches
+    pub players: u8,                // everyone in the match, decided by the host in network matches
+    pub targeting: Targeting,       // who our garbage goes to when there's more than one opponent
+    pub teams: u8, // fewer than two is every player for themselves, decided by the host in network matches
+    pub split_garbage: bool, // garbage sent to a team is shared between its members
+    pub randomizer: RandomizerKind, // how the pieces are dealt, decided by the host in network matches
+    pub attack_table: AttackTable, // combo and back-to-back bonuses, decided by the host in network matches
+    pub ai: [Option<String>; 2], // the AI scripts playing P1 and P2 instead of the keyboard, only ours in network matches
+    pub bindings: KeyBindings,
+    pub config: NetConfig,
+    pub record: Option<String>, // where to save a replay of the match
+    pub chat_in_game: bool,     // the chat can be opened during a match, not only between matches
+    pub lobby: Option<String>,  // the lobby to browse on the start screen
+}
+
+impl Default for Setup {
+    fn default() -> Setup {
+        Setup {
+            name: "Player".to_string(),
+            start_level: 5,
+            seed: None,
+            best_of: 1,
+            players: MIN_PLAYERS as u8,
+            targeting: Targeting::Random,
+            teams: 0,
+            split_garbage: false,
+            randomizer: RandomizerKind::Bag7,
+            attack_table: AttackTable::default(),
+            ai: [None, None],
+            bindings: KeyBindings::default(),
+            config: NetConfig::default(),
+            record: None,
+            chat_in_game: false,
+            lobby: None,
+        }
+    }
+}
+
+/// Which end of the connections we are, and how to get back to the other end if one is lost
+enum Role {
+    /// Every player is on this machine, there is no connection
+    Local,
+    /// Keeps accepting spectators and the clients coming back
+    Host(Acceptor),
+    /// Knows the host's adress to reconnect to
+    Client(String),
+    /// Only watches, every player is simulated from the moves the host forwards
+    Spectator,
+}
+
+/// Another machine in the match: every client for the host, the host for a client or spectator
+struct Peer {
+    side: usize, // the side of the player on that machine
+    connection: Connection,
+    session: u64,                        // token the client uses to resume the match
+    reconnect_deadline: Option<Instant>, // set while the connection is lost
+}
+
 pub struct Game {
-    players: [Player; PLAYER_AMOUNT],
-    ai_lib: [Option<Library>; 2],
-    recieved_moves: mpsc::Receiver<Packet>, // channel for networking
-    moves_to_send: mpsc::Sender<Packet>,
+    players: Vec<Player>, // ours first, then the others in side order after ours
+    ai_lib: Vec<Option<Library>>,
+    peers: Vec<Peer>,
+    role: Role,
+    config: NetConfig,
+    reconnect: Option<Reconnect>, // the client trying to get a lost connection back
+    awaiting_snapshot: bool,      // the client waits for the host's state after reconnecting
+    authoritative: bool, // a match server simulates the match, we only send moves and show its state
+    lost_connection: Option<String>, // set when a peer didn't come back in time
+    spectators: Vec<(String, Connection)>,
+    beacon: Option<Beacon>, // announces the host's match on the local network
+    settings: Settings,
+    names: Vec<String>,                       // in the same order as `players`
+    side: u64,                                // our place in the side order, 0 for the host
+    tick: u64,                                // the next tick to simulate
+    sent_until: u64,                          // local frames before this tick have been sent
+    local_moves: Vec<Vec<Move>>, // moves not yet put in a frame, only the first player has any in network matches
+    frames: Vec<HashMap<u64, Vec<Move>>>, // the moves of each player by tick
+    attacks: Vec<HashMap<u64, (u8, Attack)>>, // the garbage each player sent by the tick it lands, with the side it lands on
+    rollback: Option<Rollback>, // set when the match is played with rollback instead of lockstep
+    last_update: Instant,
+    lag: Duration, // time not yet simulated
+    sent_game_over: bool,
+    checksums: HashMap<u64, Vec<Player>>, // our players at the ticks being checked, in side order
+    remote_checksums: HashMap<(usize, u64), Vec<u64>>, // the peers' checksums by their side and the tick
+    last_desync: Option<u64>,                          // the tick the states last differed at
+    last_check: Option<Check>, // the last comparison of checksums with a peer
+    traffic: RateMeter,
+    bindings: KeyBindings,
+    record_to: Option<String>, // the file every match is saved to when it's over
+    recording: Option<Replay>, // the match so far, with ticks counted from `match_start`
+    match_start: u64,          // the tick the current match started on
+    replaying: Option<Replay>, // the recorded match being played back
+    wins: Vec<u32>,            // matches won in the current series
+    result_counted: bool,      // the winner of the match that is over has been added to `wins`
+    rematch: Vec<bool>,        // who has asked to play again since the match ended
+    chat: Chat,
+    targeting: Targeting, // who the garbage of the players on this machine goes to
 }
 
 impl Game {
-    pub fn new(init_level: usize) -> Game {
-        // let library: Option<Library>;
-        // if let Some(lib_path) = env::args().nth(1) {
-        //     if cfg!(windows) && !lib_path.ends_with(".dll") {
-        //         panic!("Must use .dll if running an AI in windows!")
-        //     } else if cfg!(unix) && !lib_path.ends_with(".so") {
-        //         panic!("Must use .so if running an AI in a 'nix-system!")
-        //     }
-        //     if let Ok(lib) = Library::new(lib_path) {
-        //         library = Some(lib);
-        //     } else {
-        //         library = None;
-        //     }
-        // } else {
-        //     library = None;
-        // }
-        // let library2: Option<Library>;
-        // if let Some(lib_path2) = env::args().nth(2) {
-        //     if cfg!(windows) && !lib_path2.ends_with(".dll") {
-        //         panic!("Must use .dll if running an AI in windows!")
-        //     } else if cfg!(unix) && !lib_path2.ends_with(".so") {
-        //         panic!("Must use .so if running an AI in a 'nix-system!")
-        //     }
-        //     if let Ok(lib2) = Library::new(lib_path2) {
-        //         library2 = Some(lib2);
-        //     } else {
-        //         library2 = None;
-        //     }
-        // } else {
-        //     library2 = None;
-        // }
-
-        let mut players = [Player::new(init_level), Player::new(init_level)];
-        // open channel for multi-threading
-        let (sender, recieved_moves) = mpsc::channel();
-        let (moves_to_send, reciever) = mpsc::channel();
-
-        let mut stream: TcpStream;
-        let connection_type = env::args().nth(1).unwrap();
-        let ip = env::args().nth(2).unwrap();
-        // establish connection
-        if  connection_type == "host" {
-            let listener = TcpListener::bind(ip).expect("invalid adress");
-            stream = listener.accept().unwrap().0;
-        } else if connection_type == "connect" {
-            stream = TcpStream::connect(ip).unwrap();
-        } else {
-            panic!("Could not establish connection");
-        }
-
-        // connection established
-        // send first package containing current piece and next piece
-        stream
-            .write(&[
-                players[0].current_piece.color as u8,
-                players[0].next_piece.color as u8,
-            ])
-            .unwrap();
-        stream.flush().unwrap();
-
-        // read first package
-        let mut buffer = [0; 2];
-        stream.read(&mut buffer);
-
-        // set P2 pieces according to package
-        let current_piece = Game::piece_from_u8(buffer[0]);
-        let next_piece = Game::piece_from_u8(buffer[1]);
-        players[1].current_piece = current_piece;
-        players[1].next_piece = next_piece;
-
-        // spawn thread
-        thread::spawn(move || {
-            Game::handle_connection(stream, sender, reciever);
-        });
-
+    /// Sets up the match, only connects to anyone in the networked modes
+    pub fn start_mode(mode: Mode, setup: &Setup) -> Result<Game, String> {
+        let scripts = match mode {
+            Mode::Local => Game::local_scripts(setup),
+            _ => vec![setup.ai[0].clone()],
+        };
+        let mut ai_lib = Vec::new();
+        for (n, script) in scripts.iter().enumerate() {
+            ai_lib.push(match script {
+                // nobody picked the script of the players after P2 if P2 is on the keyboard
+                Some(script) if n >= 2 && setup.ai[1].is_none() => {
+                    let hint =
+                        "the players after P2 are played by the example AI, pick one with --ai2";
+                    Some(load_ai(script).map_err(|err| format!("{}: {}", hint, err))?)
+                }
+                Some(script) => Some(load_ai(script)?),
+                None => None,
+            });
+        }
+        let level = setup.start_level;
+        let name = setup.name.clone();
+        let config = NetConfig {
+            seed: setup.seed,
+            best_of: setup.best_of,
+            players: setup.players,
+            teams: setup.teams,
+            split_garbage: setup.split_garbage,
+            randomizer: setup.randomizer,
+            attack_table: setup.attack_table,
+            ..setup.config
+        };
+        let mut game = match mode {
+            Mode::Local => {
+                let names = Game::local_names(&scripts);
+                let seed = setup.seed.unwrap_or_else(rand::random);
+                let mut game = Game::local(level, seed, ai_lib, names);
+                game.settings.best_of = setup.best_of;
+                game.settings.teams = setup.teams;
+                game.settings.split_garbage = setup.split_garbage;
+                game.settings.randomizer = setup.randomizer;
+                game.settings.attack_table = setup.attack_table;
+                // dealt again with the randomizer that was picked
+                game.players = Game::new_players(&game.settings, 0, game.players.len());
+                game
+            }
+            Mode::Replay(path) => {
+                let replay = Replay::load(&path)
+                    .map_err(|err| format!("could not play {}: {}", path, err))?;
+                Game::replay(replay)
+            }
+            Mode::Host(ip) => {
+                let ip = ip.unwrap_or_else(|| format!("0.0.0.0:{}", DEFAULT_PORT));
+                info!("Waiting for opponents on {}...", ip);
+                let mut game =
+                    Game::host_on_lan(&ip, level, name, config).map_err(|err| err.to_string())?;
+                game.ai_lib[0] = ai_lib.remove(0);
+                game
+            }
+            Mode::Join(ip) => {
+                let mut game = Game::find_adress(ip, false)
+                    .and_then(|ip| Game::connect(&ip, name, config))
+                    .map_err(|err| err.to_string())?;
+                game.ai_lib[0] = ai_lib.remove(0);
+                game
+            }
+            Mode::Spectate(ip) => Game::find_adress(ip, true)
+                .and_then(|ip| Game::spectate(&ip, name, config))
+                .map_err(|err| err.to_string())?,
+        };
+        game.bindings = setup.bindings.clone();
+        game.targeting = setup.targeting;
+        if let Some(path) = &setup.record {
+            if game.authoritative {
+                warn!("Matches on a match server can't be recorded");
+            } else {
+                game.record_to = Some(path.clone());
+                game.start_recording();
+            }
+        }
+        Ok(game)
+    }
+    /// The AI scripts of a local match, the players after the first two are played by P2's script or the example one
+    fn local_scripts(setup: &Setup) -> Vec<Option<String>> {
+        let players = (setup.players as usize).clamp(MIN_PLAYERS, MAX_PLAYERS);
+        (0..players)
+            .map(|n| match n {
+                0 | 1 => setup.ai[n].clone(),
+                _ => Some(
+                    setup.ai[1]
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_AI.to_string()),
+                ),
+            })
+            .collect()
+    }
+    /// "P1", "P2" and so on, or "AI 1", "AI 2" for the players played by AI scripts
+    fn local_names(scripts: &[Option<String>]) -> Vec<String> {
+        scripts
+            .iter()
+            .enumerate()
+            .map(|(n, script)| match script {
+                Some(_) => format!("AI {}", n + 1),
+                None => format!("P{}", n + 1),
+            })
+            .collect()
+    }
+    /// A match where every player is on this machine
+    fn local(
+        init_level: usize,
+        seed: u64,
+        ai_lib: Vec<Option<Library>>,
+        names: Vec<String>,
+    ) -> Game {
+        let settings = Settings {
+            start_level: init_level as u8,
+            cols: COLS as u8,
+            rows: ROWS as u8,
+            seed,
+            tick_rate: DEFAULT_TICK_RATE,
+            rollback: false,
+            best_of: 1,
+            teams: 0,
+            split_garbage: false,
+            randomizer: RandomizerKind::Bag7,
+            attack_table: AttackTable::default(),
+        };
+        let n = names.len();
         Game {
-            players: players,
-            ai_lib: [None, None], //[library2, library],
-            recieved_moves: recieved_moves,
-            moves_to_send: moves_to_send,
+            players: Game::new_players(&settings, 0, n),
+            ai_lib,
+            peers: Vec::new(),
+            role: Role::Local,
+            config: NetConfig::default(),
+            reconnect: None,
+            awaiting_snapshot: false,
+            authoritative: false,
+            lost_connection: None,
+            spectators: Vec::new(),
+            beacon: None,
+            settings,
+            names,
+            side: 0,
+            tick: 0,
+            sent_until: 0,
+            local_moves: vec![Vec::new(); n],
+            frames: vec![HashMap::new(); n],
+            attacks: vec![HashMap::new(); n],
+            rollback: None,
+            last_update: Instant::now(),
+            lag: Duration::from_secs(0),
+            sent_game_over: false,
+            checksums: HashMap::new(),
+            remote_checksums: HashMap::new(),
+            last_desync: None,
+            last_check: None,
+            traffic: RateMeter::default(),
+            bindings: KeyBindings::default(),
+            record_to: None,
+            recording: None,
+            match_start: 0,
+            replaying: None,
+            wins: vec![0; n],
+            result_counted: false,
+            rematch: vec![false; n],
+            chat: Chat::default(),
+            targeting: Targeting::Random,
+        }
+    }
+    /// Plays a recorded match again, the keyboard does nothing
+    fn replay(replay: Replay) -> Game {
+        let ai_lib = replay.names.iter().map(|_| None).collect();
+        let mut game = Game::local(0, 0, ai_lib, replay.names.clone());
+        game.settings = replay.settings.clone();
+        game.players = Game::new_players(&game.settings, 0, game.names.len());
+        game.replaying = Some(replay);
+        game
+    }
+    /// Hosts a match and announces it on the local network while it lasts
+    pub fn host_on_lan(
+        adress: &str,
+        init_level: usize,
+        name: String,
+        config: NetConfig,
+    ) -> Result<Game, ProtocolError> {
+        let port = adress
+            .parse::<SocketAddr>()
+            .map_or(DEFAULT_PORT, |adress| adress.port());
+        let free_slots = config.players.max(MIN_PLAYERS as u8) - 1;
+        let beacon = match Beacon::start(name.clone(), port, free_slots) {
+            Ok(beacon) => Some(beacon),
+            Err(err) => {
+                warn!("Could not announce the game on the local network: {}", err);
+                None
+            }
+        };
+        let mut game = Game::host(adress, init_level, name, config)?;
+        if let Some(beacon) = &beacon {
+            // still announced so spectators can find it
+            beacon.set_free_slots(0);
         }
+        game.beacon = beacon;
+        Ok(game)
     }
-    /// The game-tick update function
+    /// Uses the given adress, or lets the player pick a game found on the local network
+    fn find_adress(adress: Option<String>, spectating: bool) -> Result<String, ProtocolError> {
+        match adress {
+            Some(adress) => Ok(adress),
+            None => Ok(discovery::choose_game(spectating)?.to_string()),
+        }
+    }
+    /// Waits for every client to connect and decides the settings of the match
+    pub fn host(
+        adress: &str,
+        init_level: usize,
+        name: String,
+        config: NetConfig,
+    ) -> Result<Game, ProtocolError> {
+        let listener = TcpListener::bind(adress)?;
+        Game::host_listening(listener, init_level, name, config)
+    }
+    /// `host` on a listener that's already bound, clients can connect as soon as it is
+    pub fn host_listening(
+        listener: TcpListener,
+        init_level: usize,
+        name: String,
+        config: NetConfig,
+    ) -> Result<Game, ProtocolError> {
+        let players = (config.players as usize).clamp(MIN_PLAYERS, MAX_PLAYERS);
+        // the clients that said hello, in the order they joined
+        let mut joined: Vec<(TcpStream, String, Option<Seal>)> = Vec::new();
+        while joined.len() + 1 < players {
+            let mut stream = listener.accept()?.0;
+            stream.set_read_timeout(Some(config.timeout))?;
+            match Message::read_from(&mut stream) {
+                Ok(Message::Hello {
+                    version,
+                    name: opponent,
+                }) if version == PROTOCOL_VERSION => {
+                    let seal = match auth::check_password(&mut stream, config.key.as_ref()) {
+                        Ok(seal) => seal,
+                        Err(err) => {
+                            warn!("Ignored {}: {}", opponent, err);
+                            continue;
+                        }
+                    };
+                    info!("{} joined", opponent);
+                    joined.push((stream, opponent, seal));
+                    if joined.len() + 1 < players {
+                        // everyone waiting hears how many have joined, whoever can't be reached has left
+                        let waiting = Message::Waiting {
+                            joined: joined.len() as u8 + 1,
+                            players: players as u8,
+                        };
+                        joined.retain_mut(|(stream, name, seal)| {
+                            let here = auth::write(&waiting, stream, seal).is_ok();
+                            if !here {
+                                info!("{} left", name);
+                            }
+                            here
+                        });
+                    }
+                }
+                Ok(Message::Hello { version, .. }) => {
+                    let reason = format!(
+                        "protocol version {} is not supported, the host runs version {}",
+                        version, PROTOCOL_VERSION
+                    );
+                    warn!("Rejected a client: {}", reason);
+                    Message::Reject { reason }.write_to(&mut stream).ok();
+                }
+                Ok(message) => warn!("Ignored a client: {}", ProtocolError::Unexpected(message)),
+                Err(err) => warn!("Ignored a client: {}", err),
+            }
+        }
+        if config.rollback && players > MIN_PLAYERS {
+            warn!("Rollback only works with two players, the match is played in lockstep");
+        }
+        let settings = Settings {
+            start_level: init_level as u8,
+            cols: COLS as u8,
+            rows: ROWS as u8,
+            seed: config.seed.unwrap_or_else(rand::random),
+            tick_rate: DEFAULT_TICK_RATE,
+            rollback: config.rollback && players == MIN_PLAYERS,
+            best_of: config.best_of,
+            teams: config.teams,
+            split_garbage: config.split_garbage,
+            randomizer: config.randomizer,
+            attack_table: config.attack_table,
+        };
+        let mut names = vec![name];
+        names.extend(joined.iter().map(|(_, name, _)| name.clone()));
+        let mut peers = Vec::new();
+        for (index, (mut stream, _, mut seal)) in joined.into_iter().enumerate() {
+            let session = rand::random();
+            let welcome = Message::Welcome {
+                settings: settings.clone(),
+                side: index as u8 + 1,
+                names: names.clone(),
+                session,
+            };
+            auth::write(&welcome, &mut stream, &mut seal)?;
+            peers.push((index + 1, stream, session, seal));
+        }
+        let sessions = peers.iter().map(|(_, _, session, _)| *session).collect();
+        let role = Role::Host(Acceptor::start(listener, sessions, config));
+        Ok(Game::start(peers, role, config, settings, 0, names))
+    }
+    /// Connects to a host and plays with the settings it sends back once every player has joined
+    pub fn connect(adress: &str, name: String, config: NetConfig) -> Result<Game, ProtocolError> {
+        let mut stream = TcpStream::connect(adress)?;
+        stream.set_read_timeout(Some(config.timeout))?;
+        Message::Hello {
+            version: PROTOCOL_VERSION,
+            name,
+        }
+        .write_to(&mut stream)?;
+        let mut seal = auth::give_password(&mut stream, config.key.as_ref())?;
+        loop {
+            match auth::read(&mut stream, &mut seal)? {
+                Message::Waiting { joined, players } => {
+                    info!("Waiting for players, {} of {} are here", joined, players);
+                    // the host only says more when someone joins, which can take a while
+                    stream.set_read_timeout(None)?;
+                }
+                Message::Welcome {
+                    settings,
+                    side,
+                    names,
+                    session,
+                } if Game::valid_side(side, &names) && side != 0 => {
+                    let role = Role::Client(adress.to_string());
+                    let peers = vec![(0, stream, session, seal)];
+                    return Ok(Game::start(
+                        peers,
+                        role,
+                        config,
+                        settings,
+                        side as u64,
+                        names,
+                    ));
+                }
+                Message::Seated {
+                    settings,
+                    side,
+                    names,
+                    session,
+                } if Game::valid_side(side, &names) => {
+                    let role = Role::Client(adress.to_string());
+                    let peers = vec![(0, stream, session, seal)];
+                    let mut game = Game::start(peers, role, config, settings, side as u64, names);
+                    game.authoritative = true;
+                    // nothing is shown until the server's first state
+                    game.awaiting_snapshot = true;
+                    return Ok(game);
+                }
+                Message::Challenge { .. } => {
+                    return Err(ProtocolError::Unauthorized(
+                        "the host wants a password, give it with --password",
+                    ))
+                }
+                Message::Reject { reason } => return Err(ProtocolError::Rejected(reason)),
+                message => return Err(ProtocolError::Unexpected(message)),
+            }
+        }
+    }
+    /// Whether `side` is a place in a match between `names`
+    fn valid_side(side: u8, names: &[String]) -> bool {
+        (MIN_PLAYERS..=MAX_PLAYERS).contains(&names.len()) && (side as usize) < names.len()
+    }
+    /// Connects to a host to watch its match
+    pub fn spectate(adress: &str, name: String, config: NetConfig) -> Result<Game, ProtocolError> {
+        let mut stream = TcpStream::connect(adress)?;
+        stream.set_read_timeout(Some(config.timeout))?;
+        Message::Spectate {
+            version: PROTOCOL_VERSION,
+            name,
+        }
+        .write_to(&mut stream)?;
+        let mut seal = auth::give_password(&mut stream, config.key.as_ref())?;
+        match auth::read(&mut stream, &mut seal)? {
+            Message::Spectating { settings, names } if Game::valid_side(0, &names) => {
+                let peers = vec![(0, stream, 0, seal)];
+                Ok(Game::start(
+                    peers,
+                    Role::Spectator,
+                    config,
+                    settings,
+                    0,
+                    names,
+                ))
+            }
+            Message::Challenge { .. } => Err(ProtocolError::Unauthorized(
+                "the host wants a password, give it with --password",
+            )),
+            Message::Reject { reason } => Err(ProtocolError::Rejected(reason)),
+            message => Err(ProtocolError::Unexpected(message)),
+        }
+    }
+    /// Starts the network threads and the match once the handshake is done.
+    /// `peers` are the side, stream, session and seal of every machine we're connected to, `names` are in side order.
+    fn start(
+        peers: Vec<(usize, TcpStream, u64, Option<Seal>)>,
+        role: Role,
+        config: NetConfig,
+        settings: Settings,
+        side: u64,
+        mut names: Vec<String>,
+    ) -> Game {
+        info!("{}", names.join(" vs "));
+        let n = names.len();
+        // ours first, like the players
+        names.rotate_left(side as usize);
+        // spectators start from the host's current state
+        let awaiting_snapshot = matches!(role, Role::Spectator);
+        let players = Game::new_players(&settings, side, n);
+        let sent_until = if settings.rollback { 0 } else { INPUT_DELAY };
+        let rollback = match role {
+            Role::Host(_) | Role::Client(_) if settings.rollback && n == MIN_PLAYERS => {
+                Some(Rollback::new(&peers[0].1, players.clone()))
+            }
+            _ => None,
+        };
+        // the udp port goes to the only peer of a rollback match
+        let udp_peer = peers.first().and_then(|(_, stream, _, seal)| {
+            stream.try_clone().ok().map(|stream| (stream, seal.clone()))
+        });
+        let peers = peers
+            .into_iter()
+            .map(|(side, stream, session, seal)| Peer {
+                side,
+                connection: Connection::start(stream, config, seal),
+                session,
+                reconnect_deadline: None,
+            })
+            .collect();
+        let mut game = Game {
+            players,
+            ai_lib: (0..n).map(|_| None).collect(),
+            peers,
+            role,
+            config,
+            reconnect: None,
+            awaiting_snapshot,
+            authoritative: false,
+            lost_connection: None,
+            spectators: Vec::new(),
+            beacon: None,
+            settings,
+            names,
+            side,
+            tick: 0,
+            sent_until,
+            local_moves: vec![Vec::new(); n],
+            frames: vec![HashMap::new(); n],
+            attacks: vec![HashMap::new(); n],
+            rollback,
+            last_update: Instant::now(),
+            lag: Duration::from_secs(0),
+            sent_game_over: false,
+            checksums: HashMap::new(),
+            remote_checksums: HashMap::new(),
+            last_desync: None,
+            last_check: None,
+            traffic: RateMeter::default(),
+            bindings: KeyBindings::default(),
+            record_to: None,
+            recording: None,
+            match_start: 0,
+            replaying: None,
+            wins: vec![0; n],
+            result_counted: false,
+            rematch: vec![false; n],
+            chat: Chat::default(),
+            targeting: Targeting::Random,
+        };
+        if let Some((stream, seal)) = udp_peer {
+            game.send_udp_port(&stream, seal.as_ref());
+        }
+        game
+    }
+    /// Creates the players with ours first and the others in side order after it.
+    /// Each side has its own seed so every peer ends up with the same pieces.
+    fn new_players(settings: &Settings, side: u64, count: usize) -> Vec<Player> {
+        let level = settings.start_level as usize;
+        (0..count as u64)
+            .map(|index| {
+                let seed = settings.seed.wrapping_add((side + index) % count as u64);
+                let mut player = Player::new(level, seed, settings.tick_rate, settings.randomizer);
+                player.set_attack_table(settings.attack_table);
+                player
+            })
+            .collect()
+    }
+    /// The side of the player at `index`
+    fn side_of(&self, index: usize) -> usize {
+        (index + self.side as usize) % self.players.len()
+    }
+    /// Where the player on `side` is kept
+    fn index_of(&self, side: usize) -> usize {
+        (side + self.players.len() - self.side as usize) % self.players.len()
+    }
+    /// Puts things kept in player order into side order (host first)
+    fn in_side_order<T: Clone>(&self, items: &[T]) -> Vec<T> {
+        let mut items = items.to_vec();
+        let len = items.len().max(1);
+        items.rotate_right(self.side as usize % len);
+        items
+    }
+    /// Puts things in side order into the order the players are kept in, ours first
+    fn to_player_order<T>(&self, mut items: Vec<T>) -> Vec<T> {
+        let len = items.len().max(1);
+        items.rotate_left(self.side as usize % len);
+        items
+    }
+    /// The game-tick update function, runs as many ticks as the clock and the other players allow
     pub fn update(&mut self) {
-        // get next action from remote opponent
-        if let Ok(package) = self.recieved_moves.try_recv() {
-            if package != [0, 0] {
-                //println!("Got a non [0,0] package");
-                self.players[1].next_piece = Game::piece_from_u8(package[1]);
-                self.parse_ai_output(1, package[0] as u32);
+        // get messages from the other machines
+        for p in 0..self.peers.len() {
+            while let Some(message) = self.peers[p].connection.try_recv() {
+                let from = self.peers[p].side;
+                self.handle_message(from, message);
             }
         }
-        // update game tick for players
-        let mut target_mod: i32 = 1; //Pairs, you attack the one next to you
-        for p in 0..self.players.len() {
-            self.players[p].update();
-            //attack handling
-            if let Some(attack) = self.players[p].take_outgoing() {
-                self.players[(p as i32 + target_mod) as usize].add_incoming(attack);
+        self.traffic.update(self.total_traffic());
+
+        let now = Instant::now();
+        self.lag += now - self.last_update;
+        self.last_update = now;
+        if !self.check_connection() {
+            // the match is paused until everyone is back
+            self.lag = Duration::from_secs(0);
+            return;
+        }
+        if self.authoritative {
+            self.count_result();
+            // the server applies our moves as soon as they arrive
+            if !self.local_moves[0].is_empty() && !self.is_spectator() {
+                let moves = mem::take(&mut self.local_moves[0]);
+                self.send_to_peers(Message::Input {
+                    tick: self.tick,
+                    moves,
+                });
             }
+            return;
+        }
 
-            target_mod *= -1;
+        if self.rollback.is_some() {
+            self.receive_packets();
+            self.roll_back();
+            self.confirm();
         }
-        for i in 0..self.ai_lib.len() {
-            if self.ai_lib[i].is_some() {
-                let ai_output = self.call_ai_script(i);
-                self.parse_ai_output(i, ai_output);
+
+        let tick_length = Duration::from_secs(1) / self.settings.tick_rate;
+        let mut ticks = 0;
+        // nothing more happens once the match is decided, until the next match
+        while self.lag >= tick_length && ticks < MAX_CATCH_UP && !self.is_over() {
+            if !self.step() {
+                break;
             }
+            self.lag -= tick_length;
+            ticks += 1;
+        }
+        // waiting on the others shouldn't build up ticks to rush through later
+        self.lag = self.lag.min(tick_length * MAX_CATCH_UP);
+        if self.rollback.is_some() {
+            self.send_packet();
         }
+        self.prune_frames();
+        self.compare_checksums();
+        self.count_result();
     }
-    /// Gets and returns the graphical boardstate of the players
-    pub fn get_boards(&self) -> [[[u32; COLS]; ROWS]; PLAYER_AMOUNT] {
-        [
-            self.players[0].get_board_visual(),
-            self.players[1].get_board_visual(),
-        ]
+    /// Handles a message from the peer playing on the `from` side
+    fn handle_message(&mut self, from: usize, message: Message) {
+        let host = matches!(self.role, Role::Host(_));
+        match message {
+            Message::Input { tick, moves } => self.receive_frame(from, tick, moves),
+            Message::Attack {
+                tick,
+                target,
+                lines,
+                hole,
+                delay,
+            } => self.receive_attack(from, tick, target, Attack { lines, hole, delay }),
+            Message::UdpPort { port } => {
+                if let Some(rollback) = &mut self.rollback {
+                    rollback.connect(port);
+                }
+            }
+            // the host forwards what the other players do, our own moves never come back
+            Message::Relay { side, tick, moves } if !host && self.is_other(side) => {
+                self.receive_frame(side as usize, tick, moves)
+            }
+            Message::RelayAttack {
+                side,
+                tick,
+                target,
+                lines,
+                hole,
+                delay,
+            } if !host && self.is_other(side) => {
+                self.receive_attack(side as usize, tick, target, Attack { lines, hole, delay })
+            }
+            Message::GameOver { tick } => info!(
+                "{} topped out at tick {}",
+                self.names[self.index_of(from)],
+                tick
+            ),
+            Message::Snapshot {
+                tick,
+                players,
+                frames,
+                attacks,
+            } if self.awaiting_snapshot => self.apply_snapshot(tick, players, frames, attacks),
+            Message::State { tick, players } => self.apply_state(tick, players),
+            Message::Checksum { tick, hashes } => {
+                self.remote_checksums.insert((from, tick), hashes);
+            }
+            Message::Resync if host => {
+                info!("{} asked for our state", self.names[self.index_of(from)]);
+                self.send_to(from, self.snapshot());
+            }
+            Message::Rematch { side } if !self.is_spectator() => {
+                // the host knows who sent it, the clients trust the host's forwarding
+                let side = if host { from as u8 } else { side };
+                if self.is_other(side) {
+                    let index = self.index_of(side as usize);
+                    info!("{} wants a rematch", self.names[index]);
+                    self.rematch[index] = true;
+                    if host {
+                        self.send_to_others(side as usize, Message::Rematch { side });
+                    }
+                    self.start_rematch();
+                }
+            }
+            Message::NewMatch {
+                tick,
+                settings,
+                wins,
+            } if !host && wins.len() == self.players.len() => {
+                self.wins = self.to_player_order(wins.iter().map(|wins| *wins as u32).collect());
+                // the host's score already has the last match in it
+                self.result_counted = true;
+                self.start_match(tick, settings);
+            }
+            Message::Chat { side, text } => {
+                let side = if host { from as u8 } else { side };
+                if (side as usize) < self.players.len() {
+                    self.receive_chat(side as usize, &text)
+                }
+            }
+            Message::Reject { reason } => self.lost_connection = Some(reason),
+            _ => (),
+        }
     }
-    /// Gets and returns the next pieces of the players
-    pub fn get_next_pieces(&self) -> [[[u32; 4]; 4]; PLAYER_AMOUNT] {
-        let mut next_pieces = [[[0; 4]; 4]; PLAYER_AMOUNT];
-        for p in 0..self.players.len() {
-            next_pieces[p] = self.players[p].get_next_piece().get_display_shape();
+    /// Whether `side` is a player whose moves come from another machine
+    fn is_other(&self, side: u8) -> bool {
+        (side as usize) < self.players.len() && (self.is_spectator() || side as u64 != self.side)
+    }
+    /// Sends a message to every peer
+    fn send_to_peers(&self, message: Message) {
+        for peer in &self.peers {
+            peer.connection.send(message.clone());
+        }
+    }
+    /// Sends a message to the peer playing on `side`
+    fn send_to(&self, side: usize, message: Message) {
+        if let Some(peer) = self.peers.iter().find(|peer| peer.side == side) {
+            peer.connection.send(message);
+        }
+    }
+    /// Lets the host forward a message from the player on `side` to the other clients
+    fn send_to_others(&self, side: usize, message: Message) {
+        if !matches!(self.role, Role::Host(_)) || side as u64 == self.side {
+            return;
+        }
+        for peer in self.peers.iter().filter(|peer| peer.side != side) {
+            peer.connection.send(message.clone());
+        }
+    }
+    /// Simulates one tick if the other players' moves for it have arrived, returns whether it did
+    fn step(&mut self) -> bool {
+        if self.rollback.is_some() {
+            return self.step_predicted();
+        }
+        if self.is_local() {
+            // nobody to wait for, the moves are played on the tick they're made
+            for index in 0..self.players.len() {
+                let moves = match &self.replaying {
+                    Some(replay) => {
+                        let tick = self.tick - self.match_start;
+                        if let Some(attack) = replay.attacks[index].get(&tick) {
+                            self.attacks[index].insert(self.tick, *attack);
+                        }
+                        replay.frames[index].get(&tick).cloned()
+                    }
+                    None => Some(self.take_moves(index)),
+                };
+                self.frames[index].insert(self.tick, moves.unwrap_or_default());
+            }
+            self.simulate(self.tick);
+            self.record(self.tick);
+            self.tick += 1;
+            return true;
+        }
+        // send what the local player did, to be applied INPUT_DELAY ticks from now
+        while !self.is_spectator() && self.sent_until <= self.tick + self.input_delay() {
+            self.seal_frame();
         }
 
-        next_pieces
+        // the first INPUT_DELAY ticks of a match have no moves on either side
+        let tick = self.tick;
+        if tick >= self.match_start + self.input_delay()
+            && !self.frames.iter().all(|frames| frames.contains_key(&tick))
+        {
+            return false;
+        }
+        self.simulate(tick);
+        self.record(tick);
+        self.tick += 1;
+        if !self.is_spectator() {
+            let players = self.players.clone();
+            self.check_state(self.tick, &players);
+        }
+        true
     }
-    /// Gets and returns the saved pieces of the players
-    pub fn get_saved_pieces(&self) -> [[[u32; 4]; 4]; PLAYER_AMOUNT] {
-        let mut saved_pieces = [[[0; 4]; 4]; PLAYER_AMOUNT];
-        for p in 0..self.players.len() {
-            if let Some(piece) = self.players[p].get_saved_piece() {
-                saved_pieces[p] = piece.get_display_shape();
+    /// Ticks between a local move and the tick it's applied on
+    fn input_delay(&self) -> u64 {
+        if self.settings.rollback {
+            0
+        } else {
+            INPUT_DELAY
+        }
+    }
+    /// The moves a player on this machine made since they were last taken, asks the AI script for one if it plays
+    fn take_moves(&mut self, index: usize) -> Vec<Move> {
+        if self.ai_lib[index].is_some() {
+            let ai_output = self.call_ai_script(index);
+            if let Some(mv) = Move::from_u8(ai_output as u8) {
+                self.local_moves[index].push(mv);
             }
         }
-        saved_pieces
+        mem::take(&mut self.local_moves[index])
     }
-    /// Gets the incoming attacks from players and returns formatted data
-    pub fn get_attackbars(&self) -> [u32; PLAYER_AMOUNT] {
-        let mut attackbars = [0; PLAYER_AMOUNT];
+    /// Puts the local moves in the next frame and sends it
+    fn seal_frame(&mut self) {
+        let frame_tick = self.sent_until;
+        let moves = self.take_moves(0);
+        // rollback matches send it over udp as well, the connection is the reliable fallback
+        self.send_to_peers(Message::Input {
+            tick: frame_tick,
+            moves: moves.clone(),
+        });
+        self.relay(self.side as usize, frame_tick, &moves);
+        self.frames[0].insert(frame_tick, moves);
+        self.sent_until = frame_tick + 1;
+    }
+    /// Takes the moves of the player on `side` for a tick, whichever way they came
+    fn receive_frame(&mut self, side: usize, tick: u64, moves: Vec<Move>) {
+        let index = self.index_of(side);
+        if self.frames[index].contains_key(&tick) {
+            return;
+        }
+        if let Some(rollback) = &mut self.rollback {
+            if !rollback.receive(tick, !moves.is_empty(), self.tick) {
+                return;
+            }
+        }
+        self.relay(side, tick, &moves);
+        self.frames[index].insert(tick, moves);
+    }
+    /// Takes the garbage the player on `side` sent, whichever way it came
+    fn receive_attack(&mut self, side: usize, tick: u64, target: u8, attack: Attack) {
+        let index = self.index_of(side);
+        if self.attacks[index].contains_key(&tick) || target as usize >= self.players.len() {
+            return;
+        }
+        if let Some(rollback) = &mut self.rollback {
+            if !rollback.receive(tick, true, self.tick) {
+                return;
+            }
+        }
+        self.relay_attack(side, tick, target, attack);
+        self.attacks[index].insert(tick, (target, attack));
+    }
+    /// Plays the moves of a tick and advances every player
+    fn simulate(&mut self, tick: u64) {
+        // the garbage landing on this tick comes first
+        for p in 0..self.players.len() {
+            if let Some((target, attack)) = self.attacks[p].get(&tick).cloned() {
+                let target = self.index_of(target as usize);
+                let from = self.side_of(p) as u8;
+                let (teams, split) = (self.get_teams(), self.settings.split_garbage);
+                let receivers =
+                    teams::receivers(target, &self.players, &teams, split, |i| self.side_of(i));
+                let shares = teams::share(attack, receivers.len());
+                for (receiver, share) in receivers.into_iter().zip(shares) {
+                    if let Some(share) = share {
+                        self.players[receiver].add_incoming_from(share, from);
+                    }
+                }
+            }
+        }
+        let lost: Vec<bool> = self.players.iter().map(Player::get_lost).collect();
         for p in 0..self.players.len() {
-            for (attack, _) in self.players[p].get_incoming() {
-                attackbars[p] += *attack as u32;
+            let moves = self.frames[p].get(&tick).cloned().unwrap_or_default();
+            for mv in moves {
+                self.players[p].apply_move(mv);
             }
         }
-        attackbars
+        // update game tick for players
+        for player in &mut self.players {
+            player.update();
+        }
+        let (n, side) = (self.players.len(), self.side as usize);
+        targeting::count_knockouts(&mut self.players, &lost, |s| (s + n - side) % n);
+        for p in 0..self.players.len() {
+            let attack = self.players[p].take_outgoing();
+            // only garbage from a tick simulated for the first time is sent, not from one simulated again after a rollback
+            if let (Some(attack), true) = (attack, tick == self.tick && self.sends_attacks(p)) {
+                if let Some(target) = self.pick_target(p, tick) {
+                    let target = self.side_of(target) as u8;
+                    self.send_attack(p, tick + 1 + self.input_delay(), target, attack);
+                }
+            }
+        }
+    }
+    /// Who the garbage the player at `index` sends on `tick` goes to, by our targeting.
+    /// The roll between equal targets comes from the seed, so a local match with a given seed plays out the same.
+    fn pick_target(&self, index: usize, tick: u64) -> Option<usize> {
+        let attacker = self.players[index]
+            .get_attacker()
+            .map(|side| self.index_of(side as usize));
+        let seed = self.settings.seed ^ tick.rotate_left(16) ^ self.side_of(index) as u64;
+        let roll = GameRng::new(seed).next_u64();
+        self.targeting
+            .pick(index, &self.players, &self.get_teams(), attacker, roll)
+    }
+    /// Whether our simulation of a player decides the garbage it sends, otherwise it comes from the network or a replay
+    fn sends_attacks(&self, index: usize) -> bool {
+        match self.role {
+            Role::Local => self.replaying.is_none(),
+            Role::Host(_) | Role::Client(_) => index == 0,
+            Role::Spectator => false,
+        }
+    }
+    /// Lets the garbage of a player land on the player on the `target` side at `tick`, on every other machine too.
+    /// It lands a tick after the next frame is sealed, so it's sent before the moves of that tick.
+    fn send_attack(&mut self, index: usize, tick: u64, target: u8, attack: Attack) {
+        if !self.is_local() {
+            self.send_to_peers(Message::Attack {
+                tick,
+                target,
+                lines: attack.lines,
+                hole: attack.hole,
+                delay: attack.delay,
+            });
+            self.relay_attack(self.side_of(index), tick, target, attack);
+        }
+        self.attacks[index].insert(tick, (target, attack));
+    }
+    /// Starts recording the current match if it should be
+    fn start_recording(&mut self) {
+        if self.record_to.is_some() {
+            let names = self.in_side_order(&self.names);
+            self.recording = Some(Replay::new(self.settings.clone(), names));
+        }
+    }
+    /// Adds the moves of a tick to the recording, and saves it once the match is decided
+    fn record(&mut self, tick: u64) {
+        if self.recording.is_none() {
+            return;
+        }
+        let sides: Vec<usize> = (0..self.players.len()).map(|i| self.side_of(i)).collect();
+        if let Some(replay) = &mut self.recording {
+            for (index, frames) in self.frames.iter().enumerate() {
+                if let Some(moves) = frames.get(&tick) {
+                    replay.record(sides[index], tick - self.match_start, moves);
+                }
+                if let Some((target, attack)) = self.attacks[index].get(&tick) {
+                    replay.record_attack(sides[index], tick - self.match_start, *target, *attack);
+                }
+            }
+        }
+        if self.is_over() {
+            self.save_recording();
+        }
+    }
+    /// Writes the recording to its file, nothing more is recorded until the next match
+    fn save_recording(&mut self) {
+        if let (Some(path), Some(replay)) = (&self.record_to, self.recording.take()) {
+            match replay.save(path) {
+                Ok(()) => info!("Saved the replay to {}", path),
+                Err(err) => warn!("Could not save the replay to {}: {}", path, err),
+            }
+        }
+    }
+    /// Forgets the moves no longer needed for simulating or for a snapshot
+    fn prune_frames(&mut self) {
+        let oldest = match &self.rollback {
+            Some(rollback) => rollback.oldest_needed(),
+            None => self.tick,
+        };
+        for frames in &mut self.frames {
+            frames.retain(|t, _| t + FRAME_HISTORY >= oldest);
+        }
+        for attacks in &mut self.attacks {
+            attacks.retain(|t, _| t + FRAME_HISTORY >= oldest);
+        }
+    }
+    /// Reports a game over and sends a checksum when due, `players` is the final state at the start of `tick`
+    fn check_state(&mut self, tick: u64, players: &[Player]) {
+        if players[0].get_lost() && !self.sent_game_over {
+            self.send_to_peers(Message::GameOver { tick });
+            self.sent_game_over = true;
+        }
+        // checked_rem is None when the checks are turned off
+        if tick.checked_rem(self.config.checksum_interval) == Some(0) {
+            let players = self.in_side_order(players);
+            self.send_to_peers(Message::Checksum {
+                tick,
+                hashes: players.iter().map(Player::checksum).collect(),
+            });
+            self.checksums.insert(tick, players);
+        }
+    }
+    /// Compares our states with the peers' at the ticks both have checked
+    fn compare_checksums(&mut self) {
+        let mut checks: Vec<(usize, u64)> = self
+            .remote_checksums
+            .keys()
+            .filter(|(_, tick)| self.checksums.contains_key(tick))
+            .cloned()
+            .collect();
+        checks.sort_unstable_by_key(|(side, tick)| (*tick, *side));
+        for (side, tick) in checks {
+            let remote = self.remote_checksums.remove(&(side, tick)).unwrap();
+            let local = self.checksums[&tick].clone();
+            let matched = local
+                .iter()
+                .map(Player::checksum)
+                .eq(remote.iter().cloned());
+            self.last_check = Some(Check { tick, matched });
+            if !matched {
+                self.report_desync(side, tick, &local, &remote);
+            }
+        }
+        // checks nobody answered are dropped after a while, ours are kept that long for the slower peers
+        let oldest = self.tick.saturating_sub(10 * self.config.checksum_interval);
+        self.checksums.retain(|tick, _| *tick >= oldest);
+        self.remote_checksums.retain(|(_, tick), _| *tick >= oldest);
+    }
+    /// Writes our state at the tick to a file that can be diffed against the peer's,
+    /// and lets the client ask the host for its state
+    fn report_desync(&mut self, from: usize, tick: u64, local: &[Player], remote: &[u64]) {
+        warn!(
+            "Desync at tick {} with {}! they have {:016x?}",
+            tick,
+            self.names[self.index_of(from)],
+            remote
+        );
+        self.last_desync = Some(tick);
+        let mut dump = String::new();
+        for (side, player) in local.iter().enumerate() {
+            let name = &self.names[self.index_of(side)];
+            dump += &format!("== side {}: {} ==\n", side, name);
+            dump += &player.dump();
+        }
+        let path = format!("desync-{}-side{}.txt", tick, self.side);
+        match fs::write(&path, dump) {
+            Ok(()) => warn!("Our state was written to {}", path),
+            Err(err) => warn!("Could not write {}: {}", path, err),
+        }
+
+        if self.config.resync_on_desync && matches!(self.role, Role::Client(_)) {
+            info!("Asking the host for its state");
+            self.send_to_peers(Message::Resync);
+            self.awaiting_snapshot = true;
+        }
+    }
+    /// Notices lost connections and tries to get them back, returns whether the match can go on
+    fn check_connection(&mut self) -> bool {
+        if self.lost_connection.is_some() {
+            return false;
+        }
+        self.handle_incoming();
+        let mut connected = true;
+        for p in 0..self.peers.len() {
+            if let Some(deadline) = self.peers[p].reconnect_deadline {
+                if let Some((stream, seal)) = self.reconnect.as_ref().and_then(Reconnect::try_get) {
+                    info!("Reconnected!");
+                    self.set_connection(p, stream, seal);
+                    self.reconnect = None;
+                    self.peers[p].reconnect_deadline = None;
+                    self.awaiting_snapshot = true;
+                } else if Instant::now() >= deadline {
+                    let reason = self.peers[p]
+                        .connection
+                        .get_disconnected()
+                        .unwrap_or("timed out");
+                    self.lost_connection = Some(reason.to_string());
+                    self.reconnect = None;
+                    self.peers[p].reconnect_deadline = None;
+                }
+                connected = false;
+                continue;
+            }
+            if let Some(reason) = self.peers[p].connection.get_disconnected() {
+                match &self.role {
+                    Role::Host(_) => info!(
+                        "Waiting for {} to reconnect...",
+                        self.names[self.index_of(self.peers[p].side)]
+                    ),
+                    Role::Client(adress) => {
+                        info!("Trying to reconnect...");
+                        let adress = adress.clone();
+                        let session = self.peers[p].session;
+                        self.reconnect = Some(Reconnect::connect(adress, session, self.config));
+                    }
+                    Role::Spectator | Role::Local => {
+                        self.lost_connection = Some(reason.to_string());
+                        return false;
+                    }
+                }
+                self.peers[p].reconnect_deadline = Some(Instant::now() + self.config.grace_period);
+                connected = false;
+            }
+        }
+        connected && !self.awaiting_snapshot
+    }
+    /// Continues the match over a new connection to the peer at `p`
+    fn set_connection(&mut self, p: usize, stream: TcpStream, seal: Option<Seal>) {
+        match stream.try_clone() {
+            Ok(clone) => {
+                self.peers[p].connection = Connection::start(clone, self.config, seal.clone())
+            }
+            Err(err) => {
+                warn!("Could not use the new connection: {}", err);
+                return;
+            }
+        }
+        self.send_udp_port(&stream, seal.as_ref());
+    }
+    /// Lets the host take in spectators and the clients coming back, and drops spectators who left
+    fn handle_incoming(&mut self) {
+        let mut incoming = Vec::new();
+        if let Role::Host(acceptor) = &self.role {
+            while let Some(connection) = acceptor.try_recv() {
+                incoming.push(connection);
+            }
+        }
+        for connection in incoming {
+            match connection {
+                Incoming::Resume(stream, session, seal) => {
+                    // the session token proves who it is, even if we hadn't noticed the old connection dying
+                    let p = match self.peers.iter().position(|peer| peer.session == session) {
+                        Some(p) => p,
+                        None => continue,
+                    };
+                    info!(
+                        "{} reconnected!",
+                        self.names[self.index_of(self.peers[p].side)]
+                    );
+                    self.set_connection(p, stream, seal);
+                    self.peers[p].reconnect_deadline = None;
+                    self.peers[p].connection.send(self.snapshot());
+                }
+                Incoming::Spectator(mut stream, name, mut seal) => {
+                    let spectating = Message::Spectating {
+                        settings: self.settings.clone(),
+                        names: self.in_side_order(&self.names),
+                    };
+                    // written before the connection starts pinging, the handshake reads it first
+                    if auth::write(&spectating, &mut stream, &mut seal).is_err() {
+                        continue;
+                    }
+                    info!("{} is watching", name);
+                    let spectator = Connection::start(stream, self.config, seal);
+                    spectator.send(self.snapshot());
+                    self.spectators.push((name, spectator));
+                }
+            }
+        }
+        for (_, spectator) in &mut self.spectators {
+            // spectators have nothing to say, this only notices if they left
+            while spectator.try_recv().is_some() {}
+        }
+        self.spectators.retain(|(name, spectator)| {
+            let watching = spectator.get_disconnected().is_none();
+            if !watching {
+                info!("{} stopped watching", name);
+            }
+            watching
+        });
+    }
+    /// Forwards the garbage the player on `side` sent to the spectators, and to the other clients if we're the host
+    fn relay_attack(&self, side: usize, tick: u64, target: u8, attack: Attack) {
+        let relay = Message::RelayAttack {
+            side: side as u8,
+            tick,
+            target,
+            lines: attack.lines,
+            hole: attack.hole,
+            delay: attack.delay,
+        };
+        for (_, spectator) in &self.spectators {
+            spectator.send(relay.clone());
+        }
+        self.send_to_others(side, relay);
+    }
+    /// Adds a line another player wrote to the log, and forwards it if we're the host
+    fn receive_chat(&mut self, side: usize, text: &str) {
+        if !self.is_spectator() && side == self.side as usize {
+            return;
+        }
+        let index = self.index_of(side);
+        if !self.chat.allow(side, Instant::now()) {
+            warn!(
+                "Dropped a chat message from {}, too many too fast",
+                self.names[index]
+            );
+            return;
+        }
+        let text = chat::clean(text);
+        if text.is_empty() {
+            return;
+        }
+        self.relay_chat(side, &text);
+        self.chat.add(&self.names[index], text);
+    }
+    /// Forwards a line of chat to the spectators, and to the other clients if we're the host
+    fn relay_chat(&self, side: usize, text: &str) {
+        let chat = Message::Chat {
+            side: side as u8,
+            text: text.to_string(),
+        };
+        for (_, spectator) in &self.spectators {
+            spectator.send(chat.clone());
+        }
+        self.send_to_others(side, chat);
+    }
+    /// Forwards the moves of the player on `side` to the spectators, and to the other clients if we're the host
+    fn relay(&self, side: usize, tick: u64, moves: &[Move]) {
+        let relay = Message::Relay {
+            side: side as u8,
+            tick,
+            moves: moves.to_vec(),
+        };
+        for (_, spectator) in &self.spectators {
+            spectator.send(relay.clone());
+        }
+        self.send_to_others(side, relay);
+    }
+    /// Shows the state a match server sent, players are in side order
+    fn apply_state(&mut self, tick: u64, players: Vec<Vec<u8>>) {
+        let players: Option<Vec<Player>> = players.iter().map(|p| Player::from_bytes(p)).collect();
+        let players = match players {
+            Some(players) if players.len() == self.players.len() => players,
+            _ => {
+                warn!("Got a malformed state from the server");
+                return;
+            }
+        };
+        self.players = self.to_player_order(players);
+        self.tick = tick;
+        self.authoritative = true;
+        self.awaiting_snapshot = false;
+    }
+    /// The whole match as it is right now, in side order
+    fn snapshot(&self) -> Message {
+        let (tick, players) = self.confirmed_state();
+        let pending = |frames: &HashMap<u64, Vec<Move>>| {
+            let mut pending: Vec<Frame> = frames
+                .iter()
+                .filter(|(t, _)| **t >= tick)
+                .map(|(t, moves)| (*t, moves.clone()))
+                .collect();
+            pending.sort_by_key(|(t, _)| *t);
+            pending
+        };
+        let players = self
+            .in_side_order(&players)
+            .iter()
+            .map(Player::to_bytes)
+            .collect();
+        let frames: Vec<Vec<Frame>> = self.frames.iter().map(pending).collect();
+        let attacks: Vec<Vec<TimedAttack>> = (0..self.players.len())
+            .map(|index| self.pending_attacks(index, tick))
+            .collect();
+        Message::Snapshot {
+            tick,
+            players,
+            frames: self.in_side_order(&frames),
+            attacks: self.in_side_order(&attacks),
+        }
+    }
+    /// The garbage a player sent that lands on `tick` or later, oldest first
+    fn pending_attacks(&self, index: usize, tick: u64) -> Vec<TimedAttack> {
+        let mut pending: Vec<TimedAttack> = self.attacks[index]
+            .iter()
+            .filter(|(t, _)| **t >= tick)
+            .map(|(t, (target, attack))| (*t, *target, *attack))
+            .collect();
+        pending.sort_by_key(|(t, _, _)| *t);
+        pending
+    }
+    /// Continues the match from the host's state
+    fn apply_snapshot(
+        &mut self,
+        tick: u64,
+        players: Vec<Vec<u8>>,
+        frames: Vec<Vec<Frame>>,
+        attacks: Vec<Vec<TimedAttack>>,
+    ) {
+        let n = self.players.len();
+        let players: Option<Vec<Player>> = players.iter().map(|p| Player::from_bytes(p)).collect();
+        let players = match players {
+            Some(players) if players.len() == n && frames.len() == n && attacks.len() == n => {
+                players
+            }
+            _ => {
+                self.lost_connection = Some("the host sent a broken snapshot".to_string());
+                return;
+            }
+        };
+        self.players = self.to_player_order(players);
+        self.tick = tick;
+        let mut frames = self.to_player_order(frames).into_iter();
+        let mut attacks = self.to_player_order(attacks).into_iter();
+        let recieved: HashMap<u64, Vec<Move>> = frames.next().unwrap().into_iter().collect();
+        let recieved_attacks: HashMap<u64, (u8, Attack)> = attacks
+            .next()
+            .unwrap()
+            .into_iter()
+            .map(|(t, target, attack)| (t, (target, attack)))
+            .collect();
+        for (index, (frames, attacks)) in frames.zip(attacks).enumerate() {
+            self.frames[index + 1] = frames.into_iter().collect();
+            self.attacks[index + 1] = attacks
+                .into_iter()
+                .map(|(t, target, attack)| (t, (target, attack)))
+                .collect();
+        }
+        if !self.is_spectator() {
+            // resend the garbage and moves that were lost with the connection, garbage first like when it was made
+            for (tick, target, attack) in self.pending_attacks(0, tick) {
+                if !recieved_attacks.contains_key(&tick) {
+                    self.send_to_peers(Message::Attack {
+                        tick,
+                        target,
+                        lines: attack.lines,
+                        hole: attack.hole,
+                        delay: attack.delay,
+                    });
+                }
+            }
+            let mut missing: Vec<Frame> = self.frames[0]
+                .iter()
+                .filter(|(t, _)| **t >= tick && !recieved.contains_key(t))
+                .map(|(t, moves)| (*t, moves.clone()))
+                .collect();
+            missing.sort_by_key(|(t, _)| *t);
+            for (tick, moves) in missing {
+                self.send_to_peers(Message::Input { tick, moves });
+            }
+        }
+        self.frames[0].extend(recieved);
+        self.attacks[0].extend(recieved_attacks);
+        if let Some(rollback) = &mut self.rollback {
+            rollback.reset(tick, self.players.clone());
+        }
+        // the checks made before are about states we no longer have
+        self.checksums.clear();
+        self.remote_checksums.clear();
+        self.awaiting_snapshot = false;
+        info!("Resumed at tick {}", tick);
+    }
+    /// Gets and returns the graphical boardstate of the players
+    pub fn get_boards(&self) -> Vec<[[u32; COLS]; ROWS]> {
+        self.players.iter().map(Player::get_board_visual).collect()
+    }
+    /// Gets and returns the next pieces of the players
+    pub fn get_next_pieces(&self) -> Vec<[[u32; 4]; 4]> {
+        self.players
+            .iter()
+            .map(|player| player.get_next_piece().get_display_shape())
+            .collect()
+    }
+    /// Gets and returns the saved pieces of the players
+    pub fn get_saved_pieces(&self) -> Vec<[[u32; 4]; 4]> {
+        self.players
+            .iter()
+            .map(|player| {
+                player
+                    .get_saved_piece()
+                    .as_ref()
+                    .map_or([[0; 4]; 4], |piece| piece.get_display_shape())
+            })
+            .collect()
+    }
+    /// Gets the incoming attacks from players and returns formatted data
+    pub fn get_attackbars(&self) -> Vec<u32> {
+        self.players
+            .iter()
+            .map(|player| {
+                player
+                    .get_incoming()
+                    .iter()
+                    .map(|attack| attack.lines as u32)
+                    .sum()
+            })
+            .collect()
     }
     /// Returns formatted data for the ai-script, without block-projection.
     pub fn get_player_data(
@@ -185,156 +1513,404 @@ impl Game {
         data
     }
 
-    pub fn get_scores(&self) -> [u32; PLAYER_AMOUNT] {
-        let mut scores = [0; PLAYER_AMOUNT];
-        for p in 0..self.players.len() {
-            scores[p] = self.players[p].get_score() as u32;
+    pub fn get_scores(&self) -> Vec<u32> {
+        self.players
+            .iter()
+            .map(|player| player.get_score() as u32)
+            .collect()
+    }
+
+    pub fn get_losts(&self) -> Vec<bool> {
+        self.players.iter().map(Player::get_lost).collect()
+    }
+
+    /// How many players each player has knocked out this match
+    pub fn get_knockouts(&self) -> Vec<u32> {
+        self.players
+            .iter()
+            .map(|player| player.get_knockouts() as u32)
+            .collect()
+    }
+
+    /// The clears and T-spins to call out on each board, like "T-SPIN DOUBLE"
+    pub fn get_callouts(&self) -> Vec<Option<Clear>> {
+        self.players.iter().map(Player::get_callout).collect()
+    }
+
+    /// The combo of each player, 0 while they have none
+    pub fn get_combos(&self) -> Vec<u8> {
+        self.players.iter().map(Player::get_combo).collect()
+    }
+
+    /// Which players get the back-to-back bonus on their next tetris or T-spin clear
+    pub fn get_back_to_backs(&self) -> Vec<bool> {
+        self.players.iter().map(Player::get_back_to_back).collect()
+    }
+
+    /// The last player standing once the match is over, None before that or if the last ones topped out together.
+    /// In team matches it's the first member of the winning team still playing.
+    pub fn get_winner(&self) -> Option<usize> {
+        if !self.is_over() {
+            return None;
         }
-        scores
+        self.players.iter().position(|player| !player.get_lost())
     }
 
-    pub fn get_losts(&self) -> [bool; PLAYER_AMOUNT] {
-        [self.players[0].get_lost(), self.players[1].get_lost()]
+    /// The team of every player, ours first. Every player is their own team unless it's a team match
+    pub fn get_teams(&self) -> Vec<u8> {
+        let teams = teams::assign(self.settings.teams, self.players.len());
+        self.to_player_order(teams)
     }
 
-    //Only set for 2 players
+    /// Whether the players play in teams rather than for themselves
+    pub fn is_team_match(&self) -> bool {
+        teams::is_team_match(&self.get_teams())
+    }
+
+    /// The last team standing once the match is over, None before that or if the last ones topped out together
+    pub fn get_winning_team(&self) -> Option<u8> {
+        teams::winner(&self.players, &self.get_teams())
+    }
+
+    /// Queues a move for the player the key belongs to.
+    /// P2 only has keys in local matches, otherwise it's a remote opponent and is controlled over the network
     pub fn key_down(&mut self, key: KeyCode) {
-        if self.ai_lib[0].is_none() {
-            let mut move_index = 0;
-            match key {
-                // P1 controlls
-                KeyCode::A => {
-                    self.players[0].move_current(-1, 0);
-                    move_index = 1;
-                }
-                KeyCode::E => {
-                    self.players[0].rotate_current(true);
-                    move_index = 3;
-                }
-                KeyCode::D => {
-                    self.players[0].move_current(1, 0);
-                    move_index = 2;
-                }
-                KeyCode::Q => {
-                    self.players[0].rotate_current(false);
-                    move_index = 4;
-                }
-                KeyCode::S => {
-                    self.players[0].move_current(0, -1);
-                    move_index = 5;
-                }
-                KeyCode::W => {
-                    self.players[0].drop_current();
-                    move_index = 6;
-                }
-                KeyCode::Space => {
-                    self.players[0].save_piece();
-                    move_index = 7;
-                }
-                _ => (),
-            }
-            //println!("sent {:?} to the thread",[move_index, self.players[0].next_piece.color as u8] );
-            // send move to opponent
-            self.moves_to_send
-                .send([move_index, self.players[0].next_piece.color as u8])
-                .expect("move send error");
+        let (index, mv) = match self.bindings.get(key) {
+            Some(binding) => binding,
+            None => return,
+        };
+        if self.is_spectator() || self.replaying.is_some() || (index == 1 && !self.is_local()) {
+            return;
         }
-        if self.ai_lib[1].is_none() {
-            match key {
-                // P2 controlls
-                KeyCode::J => self.players[1].move_current(-1, 0),
-                KeyCode::O => self.players[1].rotate_current(true),
-                KeyCode::L => self.players[1].move_current(1, 0),
-                KeyCode::U => self.players[1].rotate_current(false),
-                KeyCode::K => self.players[1].move_current(0, -1),
-                KeyCode::RShift => self.players[1].save_piece(),
-                KeyCode::I => self.players[1].drop_current(),
-                _ => (),
-            }
+        if self.ai_lib[index].is_none() {
+            self.local_moves[index].push(mv);
         }
     }
 
+    /// The names of the players, ours first
+    pub fn get_names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Why the connection to a peer was lost, if it has been and it didn't come back
+    pub fn get_disconnected(&self) -> Option<&str> {
+        self.lost_connection.as_deref()
+    }
+
+    /// Whether the match is paused while a connection is being restored
+    pub fn is_reconnecting(&self) -> bool {
+        self.lost_connection.is_none()
+            && ((self.awaiting_snapshot && !self.is_spectator())
+                || self.peers.iter().any(|peer| {
+                    peer.reconnect_deadline.is_some()
+                        || peer.connection.get_disconnected().is_some()
+                }))
+    }
+
+    /// The tick the peers' states last differed at, if they have
+    pub fn get_last_desync(&self) -> Option<u64> {
+        self.last_desync
+    }
+
+    /// The next tick to be simulated
+    pub fn get_tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Whether every player is on this machine
+    pub fn is_local(&self) -> bool {
+        matches!(self.role, Role::Local)
+    }
+
+    /// Whether we're only watching the match
+    pub fn is_spectator(&self) -> bool {
+        matches!(self.role, Role::Spectator)
+    }
+
+    /// Round-trip time to the slowest peer
+    pub fn get_ping(&self) -> Option<Duration> {
+        self.peers
+            .iter()
+            .filter_map(|peer| peer.connection.get_rtt())
+            .max()
+    }
+
+    /// Everything sent and received over our connections and rollback's UDP socket
+    fn total_traffic(&self) -> Traffic {
+        let connections = self
+            .peers
+            .iter()
+            .map(|peer| &peer.connection)
+            .chain(self.spectators.iter().map(|(_, spectator)| spectator));
+        let udp = self
+            .rollback
+            .as_ref()
+            .map_or(Traffic::default(), |rollback| rollback.traffic);
+        connections.fold(udp, |total, connection| total + connection.get_traffic())
+    }
+
+    /// What the network overlay shows
+    pub fn get_net_stats(&self) -> NetStats {
+        let queued_inputs = self.frames[1..]
+            .iter()
+            .map(|frames| frames.keys().filter(|tick| **tick >= self.tick).count())
+            .sum();
+        NetStats {
+            ping: self.get_ping(),
+            jitter: self
+                .peers
+                .iter()
+                .filter_map(|peer| peer.connection.get_jitter())
+                .max(),
+            per_second: self.traffic.get_rate(),
+            input_delay: self.input_delay(),
+            queued_inputs,
+            last_check: self.last_check,
+        }
+    }
+
+    /// Matches each player has won in the current series
+    pub fn get_wins(&self) -> &[u32] {
+        &self.wins
+    }
+
+    /// How many matches the series is played over
+    pub fn get_best_of(&self) -> u32 {
+        self.settings.best_of as u32
+    }
+
+    /// The player who has won more than half of the series, if anyone has
+    pub fn get_series_winner(&self) -> Option<usize> {
+        let needed = self.get_best_of() / 2 + 1;
+        self.wins.iter().position(|wins| *wins >= needed)
+    }
+
+    /// Who has asked for a rematch since the match ended, we're first
+    pub fn get_rematch(&self) -> &[bool] {
+        &self.rematch
+    }
+
+    /// Who the garbage of the players on this machine goes to
+    pub fn get_targeting(&self) -> Targeting {
+        self.targeting
+    }
+
+    pub fn set_targeting(&mut self, targeting: Targeting) {
+        self.targeting = targeting;
+    }
+
+    /// Sends a line of chat to the other players, and through the host to the spectators
+    pub fn send_chat(&mut self, text: &str) -> Result<(), String> {
+        if self.is_local() || self.is_spectator() {
+            return Err("Only the players of a network match can chat".to_string());
+        }
+        let text = chat::clean(text);
+        if text.is_empty() {
+            return Ok(());
+        }
+        if !self.chat.allow(self.side as usize, Instant::now()) {
+            return Err(format!(
+                "Slow down, at most {} messages every {} seconds",
+                chat::RATE_LIMIT,
+                chat::RATE_WINDOW.as_secs()
+            ));
+        }
+        self.send_to_peers(Message::Chat {
+            side: self.side as u8,
+            text: text.clone(),
+        });
+        self.relay_chat(self.side as usize, &text);
+        self.chat.add(&self.names[0], text);
+        Ok(())
+    }
+
+    /// The chat so far, oldest line first
+    pub fn get_chat(&self) -> impl Iterator<Item = &ChatLine> {
+        self.chat.lines().iter()
+    }
+
+    /// Plays again. Everyone on this machine starts over right away, in a network match it's a request
+    /// and the next match starts once every player has asked.
     pub fn restart(&mut self, init_level: usize) {
-        self.players = [Player::new(init_level), Player::new(init_level)];
+        let rematch = Message::Rematch {
+            side: self.side as u8,
+        };
+        if self.authoritative {
+            // the server restarts the match when every player has asked for it
+            self.send_to_peers(rematch);
+            self.rematch[0] = true;
+            return;
+        }
+        if self.replaying.is_some() {
+            // plays the replay from the start again
+            self.save_recording();
+            self.match_start = self.tick;
+            self.players = Game::new_players(&self.settings, 0, self.players.len());
+            return;
+        }
+        if self.is_local() {
+            // nobody to agree on a seed with, so every match gets new pieces
+            let mut settings = self.settings.clone();
+            settings.seed = rand::random();
+            settings.start_level = init_level as u8;
+            self.start_match(self.tick, settings);
+            return;
+        }
+        if self.is_spectator() || !self.match_over() || self.rematch[0] {
+            return;
+        }
+        self.rematch[0] = true;
+        self.send_to_peers(rematch);
+        self.start_rematch();
+    }
+
+    /// Lets the host start the next match once every player wants one, the clients follow when they get `NewMatch`
+    fn start_rematch(&mut self) {
+        if !matches!(self.role, Role::Host(_))
+            || self.rematch.contains(&false)
+            || !self.match_over()
+        {
+            return;
+        }
+        let mut settings = self.settings.clone();
+        settings.seed = rand::random();
+        let tick = self.tick + NEW_MATCH_GAP;
+        let new_match = Message::NewMatch {
+            tick,
+            settings: settings.clone(),
+            wins: self.wins.iter().map(|wins| *wins as u8).collect(),
+        };
+        self.send_to_peers(new_match.clone());
+        for (_, spectator) in &self.spectators {
+            spectator.send(new_match.clone());
+        }
+        self.start_match(tick, settings);
     }
 
+    /// Starts a new match on `tick` with new players, the moves of the old match are forgotten
+    fn start_match(&mut self, tick: u64, settings: Settings) {
+        self.save_recording();
+        info!("New match from tick {}", tick);
+        self.settings = settings;
+        self.players = Game::new_players(&self.settings, self.side, self.players.len());
+        self.tick = tick;
+        self.match_start = tick;
+        self.sent_until = tick + self.input_delay();
+        for frames in &mut self.frames {
+            frames.clear();
+        }
+        for attacks in &mut self.attacks {
+            attacks.clear();
+        }
+        // keys pressed while the match was over don't carry over
+        for moves in &mut self.local_moves {
+            moves.clear();
+        }
+        if let Some(rollback) = &mut self.rollback {
+            rollback.reset(tick, self.players.clone());
+        }
+        self.checksums.clear();
+        self.remote_checksums.clear();
+        self.sent_game_over = false;
+        self.rematch = vec![false; self.players.len()];
+        self.start_recording();
+    }
+
+    /// Whether at most one team is left, as far as we've simulated
+    pub fn is_over(&self) -> bool {
+        teams::decided(&self.players, &self.get_teams())
+    }
+
+    /// Whether at most one team is left for sure, in rollback matches a predicted loss can still be taken back
+    fn match_over(&self) -> bool {
+        let (_, players) = self.confirmed_state();
+        teams::decided(&players, &self.get_teams())
+    }
+
+    /// Adds the winners of a match to the series score once it's over, a won series starts over with the next match.
+    /// Every member of a winning team is credited, whether they were still playing or not.
+    fn count_result(&mut self) {
+        if !self.match_over() {
+            if self.result_counted && self.get_series_winner().is_some() {
+                self.wins = vec![0; self.players.len()];
+            }
+            self.result_counted = false;
+            return;
+        }
+        if self.result_counted {
+            return;
+        }
+        self.result_counted = true;
+        // the last ones topping out on the same tick is a draw
+        let (_, players) = self.confirmed_state();
+        let teams = self.get_teams();
+        if let Some(winner) = teams::winner(&players, &teams) {
+            for (wins, team) in self.wins.iter_mut().zip(teams) {
+                if team == winner {
+                    *wins += 1;
+                }
+            }
+        }
+    }
+
+    /// Lets the AI script of a player pick its next move
     fn call_ai_script(&mut self, player_index: usize) -> u32 {
         let mut output = 0;
 
         unsafe {
             if let Some(lib) = &self.ai_lib[player_index] {
                 let func: Symbol<AIFunc> = lib.get(b"ai").expect("Couldn't find ai function");
-                let (board, current_piece, saved_piece) = self.get_player_data(1);
+                let (board, current_piece, saved_piece) = self.get_player_data(player_index);
                 output = func(&board, &current_piece, &saved_piece);
             }
         }
         output
     }
+}
 
-    fn parse_ai_output(&mut self, player_index: usize, output: u32) {
-        match output {
-            1 => self.players[player_index].move_current(-1, 0),
-            2 => self.players[player_index].move_current(1, 0),
-            3 => self.players[player_index].rotate_current(true),
-            4 => self.players[player_index].rotate_current(false),
-            5 => self.players[player_index].move_current(0, -1),
-            6 => self.players[player_index].drop_current(),
-            7 => self.players[player_index].save_piece(),
-            _ => (),
-        }
+impl Drop for Game {
+    fn drop(&mut self) {
+        // a match that was quit before it was over is saved too
+        self.save_recording();
     }
-    /// handles recieving and sending moves to the online opponent
-    fn handle_connection(
-        mut stream: TcpStream,
-        sender: mpsc::Sender<Packet>,
-        reciever: mpsc::Receiver<Packet>,
-    ) {
-        println!("handeling connection");
-        let mut buffer: Packet = [0; 2];
-        let mut open = true;
-        while open {
-            if let Ok(response) = reciever.try_recv() {
-                //println!("recieved move from main thread");
-                stream.write(&response).unwrap();
-                stream.flush().unwrap();
-            //println!("wrote move from main thread");
-            } else {
-                stream.write(&[0, 0]).unwrap();
-                stream.flush().unwrap();
-            }
+}
 
-            match stream.read(&mut buffer) {
-                Ok(0) => {
-                    open = false;
-                    println!("Connection closed!");
-                }
-                Ok(len) => {
-                    if buffer != [0, 0] {
-                        //println!("Recieved: {:?}", &buffer);
+/// Sends the attacks of this tick, done after all players have updated so the order doesn't matter.
+/// `players` are in side order, only opponents are attacked and `roll` decides between equally good targets.
+pub fn route_attacks(players: &mut [Player], settings: &Settings, targeting: Targeting, roll: u64) {
+    let teams = teams::assign(settings.teams, players.len());
+    for p in 0..players.len() {
+        if let Some(attack) = players[p].take_outgoing() {
+            let attacker = players[p].get_attacker().map(|side| side as usize);
+            let roll = roll.rotate_left(8 * p as u32);
+            if let Some(target) = targeting.pick(p, players, &teams, attacker, roll) {
+                let receivers =
+                    teams::receivers(target, players, &teams, settings.split_garbage, |side| side);
+                let shares = teams::share(attack, receivers.len());
+                for (receiver, share) in receivers.into_iter().zip(shares) {
+                    if let Some(share) = share {
+                        players[receiver].add_incoming_from(share, p as u8);
                     }
                 }
-                Err(_) => println!("Error reading stream"),
-            }
-            if buffer != [0, 0] {
-                sender.send(buffer).expect("Send error");
             }
         }
     }
-    /// creates a piece based on the color
-    fn piece_from_u8(input: u8) -> Piece {
-        let color = match input {
-            1 => Color::Color1,
-            2 => Color::Color2,
-            3 => Color::Color3,
-            4 => Color::Color4,
-            5 => Color::Color5,
-            6 => Color::Color6,
-            _ => Color::Color7,
-        };
-        Piece::new(
-            SHAPES[(input - 1) as usize],
-            color,
-            [COLS as i32 / 2, ROWS as i32 - 1],
-        )
+}
+
+/// Loads an AI script, a library with an `ai` function
+fn load_ai(path: &str) -> Result<Library, String> {
+    let extension = if cfg!(windows) { ".dll" } else { ".so" };
+    if !path.ends_with(extension) {
+        return Err(format!(
+            "the AI script {} has to be a {} file",
+            path, extension
+        ));
+    }
+    let lib = Library::new(path).map_err(|err| format!("could not load {}: {}", path, err))?;
+    unsafe {
+        if let Err(err) = lib.get::<AIFunc>(b"ai") {
+            return Err(format!("{} has no ai function: {}", path, err));
+        }
     }
+    Ok(lib)
 }
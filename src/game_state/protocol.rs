@@ -0,0 +1,978 @@
+use super::MAX_PLAYERS;
+use crate::game_data::{Attack, AttackTable, Move, RandomizerKind, COLS, ROWS, TIME_LEVELS};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// First bytes of every hello, so we don't start talking to something that isn't the game
+pub const MAGIC: [u8; 4] = *b"TTRS";
+/// Bumped whenever the messages below change
+pub const PROTOCOL_VERSION: u16 = 21;
+/// Upper limit on the length of one message, anything longer is treated as garbage
+const MAX_MESSAGE_LEN: u32 = 64 * 1024;
+/// Fastest tick rate a host can ask for
+const MAX_TICK_RATE: u32 = 1000;
+
+/// The settings the host decides for a match
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub start_level: u8,
+    pub cols: u8,
+    pub rows: u8,
+    pub seed: u64,
+    pub tick_rate: u32,
+    pub rollback: bool, // moves are applied right away and the opponent's are predicted, see `game_state/rollback.rs`
+    pub best_of: u8,    // matches in a series, the first to win more than half of them wins it
+    pub teams: u8, // the sides are dealt into this many teams, everyone plays for themselves with fewer than two
+    pub split_garbage: bool, // garbage sent to a player is shared between the members of their team still playing
+    pub randomizer: RandomizerKind, // how every player's pieces are dealt
+    pub attack_table: AttackTable, // the bonus lines for combos and back-to-backs
+}
+
+impl Settings {
+    /// Whether we can play with the settings a host sent, checked before any player is built from them
+    pub fn check(&self) -> Result<(), ProtocolError> {
+        if self.cols as usize != COLS || self.rows as usize != ROWS {
+            return Err(ProtocolError::Unsupported(format!(
+                "the host plays on a {}x{} board",
+                self.cols, self.rows
+            )));
+        }
+        let valid = (self.start_level as usize) < TIME_LEVELS.len()
+            && (1..=MAX_TICK_RATE).contains(&self.tick_rate)
+            && self.best_of > 0
+            && self.teams as usize <= MAX_PLAYERS
+            && self.attack_table.is_valid();
+        if !valid {
+            return Err(ProtocolError::Malformed);
+        }
+        Ok(())
+    }
+}
+
+/// How the match of a lobby room is played
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RoomMode {
+    /// The player who opened the room hosts the match and plays in it
+    Hosted,
+    /// A match server runs the match, everyone in the room connects to it
+    Server,
+}
+
+/// What a lobby room is opened with. The match is found at the ip the room was opened from and `port`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomSettings {
+    pub name: String,
+    pub mode: RoomMode,
+    pub port: u16,
+    pub start_level: u8,
+    pub players: u8,
+}
+
+/// A room as the lobby lists it, `joined` counts the host of a hosted room too
+#[derive(Clone, Debug, PartialEq)]
+pub struct Room {
+    pub id: u32,
+    pub owner: String,
+    pub settings: RoomSettings,
+    pub joined: u8,
+}
+
+/// The moves a player made during one tick
+pub type Frame = (u64, Vec<Move>);
+/// Garbage a player sent, with the tick it lands on and the side of the player it lands on
+pub type TimedAttack = (u64, u8, Attack);
+
+/// Everything sent over the connection.
+/// On the wire a message is [length: u32][type: u8][payload], where length counts the type and payload.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// First message from the client
+    Hello {
+        version: u16,
+        name: String,
+    },
+    /// The host's answer to an accepted hello once every player has joined, the session is used to resume
+    /// the match after a disconnect. `names` are in side order and `side` is the receiver's place in them.
+    Welcome {
+        settings: Settings,
+        side: u8,
+        names: Vec<String>,
+        session: u64,
+    },
+    /// The host's answer to an accepted hello while it waits for more players, sent again whenever someone joins
+    Waiting {
+        joined: u8,
+        players: u8,
+    },
+    /// A match server's answer to a hello, the server simulates the match and only wants our moves.
+    /// `names` are in side order and `side` is the receiver's place in them.
+    Seated {
+        settings: Settings,
+        side: u8,
+        names: Vec<String>,
+        session: u64,
+    },
+    /// The state of every player after a tick on a match server, encoded with `Player::to_bytes` in side order
+    State {
+        tick: u64,
+        players: Vec<Vec<u8>>,
+    },
+    /// First message from a client that lost its connection and wants back into the match
+    Resume {
+        version: u16,
+        session: u64,
+    },
+    /// First message from someone who wants to watch the match
+    Spectate {
+        version: u16,
+        name: String,
+    },
+    /// The host's answer to an accepted spectator, followed by a snapshot
+    Spectating {
+        settings: Settings,
+        names: Vec<String>,
+    },
+    /// The moves a player made during a tick, forwarded by the host to spectators and the other clients
+    Relay {
+        side: u8,
+        tick: u64,
+        moves: Vec<Move>,
+    },
+    /// Garbage a player sent, forwarded by the host to spectators and the other clients
+    RelayAttack {
+        side: u8,
+        tick: u64,
+        target: u8,
+        lines: u8,
+        hole: u8,
+        delay: u8,
+    },
+    /// The full state of a match at a tick, sent by the host when a client resumes or a spectator joins.
+    /// `players` are encoded with `Player::to_bytes`, `frames` holds the moves each player has made for
+    /// that tick and later and `attacks` the garbage that hasn't landed yet, all in side order (host first).
+    Snapshot {
+        tick: u64,
+        players: Vec<Vec<u8>>,
+        frames: Vec<Vec<Frame>>,
+        attacks: Vec<Vec<TimedAttack>>,
+    },
+    /// The host's answer to a hello it can't accept, the connection is closed after it.
+    /// Also the lobby's answer to a request it can't do, the connection to a lobby stays open.
+    Reject {
+        reason: String,
+    },
+    /// The moves the sender made during a tick
+    Input {
+        tick: u64,
+        moves: Vec<Move>,
+    },
+    /// Garbage the sender's player made, landing on the player on the `target` side at `tick`.
+    /// Sent before the sender's moves for that tick, so it has always arrived when the tick is simulated.
+    Attack {
+        tick: u64,
+        target: u8,
+        lines: u8,
+        hole: u8,
+        delay: u8,
+    },
+    /// The sender topped out at a tick
+    GameOver {
+        tick: u64,
+    },
+    /// The player on `side` wants to play again, forwarded by the host to the other clients
+    Rematch {
+        side: u8,
+    },
+    /// The host's answer once every player wants a rematch: the next match is played with `settings` from `tick` on.
+    /// `wins` is the series score before it, in side order.
+    NewMatch {
+        tick: u64,
+        settings: Settings,
+        wins: Vec<u8>,
+    },
+    /// A line of chat written by the player on `side`, forwarded by the host to everyone else
+    Chat {
+        side: u8,
+        text: String,
+    },
+    /// `Player::checksum` of every player after a tick, in side order
+    Checksum {
+        tick: u64,
+        hashes: Vec<u64>,
+    },
+    /// The client found a desync and wants a snapshot of the host's state
+    Resync,
+    Ping {
+        id: u64,
+    },
+    Pong {
+        id: u64,
+    },
+    /// Sent over UDP in rollback matches, the sender's moves and attacks the receiver hasn't confirmed yet.
+    /// `ack` is the first tick the sender is missing the receiver's moves for.
+    Inputs {
+        ack: u64,
+        frames: Vec<Frame>,
+        attacks: Vec<TimedAttack>,
+    },
+    /// The port the sender receives `Inputs` on, its ip is the same as the connection's
+    UdpPort {
+        port: u16,
+    },
+    /// The host's answer to the first message of a connection when the room has a password, see `game_state/auth.rs`
+    Challenge {
+        nonce: [u8; 16],
+    },
+    /// Proves the sender knows the room password, the client's answer to a challenge and the host's answer to that
+    Proof {
+        nonce: [u8; 16],
+        mac: [u8; 32],
+    },
+    /// First message to a lobby, answered with the rooms
+    LobbyHello {
+        version: u16,
+        name: String,
+    },
+    /// Asks the lobby for the open rooms
+    ListRooms,
+    /// The rooms that aren't full yet
+    Rooms {
+        rooms: Vec<Room>,
+    },
+    /// Opens a room, it's closed when the connection of whoever opened it closes, and a hosted room also once it's full
+    CreateRoom {
+        settings: RoomSettings,
+    },
+    /// The lobby's answer to `CreateRoom`
+    RoomCreated {
+        id: u32,
+    },
+    /// Takes a place in a room
+    JoinRoom {
+        id: u32,
+    },
+    /// The lobby's answer to `JoinRoom`, where to connect for the match
+    HandOff {
+        adress: String,
+    },
+    /// Tells the lobby how many players are in a room we opened, answered with the rooms
+    UpdateRoom {
+        id: u32,
+        joined: u8,
+    },
+    /// Broadcast over UDP by a host so it can be found on the local network, `port` is where it accepts players
+    Beacon {
+        version: u16,
+        name: String,
+        port: u16,
+        free_slots: u8,
+    },
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    BadMagic,
+    TooLong(u32),
+    UnknownMessage(u8),
+    Malformed,
+    /// The peer closed the connection with a reason
+    Rejected(String),
+    /// The peer wants to play with settings we can't play with
+    Unsupported(String),
+    /// The peer sent a valid message we didn't expect at this point
+    Unexpected(Message),
+    /// The room password didn't match, or a message didn't come from whoever knows it
+    Unauthorized(&'static str),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Io(err) => write!(f, "connection error: {}", err),
+            ProtocolError::BadMagic => write!(f, "the other side is not running this game"),
+            ProtocolError::TooLong(len) => write!(f, "message of {} bytes is too long", len),
+            ProtocolError::UnknownMessage(kind) => write!(f, "unknown message type {}", kind),
+            ProtocolError::Malformed => write!(f, "malformed message"),
+            ProtocolError::Rejected(reason) => write!(f, "rejected by host: {}", reason),
+            ProtocolError::Unsupported(reason) => write!(f, "unsupported settings: {}", reason),
+            ProtocolError::Unexpected(message) => write!(f, "unexpected message {:?}", message),
+            ProtocolError::Unauthorized(reason) => write!(f, "not authorized: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> ProtocolError {
+        ProtocolError::Io(err)
+    }
+}
+
+impl Message {
+    fn kind(&self) -> u8 {
+        match self {
+            Message::Hello { .. } => 1,
+            Message::Welcome { .. } => 2,
+            Message::Reject { .. } => 3,
+            Message::Input { .. } => 4,
+            Message::Attack { .. } => 6,
+            Message::GameOver { .. } => 7,
+            Message::Rematch { .. } => 8,
+            Message::Ping { .. } => 9,
+            Message::Pong { .. } => 10,
+            Message::Resume { .. } => 11,
+            Message::Snapshot { .. } => 12,
+            Message::Spectate { .. } => 13,
+            Message::Spectating { .. } => 14,
+            Message::Relay { .. } => 15,
+            Message::Beacon { .. } => 16,
+            Message::Seated { .. } => 17,
+            Message::State { .. } => 18,
+            Message::Checksum { .. } => 19,
+            Message::Resync => 20,
+            Message::Inputs { .. } => 21,
+            Message::UdpPort { .. } => 22,
+            Message::NewMatch { .. } => 23,
+            Message::RelayAttack { .. } => 24,
+            Message::Chat { .. } => 25,
+            Message::Waiting { .. } => 26,
+            Message::Challenge { .. } => 27,
+            Message::Proof { .. } => 28,
+            Message::LobbyHello { .. } => 29,
+            Message::ListRooms => 30,
+            Message::Rooms { .. } => 31,
+            Message::CreateRoom { .. } => 32,
+            Message::RoomCreated { .. } => 33,
+            Message::JoinRoom { .. } => 34,
+            Message::HandOff { .. } => 35,
+            Message::UpdateRoom { .. } => 36,
+        }
+    }
+
+    /// Encodes the message with its length prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0, 0, 0, 0, self.kind()];
+        match self {
+            Message::Hello { version, name } => {
+                buf.extend_from_slice(&MAGIC);
+                buf.extend_from_slice(&version.to_be_bytes());
+                put_str(&mut buf, name);
+            }
+            Message::Welcome {
+                settings,
+                side,
+                names,
+                session,
+            }
+            | Message::Seated {
+                settings,
+                side,
+                names,
+                session,
+            } => {
+                put_settings(&mut buf, settings);
+                buf.push(*side);
+                buf.push(names.len() as u8);
+                for name in names {
+                    put_str(&mut buf, name);
+                }
+                buf.extend_from_slice(&session.to_be_bytes());
+            }
+            Message::Waiting { joined, players } => buf.extend_from_slice(&[*joined, *players]),
+            Message::Challenge { nonce } => buf.extend_from_slice(nonce),
+            Message::Proof { nonce, mac } => {
+                buf.extend_from_slice(nonce);
+                buf.extend_from_slice(mac);
+            }
+            Message::LobbyHello { version, name } => {
+                buf.extend_from_slice(&MAGIC);
+                buf.extend_from_slice(&version.to_be_bytes());
+                put_str(&mut buf, name);
+            }
+            Message::ListRooms => (),
+            Message::Rooms { rooms } => {
+                buf.extend_from_slice(&(rooms.len() as u16).to_be_bytes());
+                for room in rooms {
+                    buf.extend_from_slice(&room.id.to_be_bytes());
+                    put_str(&mut buf, &room.owner);
+                    put_room_settings(&mut buf, &room.settings);
+                    buf.push(room.joined);
+                }
+            }
+            Message::CreateRoom { settings } => put_room_settings(&mut buf, settings),
+            Message::RoomCreated { id } | Message::JoinRoom { id } => {
+                buf.extend_from_slice(&id.to_be_bytes())
+            }
+            Message::HandOff { adress } => put_str(&mut buf, adress),
+            Message::UpdateRoom { id, joined } => {
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf.push(*joined);
+            }
+            Message::Spectate { version, name } => {
+                buf.extend_from_slice(&MAGIC);
+                buf.extend_from_slice(&version.to_be_bytes());
+                put_str(&mut buf, name);
+            }
+            Message::Spectating { settings, names } => {
+                put_settings(&mut buf, settings);
+                buf.push(names.len() as u8);
+                for name in names {
+                    put_str(&mut buf, name);
+                }
+            }
+            Message::State { tick, players } => {
+                buf.extend_from_slice(&tick.to_be_bytes());
+                put_players(&mut buf, players);
+            }
+            Message::Relay { side, tick, moves } => {
+                buf.push(*side);
+                put_frame(&mut buf, *tick, moves);
+            }
+            Message::Resume { version, session } => {
+                buf.extend_from_slice(&MAGIC);
+                buf.extend_from_slice(&version.to_be_bytes());
+                buf.extend_from_slice(&session.to_be_bytes());
+            }
+            Message::Snapshot {
+                tick,
+                players,
+                frames,
+                attacks,
+            } => {
+                buf.extend_from_slice(&tick.to_be_bytes());
+                put_players(&mut buf, players);
+                buf.push(frames.len() as u8);
+                for side in frames {
+                    buf.extend_from_slice(&(side.len() as u16).to_be_bytes());
+                    for (tick, moves) in side {
+                        put_frame(&mut buf, *tick, moves);
+                    }
+                }
+                buf.push(attacks.len() as u8);
+                for side in attacks {
+                    put_attacks(&mut buf, side);
+                }
+            }
+            Message::Reject { reason } => put_str(&mut buf, reason),
+            Message::Input { tick, moves } => put_frame(&mut buf, *tick, moves),
+            Message::Attack {
+                tick,
+                target,
+                lines,
+                hole,
+                delay,
+            } => {
+                buf.extend_from_slice(&tick.to_be_bytes());
+                buf.extend_from_slice(&[*target, *lines, *hole, *delay]);
+            }
+            Message::RelayAttack {
+                side,
+                tick,
+                target,
+                lines,
+                hole,
+                delay,
+            } => {
+                buf.push(*side);
+                buf.extend_from_slice(&tick.to_be_bytes());
+                buf.extend_from_slice(&[*target, *lines, *hole, *delay]);
+            }
+            Message::GameOver { tick } => buf.extend_from_slice(&tick.to_be_bytes()),
+            Message::Chat { side, text } => {
+                buf.push(*side);
+                put_str(&mut buf, text);
+            }
+            Message::Rematch { side } => buf.push(*side),
+            Message::Resync => (),
+            Message::NewMatch {
+                tick,
+                settings,
+                wins,
+            } => {
+                buf.extend_from_slice(&tick.to_be_bytes());
+                put_settings(&mut buf, settings);
+                buf.push(wins.len() as u8);
+                buf.extend_from_slice(wins);
+            }
+            Message::Inputs {
+                ack,
+                frames,
+                attacks,
+            } => {
+                buf.extend_from_slice(&ack.to_be_bytes());
+                buf.push(frames.len() as u8);
+                for (tick, moves) in frames {
+                    put_frame(&mut buf, *tick, moves);
+                }
+                put_attacks(&mut buf, attacks);
+            }
+            Message::UdpPort { port } => buf.extend_from_slice(&port.to_be_bytes()),
+            Message::Checksum { tick, hashes } => {
+                buf.extend_from_slice(&tick.to_be_bytes());
+                buf.push(hashes.len() as u8);
+                for hash in hashes {
+                    buf.extend_from_slice(&hash.to_be_bytes());
+                }
+            }
+            Message::Ping { id } | Message::Pong { id } => buf.extend_from_slice(&id.to_be_bytes()),
+            Message::Beacon {
+                version,
+                name,
+                port,
+                free_slots,
+            } => {
+                buf.extend_from_slice(&MAGIC);
+                buf.extend_from_slice(&version.to_be_bytes());
+                put_str(&mut buf, name);
+                buf.extend_from_slice(&port.to_be_bytes());
+                buf.push(*free_slots);
+            }
+        }
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    /// Decodes one message without its length prefix
+    pub fn decode(bytes: &[u8]) -> Result<Message, ProtocolError> {
+        let mut r = Reader { bytes };
+        let kind = r.u8()?;
+        let message = match kind {
+            1 => {
+                if r.take(4)? != MAGIC {
+                    return Err(ProtocolError::BadMagic);
+                }
+                Message::Hello {
+                    version: r.u16()?,
+                    name: r.string()?,
+                }
+            }
+            2 => {
+                let settings = r.settings()?;
+                let side = r.u8()?;
+                let mut names = Vec::new();
+                for _ in 0..r.u8()? {
+                    names.push(r.string()?);
+                }
+                Message::Welcome {
+                    settings,
+                    side,
+                    names,
+                    session: r.u64()?,
+                }
+            }
+            3 => Message::Reject {
+                reason: r.string()?,
+            },
+            4 => {
+                let (tick, moves) = r.frame()?;
+                Message::Input { tick, moves }
+            }
+            6 => {
+                let (tick, target, attack) = r.attack()?;
+                Message::Attack {
+                    tick,
+                    target,
+                    lines: attack.lines,
+                    hole: attack.hole,
+                    delay: attack.delay,
+                }
+            }
+            7 => Message::GameOver { tick: r.u64()? },
+            8 => Message::Rematch { side: r.u8()? },
+            9 => Message::Ping { id: r.u64()? },
+            10 => Message::Pong { id: r.u64()? },
+            11 => {
+                if r.take(4)? != MAGIC {
+                    return Err(ProtocolError::BadMagic);
+                }
+                Message::Resume {
+                    version: r.u16()?,
+                    session: r.u64()?,
+                }
+            }
+            12 => {
+                let tick = r.u64()?;
+                let players = r.players()?;
+                let mut frames = Vec::new();
+                for _ in 0..r.u8()? {
+                    let mut side = Vec::new();
+                    for _ in 0..r.u16()? {
+                        side.push(r.frame()?);
+                    }
+                    frames.push(side);
+                }
+                let mut attacks = Vec::new();
+                for _ in 0..r.u8()? {
+                    attacks.push(r.attacks()?);
+                }
+                Message::Snapshot {
+                    tick,
+                    players,
+                    frames,
+                    attacks,
+                }
+            }
+            13 => {
+                if r.take(4)? != MAGIC {
+                    return Err(ProtocolError::BadMagic);
+                }
+                Message::Spectate {
+                    version: r.u16()?,
+                    name: r.string()?,
+                }
+            }
+            14 => {
+                let settings = r.settings()?;
+                let mut names = Vec::new();
+                for _ in 0..r.u8()? {
+                    names.push(r.string()?);
+                }
+                Message::Spectating { settings, names }
+            }
+            15 => {
+                let side = r.u8()?;
+                let (tick, moves) = r.frame()?;
+                Message::Relay { side, tick, moves }
+            }
+            16 => {
+                if r.take(4)? != MAGIC {
+                    return Err(ProtocolError::BadMagic);
+                }
+                Message::Beacon {
+                    version: r.u16()?,
+                    name: r.string()?,
+                    port: r.u16()?,
+                    free_slots: r.u8()?,
+                }
+            }
+            17 => {
+                let settings = r.settings()?;
+                let side = r.u8()?;
+                let mut names = Vec::new();
+                for _ in 0..r.u8()? {
+                    names.push(r.string()?);
+                }
+                Message::Seated {
+                    settings,
+                    side,
+                    names,
+                    session: r.u64()?,
+                }
+            }
+            18 => Message::State {
+                tick: r.u64()?,
+                players: r.players()?,
+            },
+            19 => {
+                let tick = r.u64()?;
+                let mut hashes = Vec::new();
+                for _ in 0..r.u8()? {
+                    hashes.push(r.u64()?);
+                }
+                Message::Checksum { tick, hashes }
+            }
+            20 => Message::Resync,
+            21 => {
+                let ack = r.u64()?;
+                let mut frames = Vec::new();
+                for _ in 0..r.u8()? {
+                    frames.push(r.frame()?);
+                }
+                Message::Inputs {
+                    ack,
+                    frames,
+                    attacks: r.attacks()?,
+                }
+            }
+            22 => Message::UdpPort { port: r.u16()? },
+            23 => {
+                let tick = r.u64()?;
+                let settings = r.settings()?;
+                let count = r.u8()? as usize;
+                Message::NewMatch {
+                    tick,
+                    settings,
+                    wins: r.take(count)?.to_vec(),
+                }
+            }
+            24 => {
+                let side = r.u8()?;
+                let (tick, target, attack) = r.attack()?;
+                Message::RelayAttack {
+                    side,
+                    tick,
+                    target,
+                    lines: attack.lines,
+                    hole: attack.hole,
+                    delay: attack.delay,
+                }
+            }
+            25 => Message::Chat {
+                side: r.u8()?,
+                text: r.string()?,
+            },
+            26 => Message::Waiting {
+                joined: r.u8()?,
+                players: r.u8()?,
+            },
+            27 => Message::Challenge { nonce: r.array()? },
+            28 => Message::Proof {
+                nonce: r.array()?,
+                mac: r.array()?,
+            },
+            29 => {
+                if r.take(4)? != MAGIC {
+                    return Err(ProtocolError::BadMagic);
+                }
+                Message::LobbyHello {
+                    version: r.u16()?,
+                    name: r.string()?,
+                }
+            }
+            30 => Message::ListRooms,
+            31 => {
+                let mut rooms = Vec::new();
+                for _ in 0..r.u16()? {
+                    rooms.push(Room {
+                        id: r.u32()?,
+                        owner: r.string()?,
+                        settings: r.room_settings()?,
+                        joined: r.u8()?,
+                    });
+                }
+                Message::Rooms { rooms }
+            }
+            32 => Message::CreateRoom {
+                settings: r.room_settings()?,
+            },
+            33 => Message::RoomCreated { id: r.u32()? },
+            34 => Message::JoinRoom { id: r.u32()? },
+            35 => Message::HandOff {
+                adress: r.string()?,
+            },
+            36 => Message::UpdateRoom {
+                id: r.u32()?,
+                joined: r.u8()?,
+            },
+            _ => return Err(ProtocolError::UnknownMessage(kind)),
+        };
+        if !r.bytes.is_empty() {
+            return Err(ProtocolError::Malformed);
+        }
+        Ok(message)
+    }
+
+    /// Writes the message and flushes the stream
+    pub fn write_to(&self, stream: &mut impl Write) -> Result<(), ProtocolError> {
+        stream.write_all(&self.encode())?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Blocks until a whole message has been read
+    pub fn read_from(stream: &mut impl Read) -> Result<Message, ProtocolError> {
+        let frame = Message::read_frame(stream)?;
+        Message::decode(&frame[4..])
+    }
+
+    /// Blocks until a whole message has been read, and returns it undecoded with its length prefix
+    pub fn read_frame(stream: &mut impl Read) -> Result<Vec<u8>, ProtocolError> {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len);
+        if len > MAX_MESSAGE_LEN {
+            return Err(ProtocolError::TooLong(len));
+        }
+        let mut frame = vec![0; 4 + len as usize];
+        frame[..4].copy_from_slice(&len.to_be_bytes());
+        stream.read_exact(&mut frame[4..])?;
+        Ok(frame)
+    }
+}
+
+/// Strings are sent as [length: u16][utf-8 bytes], longer ones are cut at the last whole character
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    let mut len = s.len().min(u16::MAX as usize);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    let bytes = &s.as_bytes()[..len];
+    buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_settings(buf: &mut Vec<u8>, settings: &Settings) {
+    buf.push(settings.start_level);
+    buf.push(settings.cols);
+    buf.push(settings.rows);
+    buf.extend_from_slice(&settings.seed.to_be_bytes());
+    buf.extend_from_slice(&settings.tick_rate.to_be_bytes());
+    buf.push(settings.rollback as u8);
+    buf.push(settings.best_of);
+    buf.push(settings.teams);
+    buf.push(settings.split_garbage as u8);
+    buf.push(settings.randomizer as u8);
+    buf.extend_from_slice(&settings.attack_table.combo);
+    buf.push(settings.attack_table.back_to_back);
+}
+
+/// Room settings are sent as [name: str][mode: u8][port: u16][start level: u8][players: u8]
+fn put_room_settings(buf: &mut Vec<u8>, settings: &RoomSettings) {
+    put_str(buf, &settings.name);
+    buf.push(settings.mode as u8);
+    buf.extend_from_slice(&settings.port.to_be_bytes());
+    buf.push(settings.start_level);
+    buf.push(settings.players);
+}
+
+/// Encoded players are sent as [count: u8] followed by [length: u16][bytes] for each
+fn put_players(buf: &mut Vec<u8>, players: &[Vec<u8>]) {
+    buf.push(players.len() as u8);
+    for player in players {
+        buf.extend_from_slice(&(player.len() as u16).to_be_bytes());
+        buf.extend_from_slice(player);
+    }
+}
+
+/// Frames are sent as [tick: u64][move count: u8][moves: u8...]
+fn put_frame(buf: &mut Vec<u8>, tick: u64, moves: &[Move]) {
+    buf.extend_from_slice(&tick.to_be_bytes());
+    buf.push(moves.len() as u8);
+    buf.extend(moves.iter().map(|mv| *mv as u8));
+}
+
+/// Attacks are sent as [tick: u64][target: u8][lines: u8][hole: u8][delay: u8], a list of them with a u16 count first
+fn put_attacks(buf: &mut Vec<u8>, attacks: &[TimedAttack]) {
+    buf.extend_from_slice(&(attacks.len() as u16).to_be_bytes());
+    for (tick, target, attack) in attacks {
+        buf.extend_from_slice(&tick.to_be_bytes());
+        buf.extend_from_slice(&[*target, attack.lines, attack.hole, attack.delay]);
+    }
+}
+
+/// Reads values from the front of a message payload
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.bytes.len() < n {
+            return Err(ProtocolError::Malformed);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        let mut buf = [0; N];
+        buf.copy_from_slice(self.take(N)?);
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, ProtocolError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn settings(&mut self) -> Result<Settings, ProtocolError> {
+        let settings = Settings {
+            start_level: self.u8()?,
+            cols: self.u8()?,
+            rows: self.u8()?,
+            seed: self.u64()?,
+            tick_rate: self.u32()?,
+            rollback: self.u8()? != 0,
+            best_of: self.u8()?,
+            teams: self.u8()?,
+            split_garbage: self.u8()? != 0,
+            randomizer: RandomizerKind::from_u8(self.u8()?).ok_or(ProtocolError::Malformed)?,
+            attack_table: AttackTable {
+                combo: self.array()?,
+                back_to_back: self.u8()?,
+            },
+        };
+        settings.check()?;
+        Ok(settings)
+    }
+
+    fn players(&mut self) -> Result<Vec<Vec<u8>>, ProtocolError> {
+        let mut players = Vec::new();
+        for _ in 0..self.u8()? {
+            let len = self.u16()? as usize;
+            players.push(self.take(len)?.to_vec());
+        }
+        Ok(players)
+    }
+
+    fn frame(&mut self) -> Result<Frame, ProtocolError> {
+        let tick = self.u64()?;
+        let count = self.u8()? as usize;
+        let mut moves = Vec::with_capacity(count);
+        for byte in self.take(count)? {
+            moves.push(Move::from_u8(*byte).ok_or(ProtocolError::Malformed)?);
+        }
+        Ok((tick, moves))
+    }
+
+    fn attack(&mut self) -> Result<TimedAttack, ProtocolError> {
+        let tick = self.u64()?;
+        let target = self.u8()?;
+        let attack = Attack {
+            lines: self.u8()?,
+            hole: self.u8()?,
+            delay: self.u8()?,
+        };
+        // garbage with no delay would have risen before it arrived
+        if attack.hole as usize >= COLS || attack.delay == 0 {
+            return Err(ProtocolError::Malformed);
+        }
+        Ok((tick, target, attack))
+    }
+
+    fn attacks(&mut self) -> Result<Vec<TimedAttack>, ProtocolError> {
+        let mut attacks = Vec::new();
+        for _ in 0..self.u16()? {
+            attacks.push(self.attack()?);
+        }
+        Ok(attacks)
+    }
+
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| ProtocolError::Malformed)
+    }
+
+    fn room_settings(&mut self) -> Result<RoomSettings, ProtocolError> {
+        Ok(RoomSettings {
+            name: self.string()?,
+            mode: match self.u8()? {
+                0 => RoomMode::Hosted,
+                1 => RoomMode::Server,
+                _ => return Err(ProtocolError::Malformed),
+            },
+            port: self.u16()?,
+            start_level: self.u8()?,
+            players: self.u8()?,
+        })
+    }
+}
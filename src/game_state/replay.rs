@@ -0,0 +1,126 @@
+use super::protocol::{Message, ProtocolError, Settings, PROTOCOL_VERSION};
+use super::{MAX_PLAYERS, MIN_PLAYERS};
+use crate::game_data::{Attack, Move};
+use std::collections::HashMap;
+use std::fs;
+
+/// First bytes of a replay file, followed by the protocol version it was recorded with
+const REPLAY_MAGIC: [u8; 4] = *b"TTRR";
+
+/// A recorded match, enough to simulate it again from the start.
+/// The file holds the same messages a spectator gets: a `Spectating` with the settings and names,
+/// then a `Relay` for every tick a player made moves and a `RelayAttack` for all the garbage that was sent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replay {
+    pub settings: Settings,
+    pub names: Vec<String>,                       // in side order
+    pub frames: Vec<HashMap<u64, Vec<Move>>>,     // the moves of each side by tick
+    pub attacks: Vec<HashMap<u64, (u8, Attack)>>, // the garbage each side sent by the tick it landed, with the side it landed on
+}
+
+impl Replay {
+    pub fn new(settings: Settings, names: Vec<String>) -> Replay {
+        let players = names.len();
+        Replay {
+            settings,
+            names,
+            frames: vec![HashMap::new(); players],
+            attacks: vec![HashMap::new(); players],
+        }
+    }
+
+    /// Adds the moves a side made during a tick
+    pub fn record(&mut self, side: usize, tick: u64, moves: &[Move]) {
+        if !moves.is_empty() {
+            self.frames[side].insert(tick, moves.to_vec());
+        }
+    }
+
+    /// Adds the garbage a side sent that landed on the `target` side on a tick
+    pub fn record_attack(&mut self, side: usize, tick: u64, target: u8, attack: Attack) {
+        self.attacks[side].insert(tick, (target, attack));
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), ProtocolError> {
+        let mut bytes = REPLAY_MAGIC.to_vec();
+        bytes.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        let spectating = Message::Spectating {
+            settings: self.settings.clone(),
+            names: self.names.clone(),
+        };
+        bytes.extend(spectating.encode());
+        for (side, frames) in self.frames.iter().enumerate() {
+            let mut ticks: Vec<&u64> = frames.keys().collect();
+            ticks.sort_unstable();
+            for tick in ticks {
+                let relay = Message::Relay {
+                    side: side as u8,
+                    tick: *tick,
+                    moves: frames[tick].clone(),
+                };
+                bytes.extend(relay.encode());
+            }
+        }
+        for (side, attacks) in self.attacks.iter().enumerate() {
+            let mut ticks: Vec<&u64> = attacks.keys().collect();
+            ticks.sort_unstable();
+            for tick in ticks {
+                let (target, attack) = attacks[tick];
+                let relay = Message::RelayAttack {
+                    side: side as u8,
+                    tick: *tick,
+                    target,
+                    lines: attack.lines,
+                    hole: attack.hole,
+                    delay: attack.delay,
+                };
+                bytes.extend(relay.encode());
+            }
+        }
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Replay, ProtocolError> {
+        let bytes = fs::read(path)?;
+        if bytes.len() < 6 || bytes[..4] != REPLAY_MAGIC {
+            return Err(ProtocolError::BadMagic);
+        }
+        let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::Unsupported(format!(
+                "the replay was recorded with protocol version {}, this is version {}",
+                version, PROTOCOL_VERSION
+            )));
+        }
+        let mut rest = &bytes[6..];
+        let mut replay = match Message::read_from(&mut rest)? {
+            Message::Spectating { settings, names }
+                if (MIN_PLAYERS..=MAX_PLAYERS).contains(&names.len()) =>
+            {
+                Replay::new(settings, names)
+            }
+            message => return Err(ProtocolError::Unexpected(message)),
+        };
+        let players = replay.names.len();
+        while !rest.is_empty() {
+            match Message::read_from(&mut rest)? {
+                Message::Relay { side, tick, moves } if (side as usize) < players => {
+                    replay.record(side as usize, tick, &moves)
+                }
+                Message::RelayAttack {
+                    side,
+                    tick,
+                    target,
+                    lines,
+                    hole,
+                    delay,
+                } if (side as usize) < players && (target as usize) < players => {
+                    replay.record_attack(side as usize, tick, target, Attack { lines, hole, delay })
+                }
+                message => return Err(ProtocolError::Unexpected(message)),
+            }
+        }
+        Ok(replay)
+    }
+}
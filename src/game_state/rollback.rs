@@ -0,0 +1,266 @@
+use super::auth::Seal;
+use super::connection::Traffic;
+use super::protocol::{Frame, Message, TimedAttack};
+use super::Game;
+use crate::game_data::Player;
+use log::warn;
+use std::collections::HashMap;
+use std::net::{IpAddr, TcpStream, UdpSocket};
+
+/// Ticks we may run ahead of the opponent's last known moves before the game waits for them
+const MAX_ROLLBACK: u64 = 12;
+/// Most frames sent in one packet, older ones are left to the connection
+const MAX_FRAMES_PER_PACKET: usize = 32;
+
+/// Rollback instead of lockstep: our moves are applied on the tick they're made and the opponent is guessed
+/// to have made no moves until theirs arrive. If they did move, the players are put back to how they were
+/// on that tick and the ticks since are simulated again.
+/// Moves are sent over UDP so one lost packet doesn't hold up the ones after it, every packet carries all the
+/// moves the opponent hasn't confirmed yet. With a room password the packets are sealed like the connection.
+pub struct Rollback {
+    socket: Option<UdpSocket>,
+    seal: Option<Seal>,
+    pub(super) traffic: Traffic, // the packets sent and received
+    peer_ip: Option<IpAddr>,
+    connected: bool,
+    confirmed: u64, // the first tick the opponent's moves are missing for, the ticks before it are final
+    remote_ack: u64, // the first tick the opponent is missing our moves for
+    states: HashMap<u64, Vec<Player>>, // the players at the start of every tick since `confirmed`
+    mispredicted: Option<u64>, // the first tick that was simulated without the opponent's actual moves
+}
+
+impl Rollback {
+    /// Opens a UDP socket on the same interface as the connection, without it the moves only go over the connection
+    pub fn new(stream: &TcpStream, players: Vec<Player>) -> Rollback {
+        let socket = stream
+            .local_addr()
+            .and_then(|adress| UdpSocket::bind((adress.ip(), 0)))
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket));
+        let socket = match socket {
+            Ok(socket) => Some(socket),
+            Err(err) => {
+                warn!(
+                    "Could not open a UDP socket, moves are only sent over TCP: {}",
+                    err
+                );
+                None
+            }
+        };
+        let mut states = HashMap::new();
+        states.insert(0, players);
+        Rollback {
+            socket,
+            seal: None,
+            traffic: Traffic::default(),
+            peer_ip: stream.peer_addr().ok().map(|adress| adress.ip()),
+            connected: false,
+            confirmed: 0,
+            remote_ack: 0,
+            states,
+            mispredicted: None,
+        }
+    }
+
+    /// Sends packets to the opponent's port from now on
+    pub fn connect(&mut self, port: u16) {
+        if let (Some(socket), Some(ip)) = (&self.socket, self.peer_ip) {
+            self.connected = socket.connect((ip, port)).is_ok();
+        }
+    }
+
+    /// Decides whether the opponent's moves or garbage for a tick are new, and remembers if they prove a guess wrong.
+    /// Only `changes` something that isn't nothing, which is what was guessed.
+    pub fn receive(&mut self, tick: u64, changes: bool, current_tick: u64) -> bool {
+        if tick < self.confirmed {
+            return false;
+        }
+        if tick < current_tick && changes {
+            let first = self.mispredicted.map_or(tick, |first| first.min(tick));
+            self.mispredicted = Some(first);
+        }
+        true
+    }
+
+    /// The oldest tick whose moves could still be needed
+    pub fn oldest_needed(&self) -> u64 {
+        if self.connected {
+            self.confirmed.min(self.remote_ack)
+        } else {
+            self.confirmed
+        }
+    }
+
+    /// Starts over from a state both sides agree on
+    pub fn reset(&mut self, tick: u64, players: Vec<Player>) {
+        self.confirmed = tick;
+        self.states.clear();
+        self.states.insert(tick, players);
+        self.mispredicted = None;
+    }
+}
+
+impl Game {
+    /// Tells the opponent where to send packets, after every new connection.
+    /// The packets are sealed with a key from the connection's seal, so both sides start over with a new one.
+    pub(super) fn send_udp_port(&mut self, stream: &TcpStream, seal: Option<&Seal>) {
+        let port = match &mut self.rollback {
+            Some(rollback) => {
+                rollback.peer_ip = stream.peer_addr().ok().map(|adress| adress.ip());
+                rollback.connected = false;
+                rollback.seal = seal.map(Seal::datagrams);
+                rollback
+                    .socket
+                    .as_ref()
+                    .and_then(|socket| socket.local_addr().ok())
+            }
+            None => None,
+        };
+        // rollback matches only have the one peer
+        if let (Some(adress), Some(peer)) = (port, self.peers.first()) {
+            peer.connection.send(Message::UdpPort {
+                port: adress.port(),
+            });
+        }
+    }
+
+    /// The last tick both players' moves are known for, and the players at its start
+    pub(super) fn confirmed_state(&self) -> (u64, Vec<Player>) {
+        match &self.rollback {
+            Some(rollback) if rollback.confirmed < self.tick => (
+                rollback.confirmed,
+                rollback.states[&rollback.confirmed].clone(),
+            ),
+            _ => (self.tick, self.players.clone()),
+        }
+    }
+
+    /// Simulates the next tick right away, guessing that the opponent made no moves if theirs haven't arrived
+    pub(super) fn step_predicted(&mut self) -> bool {
+        let tick = self.tick;
+        let rollback = self.rollback.as_mut().unwrap();
+        if tick >= rollback.confirmed + MAX_ROLLBACK {
+            // too far ahead, the opponent's moves are late
+            return false;
+        }
+        rollback.states.insert(tick, self.players.clone());
+        self.seal_frame();
+        self.simulate(tick);
+        self.tick += 1;
+        true
+    }
+
+    /// Reads every packet from the opponent that has arrived
+    pub(super) fn receive_packets(&mut self) {
+        let mut buf = [0; 2048];
+        let mut frames = Vec::new();
+        let mut attacks = Vec::new();
+        if let Some(rollback) = &mut self.rollback {
+            let socket = match &rollback.socket {
+                Some(socket) if rollback.connected => socket,
+                _ => return,
+            };
+            // stops at WouldBlock when there's nothing more to read
+            while let Ok(len) = socket.recv(&mut buf) {
+                rollback.traffic.count_received(len);
+                // a packet that fails the seal is dropped like a lost one
+                let packet = match &mut rollback.seal {
+                    Some(seal) => seal.open(&buf[..len]),
+                    None => Message::read_from(&mut &buf[..len]),
+                };
+                if let Ok(Message::Inputs {
+                    ack,
+                    frames: mut f,
+                    attacks: mut a,
+                }) = packet
+                {
+                    rollback.remote_ack = rollback.remote_ack.max(ack);
+                    frames.append(&mut f);
+                    attacks.append(&mut a);
+                }
+            }
+        }
+        // the garbage of a tick is in the same packet as its moves, and is taken first like over the connection
+        let side = self.side_of(1);
+        for (tick, target, attack) in attacks {
+            self.receive_attack(side, tick, target, attack);
+        }
+        for (tick, moves) in frames {
+            self.receive_frame(side, tick, moves);
+        }
+    }
+
+    /// Sends our moves the opponent hasn't confirmed, and tells it which of its moves we have
+    pub(super) fn send_packet(&mut self) {
+        let rollback = match &self.rollback {
+            Some(rollback) if rollback.connected => rollback,
+            _ => return,
+        };
+        let mut frames: Vec<Frame> = self.frames[0]
+            .iter()
+            .filter(|(tick, _)| **tick >= rollback.remote_ack)
+            .map(|(tick, moves)| (*tick, moves.clone()))
+            .collect();
+        frames.sort_by_key(|(tick, _)| *tick);
+        let skip = frames.len().saturating_sub(MAX_FRAMES_PER_PACKET);
+        let frames = frames.split_off(skip);
+        // the garbage landing on the ticks of the frames sent, so it never arrives after their moves
+        let oldest = frames.first().map_or(self.tick, |(tick, _)| *tick);
+        let attacks: Vec<TimedAttack> = self
+            .pending_attacks(0, oldest)
+            .into_iter()
+            .filter(|(tick, _, _)| frames.iter().any(|(t, _)| t == tick))
+            .collect();
+        let packet = Message::Inputs {
+            ack: rollback.confirmed,
+            frames,
+            attacks,
+        };
+        let rollback = self.rollback.as_mut().unwrap();
+        let bytes = match &mut rollback.seal {
+            Some(seal) => seal.seal(&packet),
+            None => packet.encode(),
+        };
+        if let Some(socket) = &rollback.socket {
+            // a lost packet is covered by the next one
+            if socket.send(&bytes).is_ok() {
+                rollback.traffic.count_sent(bytes.len());
+            }
+        }
+    }
+
+    /// Goes back to the first wrongly guessed tick and simulates the ticks since with the moves that have arrived
+    pub(super) fn roll_back(&mut self) {
+        let rollback = self.rollback.as_mut().unwrap();
+        let from = match rollback.mispredicted.take() {
+            Some(from) => from,
+            None => return,
+        };
+        self.players = rollback.states[&from].clone();
+        for tick in from..self.tick {
+            let rollback = self.rollback.as_mut().unwrap();
+            rollback.states.insert(tick, self.players.clone());
+            self.simulate(tick);
+        }
+    }
+
+    /// Moves past every tick both players' moves are known for, those ticks won't be simulated again
+    pub(super) fn confirm(&mut self) {
+        loop {
+            let rollback = self.rollback.as_mut().unwrap();
+            let tick = rollback.confirmed;
+            if tick >= self.tick || !self.frames[1].contains_key(&tick) {
+                break;
+            }
+            rollback.confirmed = tick + 1;
+            let players = match rollback.states.get(&(tick + 1)) {
+                Some(players) => players.clone(),
+                None => self.players.clone(),
+            };
+            self.record(tick);
+            self.check_state(tick + 1, &players);
+        }
+        let rollback = self.rollback.as_mut().unwrap();
+        let confirmed = rollback.confirmed;
+        rollback.states.retain(|tick, _| *tick >= confirmed);
+    }
+}
@@ -0,0 +1,412 @@
+use super::auth::{self, Seal};
+use super::connection::{Connection, NetConfig};
+use super::lobby::LobbyClient;
+use super::protocol::{Message, ProtocolError, Settings, PROTOCOL_VERSION};
+use super::targeting::{self, Targeting};
+use super::{route_attacks, teams, DEFAULT_TICK_RATE, MAX_PLAYERS, MIN_PLAYERS};
+use crate::game_data::{GameRng, Player, COLS, ROWS, TIME_LEVELS};
+use log::{info, warn};
+use rand::RngCore;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Max ticks the server simulates back to back after falling behind
+const MAX_CATCH_UP: u32 = 5;
+
+/// A player in the match and the connection to their client
+struct Seat {
+    name: String,
+    session: u64,
+    connection: Connection,
+    lost_since: Option<Instant>, // set while the client is disconnected
+    wants_rematch: bool,
+}
+
+/// Runs matches headlessly. Clients only send their moves and get the state of every player back after each tick,
+/// so no client can change the outcome by simulating its own game differently.
+pub struct Server {
+    incoming: mpsc::Receiver<(TcpStream, Message, Option<Seal>)>,
+    config: NetConfig,
+    start_level: u8,
+    settings: Settings,
+    seats: Vec<Seat>,
+    spectators: Vec<(String, Connection)>,
+    players: Vec<Player>,
+    tick: u64,
+    targeting: Targeting,              // who the garbage of every player goes to
+    lobby: Option<(LobbyClient, u32)>, // the lobby and the id of our room there, told how many players are in it
+    local_addr: SocketAddr,
+}
+
+impl Server {
+    /// Starts listening for clients, the first message of every client is read on a separate thread.
+    /// With a room password the clients have to prove they know it before their first message is passed on.
+    pub fn bind(adress: &str, start_level: u8, config: NetConfig) -> io::Result<Server> {
+        if start_level as usize >= TIME_LEVELS.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the level has to be between 0 and {}, not {}",
+                    TIME_LEVELS.len() - 1,
+                    start_level
+                ),
+            ));
+        }
+        let listener = TcpListener::bind(adress)?;
+        let local_addr = listener.local_addr()?;
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                stream.set_read_timeout(Some(config.timeout)).ok();
+                match Message::read_from(&mut stream) {
+                    Ok(Message::Hello { version, .. })
+                    | Ok(Message::Resume { version, .. })
+                    | Ok(Message::Spectate { version, .. })
+                        if version != PROTOCOL_VERSION =>
+                    {
+                        let reason = format!(
+                            "protocol version {} is not supported, the server runs version {}",
+                            version, PROTOCOL_VERSION
+                        );
+                        warn!("Rejected a client: {}", reason);
+                        Message::Reject { reason }.write_to(&mut stream).ok();
+                    }
+                    Ok(message) => {
+                        let seal = match auth::check_password(&mut stream, config.key.as_ref()) {
+                            Ok(seal) => seal,
+                            Err(err) => {
+                                warn!("Ignored a client: {}", err);
+                                continue;
+                            }
+                        };
+                        if sender.send((stream, message, seal)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => warn!("Ignored a client: {}", err),
+                }
+            }
+        });
+        Ok(Server {
+            incoming,
+            config,
+            start_level,
+            settings: Server::new_settings(start_level, &config),
+            seats: Vec::new(),
+            spectators: Vec::new(),
+            players: Vec::new(),
+            tick: 0,
+            targeting: Targeting::Random,
+            lobby: None,
+            local_addr,
+        })
+    }
+
+    /// Where clients connect, with the port the OS picked if it was bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn set_targeting(&mut self, targeting: Targeting) {
+        self.targeting = targeting;
+    }
+
+    /// Keeps the room `id` opened in a lobby up to date with the players waiting for or in the match
+    pub fn set_lobby(&mut self, client: LobbyClient, id: u32) {
+        self.lobby = Some((client, id));
+        self.update_lobby(0);
+    }
+
+    /// Hosts one match after another
+    pub fn run(&mut self) {
+        loop {
+            self.wait_for_players();
+            if let Err(reason) = self.play() {
+                info!("Match ended: {}", reason);
+                self.broadcast(&Message::Reject { reason });
+            }
+            self.seats.clear();
+            self.spectators.clear();
+            self.update_lobby(0);
+        }
+    }
+
+    /// Tells the lobby how many players are in our room, a lobby that can't be reached is given up on
+    fn update_lobby(&mut self, joined: usize) {
+        if let Some((client, id)) = &mut self.lobby {
+            if let Err(err) = client.update(*id, joined as u8) {
+                warn!("Lost the lobby: {}", err);
+                self.lobby = None;
+            }
+        }
+    }
+
+    fn new_settings(start_level: u8, config: &NetConfig) -> Settings {
+        Settings {
+            start_level,
+            cols: COLS as u8,
+            rows: ROWS as u8,
+            seed: rand::random(),
+            tick_rate: DEFAULT_TICK_RATE,
+            rollback: false,
+            best_of: 1,
+            teams: config.teams,
+            split_garbage: config.split_garbage,
+            randomizer: config.randomizer,
+            attack_table: config.attack_table,
+        }
+    }
+
+    fn new_players(&mut self) {
+        let level = self.settings.start_level as usize;
+        self.players = (0..self.seats.len() as u64)
+            .map(|side| {
+                let seed = self.settings.seed.wrapping_add(side);
+                let mut player = Player::new(
+                    level,
+                    seed,
+                    self.settings.tick_rate,
+                    self.settings.randomizer,
+                );
+                player.set_attack_table(self.settings.attack_table);
+                player
+            })
+            .collect();
+        self.tick = 0;
+    }
+
+    /// Blocks until enough players have said hello, then seats them and starts the match
+    fn wait_for_players(&mut self) {
+        info!("Waiting for players...");
+        let players = (self.config.players as usize).clamp(MIN_PLAYERS, MAX_PLAYERS);
+        let mut waiting: Vec<(TcpStream, String, Option<Seal>)> = Vec::new();
+        while waiting.len() < players {
+            let (mut stream, message, mut seal) = match self.incoming.recv() {
+                Ok(incoming) => incoming,
+                Err(_) => return,
+            };
+            match message {
+                Message::Hello { name, .. } => {
+                    info!("{} joined", name);
+                    waiting.push((stream, name, seal));
+                    if waiting.len() < players {
+                        // everyone waiting hears how many have joined, whoever can't be reached has left
+                        let message = Message::Waiting {
+                            joined: waiting.len() as u8,
+                            players: players as u8,
+                        };
+                        waiting.retain_mut(|(stream, name, seal)| {
+                            let here = auth::write(&message, stream, seal).is_ok();
+                            if !here {
+                                info!("{} left", name);
+                            }
+                            here
+                        });
+                    }
+                    self.update_lobby(waiting.len());
+                }
+                message => {
+                    let reason = "no match is running".to_string();
+                    warn!("Rejected a client: {}", ProtocolError::Unexpected(message));
+                    auth::write(&Message::Reject { reason }, &mut stream, &mut seal).ok();
+                }
+            }
+        }
+
+        self.settings = Server::new_settings(self.start_level, &self.config);
+        let names: Vec<String> = waiting.iter().map(|(_, name, _)| name.clone()).collect();
+        for (side, (mut stream, name, mut seal)) in waiting.into_iter().enumerate() {
+            let session = rand::random();
+            let seated = Message::Seated {
+                settings: self.settings.clone(),
+                side: side as u8,
+                names: names.clone(),
+                session,
+            };
+            // written before the connection starts pinging, the handshake reads it first
+            if let Err(err) = auth::write(&seated, &mut stream, &mut seal) {
+                warn!("Could not seat {}: {}", name, err);
+            }
+            self.seats.push(Seat {
+                name,
+                session,
+                connection: Connection::start(stream, self.config, seal),
+                lost_since: None,
+                wants_rematch: false,
+            });
+        }
+        self.new_players();
+        info!("{}", names.join(" vs "));
+    }
+
+    /// Runs the match until a player leaves for good
+    fn play(&mut self) -> Result<(), String> {
+        let tick_length = Duration::from_secs(1) / self.settings.tick_rate;
+        let mut next_tick = Instant::now();
+        loop {
+            self.handle_incoming();
+            self.handle_messages();
+            let paused = self.check_seats()?;
+
+            let now = Instant::now();
+            if paused || now > next_tick + tick_length * MAX_CATCH_UP {
+                // don't rush through the ticks that were missed
+                next_tick = now;
+            }
+            if !paused && now >= next_tick {
+                self.step();
+                next_tick += tick_length;
+            } else {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    /// Simulates one tick and sends the result to everyone
+    fn step(&mut self) {
+        let lost: Vec<bool> = self.players.iter().map(Player::get_lost).collect();
+        for player in &mut self.players {
+            player.update();
+        }
+        targeting::count_knockouts(&mut self.players, &lost, |side| side);
+        let roll = GameRng::new(self.settings.seed ^ self.tick).next_u64();
+        route_attacks(&mut self.players, &self.settings, self.targeting, roll);
+        self.tick += 1;
+        self.broadcast_state();
+    }
+
+    fn broadcast_state(&self) {
+        let state = Message::State {
+            tick: self.tick,
+            players: self.players.iter().map(Player::to_bytes).collect(),
+        };
+        self.broadcast(&state);
+    }
+
+    fn broadcast(&self, message: &Message) {
+        let seats = self.seats.iter().map(|seat| &seat.connection);
+        let spectators = self.spectators.iter().map(|(_, spectator)| spectator);
+        for connection in seats.chain(spectators) {
+            connection.send(message.clone());
+        }
+    }
+
+    /// Takes in spectators and players coming back, the match is full for everyone else
+    fn handle_incoming(&mut self) {
+        while let Ok((mut stream, message, mut seal)) = self.incoming.try_recv() {
+            match message {
+                Message::Resume { session, .. } => {
+                    match self.seats.iter_mut().find(|seat| seat.session == session) {
+                        Some(seat) => {
+                            info!("{} reconnected", seat.name);
+                            seat.connection = Connection::start(stream, self.config, seal);
+                            seat.lost_since = None;
+                            // the returning client waits for a state before it shows anything
+                            self.broadcast_state();
+                        }
+                        None => {
+                            let reason = "unknown session".to_string();
+                            auth::write(&Message::Reject { reason }, &mut stream, &mut seal).ok();
+                        }
+                    }
+                }
+                Message::Spectate { name, .. } => {
+                    let spectating = Message::Spectating {
+                        settings: self.settings.clone(),
+                        names: self.seats.iter().map(|seat| seat.name.clone()).collect(),
+                    };
+                    if auth::write(&spectating, &mut stream, &mut seal).is_err() {
+                        continue;
+                    }
+                    info!("{} is watching", name);
+                    self.spectators
+                        .push((name, Connection::start(stream, self.config, seal)));
+                    self.broadcast_state();
+                }
+                _ => {
+                    let reason = "the match is full".to_string();
+                    auth::write(&Message::Reject { reason }, &mut stream, &mut seal).ok();
+                }
+            }
+        }
+    }
+
+    /// Applies the moves of every player, passes on their chat and restarts the match once everyone asked for a rematch
+    fn handle_messages(&mut self) {
+        let mut chat = Vec::new();
+        for (side, (seat, player)) in self.seats.iter_mut().zip(&mut self.players).enumerate() {
+            while let Some(message) = seat.connection.try_recv() {
+                match message {
+                    Message::Input { moves, .. } => {
+                        for mv in moves {
+                            player.apply_move(mv);
+                        }
+                    }
+                    Message::Rematch { .. } => seat.wants_rematch = true,
+                    // the side comes from the seat, nobody can write as someone else
+                    Message::Chat { text, .. } => chat.push(Message::Chat {
+                        side: side as u8,
+                        text,
+                    }),
+                    _ => (),
+                }
+            }
+        }
+        for message in &chat {
+            self.broadcast(message);
+        }
+        for (_, spectator) in &mut self.spectators {
+            // spectators have nothing to say, this only notices if they left
+            while spectator.try_recv().is_some() {}
+        }
+        self.spectators.retain(|(name, spectator)| {
+            let watching = spectator.get_disconnected().is_none();
+            if !watching {
+                info!("{} stopped watching", name);
+            }
+            watching
+        });
+
+        let over = teams::decided(
+            &self.players,
+            &teams::assign(self.settings.teams, self.players.len()),
+        );
+        if over && self.seats.iter().all(|seat| seat.wants_rematch) {
+            info!("Rematch!");
+            self.settings.seed = rand::random();
+            self.new_players();
+            for seat in &mut self.seats {
+                seat.wants_rematch = false;
+            }
+        }
+    }
+
+    /// Returns whether the match has to wait for someone to reconnect,
+    /// or why it's over if they didn't make it back in time
+    fn check_seats(&mut self) -> Result<bool, String> {
+        let mut paused = false;
+        for seat in &mut self.seats {
+            if let Some(reason) = seat.connection.get_disconnected() {
+                let lost_since = match seat.lost_since {
+                    Some(lost_since) => lost_since,
+                    None => {
+                        info!("Waiting for {} to reconnect... ({})", seat.name, reason);
+                        *seat.lost_since.insert(Instant::now())
+                    }
+                };
+                if lost_since.elapsed() >= self.config.grace_period {
+                    return Err(format!("{} disconnected", seat.name));
+                }
+                paused = true;
+            }
+        }
+        Ok(paused)
+    }
+}
@@ -0,0 +1,62 @@
+use super::connection::Traffic;
+use std::time::{Duration, Instant};
+
+/// What the network overlay shows about the connections of a match
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetStats {
+    pub ping: Option<Duration>,    // to the slowest peer
+    pub jitter: Option<Duration>,  // of the peer whose ping varies the most
+    pub per_second: Traffic, // over every connection and the UDP packets of rollback, in the last second
+    pub input_delay: u64,    // ticks between a move and the tick it's played on
+    pub queued_inputs: usize, // ticks of the other players' moves that have arrived but haven't been played
+    pub last_check: Option<Check>, // None until the first checksums were compared
+}
+
+/// The result of the last comparison of checksums with a peer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Check {
+    pub tick: u64,
+    pub matched: bool,
+}
+
+/// Turns running totals of traffic into rates, measured over whole seconds so the numbers don't jump around
+pub struct RateMeter {
+    since: Instant,
+    total: Traffic, // the totals at `since`
+    rate: Traffic,
+}
+
+impl Default for RateMeter {
+    fn default() -> RateMeter {
+        RateMeter {
+            since: Instant::now(),
+            total: Traffic::default(),
+            rate: Traffic::default(),
+        }
+    }
+}
+
+impl RateMeter {
+    /// Takes the latest totals, and starts a new measurement once a second has passed
+    pub fn update(&mut self, total: Traffic) {
+        let elapsed = self.since.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        let added = total.since(self.total);
+        let per_second = |count: u64| (count as f64 / elapsed.as_secs_f64()).round() as u64;
+        self.rate = Traffic {
+            messages_sent: per_second(added.messages_sent),
+            bytes_sent: per_second(added.bytes_sent),
+            messages_received: per_second(added.messages_received),
+            bytes_received: per_second(added.bytes_received),
+        };
+        self.since = Instant::now();
+        self.total = total;
+    }
+
+    /// Traffic per second over the last measurement
+    pub fn get_rate(&self) -> Traffic {
+        self.rate
+    }
+}
@@ -0,0 +1,93 @@
+use crate::game_data::Player;
+
+/// Who a player's garbage goes to when there is more than one opponent left.
+/// Every attack names the side it lands on, so only the attacker's simulation has to pick.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Targeting {
+    /// A different random opponent for every attack
+    Random,
+    /// Whoever attacked us last, a random opponent until someone has
+    Attacker,
+    /// The opponent with the most garbage waiting, to finish them off
+    MostGarbage,
+    /// The opponent who has knocked out the most players
+    Badges,
+}
+
+/// The strategies by the names used on the command line, in the order Tab cycles through them
+pub const TARGETING_NAMES: [(&str, Targeting); 4] = [
+    ("random", Targeting::Random),
+    ("attacker", Targeting::Attacker),
+    ("most-garbage", Targeting::MostGarbage),
+    ("badges", Targeting::Badges),
+];
+
+impl Targeting {
+    pub fn parse(name: &str) -> Option<Targeting> {
+        TARGETING_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, targeting)| *targeting)
+    }
+
+    pub fn name(self) -> &'static str {
+        TARGETING_NAMES
+            .iter()
+            .find(|(_, targeting)| *targeting == self)
+            .map_or("random", |(name, _)| name)
+    }
+
+    /// The strategy after this one
+    pub fn next(self) -> Targeting {
+        let index = TARGETING_NAMES
+            .iter()
+            .position(|(_, targeting)| *targeting == self)
+            .unwrap_or(0);
+        TARGETING_NAMES[(index + 1) % TARGETING_NAMES.len()].1
+    }
+
+    /// Picks the player the garbage of `players[from]` goes to, None if every opponent has lost.
+    /// `teams` has the team of every player, teammates are never picked.
+    /// `attacker` is the index of whoever attacked `from` last, and `roll` decides between equally good targets.
+    pub fn pick(
+        self,
+        from: usize,
+        players: &[Player],
+        teams: &[u8],
+        attacker: Option<usize>,
+        roll: u64,
+    ) -> Option<usize> {
+        let alive: Vec<usize> = (0..players.len())
+            .filter(|p| teams[*p] != teams[from] && !players[*p].get_lost())
+            .collect();
+        let score = |p: usize| match self {
+            Targeting::Random => 0,
+            Targeting::Attacker => (Some(p) == attacker) as u32,
+            Targeting::MostGarbage => players[p]
+                .get_incoming()
+                .iter()
+                .map(|attack| attack.lines as u32)
+                .sum(),
+            Targeting::Badges => players[p].get_knockouts() as u32,
+        };
+        let best = alive.iter().map(|p| score(*p)).max()?;
+        let candidates: Vec<usize> = alive.into_iter().filter(|p| score(*p) == best).collect();
+        Some(candidates[(roll % candidates.len() as u64) as usize])
+    }
+}
+
+/// Credits every player who topped out since `lost` was taken to whoever attacked them last.
+/// `index_of` finds a player in `players` by their side.
+pub fn count_knockouts(players: &mut [Player], lost: &[bool], index_of: impl Fn(usize) -> usize) {
+    for p in 0..players.len() {
+        if lost[p] || !players[p].get_lost() {
+            continue;
+        }
+        if let Some(attacker) = players[p].get_attacker() {
+            let attacker = index_of(attacker as usize);
+            if attacker < players.len() && attacker != p {
+                players[attacker].add_knockout();
+            }
+        }
+    }
+}
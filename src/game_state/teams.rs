@@ -0,0 +1,89 @@
+use crate::game_data::{Attack, Player};
+
+/// The team of every side in a match of `teams` teams, dealt out in turn: side 0 to the first team, side 1 to the second and so on.
+/// With fewer than two teams everyone plays for themselves, every player is their own team.
+pub fn assign(teams: u8, players: usize) -> Vec<u8> {
+    (0..players)
+        .map(|side| {
+            if teams < 2 {
+                side as u8
+            } else {
+                (side % teams as usize) as u8
+            }
+        })
+        .collect()
+}
+
+/// Whether `teams` has more than one player on a team
+pub fn is_team_match(teams: &[u8]) -> bool {
+    teams
+        .iter()
+        .enumerate()
+        .any(|(p, team)| teams[..p].contains(team))
+}
+
+/// The teams that still have a player who hasn't topped out
+fn standing(players: &[Player], teams: &[u8]) -> Vec<u8> {
+    let mut standing: Vec<u8> = (0..players.len())
+        .filter(|p| !players[*p].get_lost())
+        .map(|p| teams[p])
+        .collect();
+    standing.sort_unstable();
+    standing.dedup();
+    standing
+}
+
+/// Whether a match between `players` is decided, at most one team is left.
+/// `teams` has the team of every player, in the same order.
+pub fn decided(players: &[Player], teams: &[u8]) -> bool {
+    standing(players, teams).len() <= 1
+}
+
+/// The last team standing, None while more are left or if the last ones topped out together
+pub fn winner(players: &[Player], teams: &[u8]) -> Option<u8> {
+    match standing(players, teams)[..] {
+        [team] => Some(team),
+        _ => None,
+    }
+}
+
+/// Who gets garbage sent to the player at `target`. With `split` it's shared between every member of its team still playing,
+/// starting with `target` and going on in side order, so every machine shares it out the same way.
+/// `side_of` finds the side of a player by its index.
+pub fn receivers(
+    target: usize,
+    players: &[Player],
+    teams: &[u8],
+    split: bool,
+    side_of: impl Fn(usize) -> usize,
+) -> Vec<usize> {
+    if !split {
+        return vec![target];
+    }
+    let n = players.len();
+    let mut members: Vec<usize> = (0..n)
+        .filter(|p| teams[*p] == teams[target] && (*p == target || !players[*p].get_lost()))
+        .collect();
+    members.sort_by_key(|p| (side_of(*p) + n - side_of(target)) % n);
+    members
+}
+
+/// Splits an attack evenly between `count` players, the lines left over go to the first ones.
+/// Players whose share has no lines get nothing.
+pub fn share(attack: Attack, count: usize) -> Vec<Option<Attack>> {
+    let count = count.max(1);
+    (0..count)
+        .map(|i| {
+            let lines =
+                attack.lines as usize / count + (i < attack.lines as usize % count) as usize;
+            if lines == 0 {
+                None
+            } else {
+                Some(Attack {
+                    lines: lines as u8,
+                    ..attack
+                })
+            }
+        })
+        .collect()
+}
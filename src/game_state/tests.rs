@@ -1,6 +1,1122 @@
-use super::Game;
+use super::connection::{Connection, NetConfig};
+use super::keys::KeyBindings;
+use super::lobby::{Lobby, LobbyClient};
+use super::protocol::{
+    Message, ProtocolError, Room, RoomMode, RoomSettings, Settings, PROTOCOL_VERSION,
+};
+use super::server::Server;
+use super::targeting::Targeting;
+use super::{auth, chat, teams, Game, Mode, Setup, DEFAULT_AI, INPUT_DELAY};
+use crate::game_data::{Attack, AttackTable, Move, Player, RandomizerKind};
+use ggez::event::KeyCode;
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+/// Hosts on a port picked by the OS, returns where to connect and the thread waiting for the clients
+fn spawn_host(
+    level: usize,
+    config: NetConfig,
+) -> (String, JoinHandle<Result<Game, ProtocolError>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let adress = listener.local_addr().unwrap().to_string();
+    let host = thread::spawn(move || Game::host_listening(listener, level, "Host".into(), config));
+    (adress, host)
+}
+
 #[test]
 fn load_dynamic_ai_lib() {
-    let mut game = Game::new(5);
-    println!("{:?}", game.call_ai_script());
+    let setup = Setup {
+        ai: [None, Some(DEFAULT_AI.to_string())],
+        ..Setup::default()
+    };
+    let mut game = Game::start_mode(Mode::Local, &setup).unwrap();
+    // the example moves its piece towards the lowest column, the leftmost one on an empty board
+    assert_eq!(
+        Some(Move::Left),
+        Move::from_u8(game.call_ai_script(1) as u8)
+    );
+    // P1 is on the keyboard
+    assert_eq!(0, game.call_ai_script(0));
+
+    // the players after P2 get the example when nobody picked a script for them
+    let setup = Setup {
+        players: 3,
+        ..Setup::default()
+    };
+    let game = Game::start_mode(Mode::Local, &setup).unwrap();
+    assert_eq!(["P1", "P2", "AI 3"], game.get_names());
+}
+
+#[test]
+fn message_round_trip() {
+    let attack = Attack {
+        lines: 2,
+        hole: 7,
+        delay: 6,
+    };
+    let messages = vec![
+        Message::Hello {
+            version: PROTOCOL_VERSION,
+            name: "Isak".to_string(),
+        },
+        Message::Welcome {
+            settings: Settings {
+                start_level: 5,
+                cols: 10,
+                rows: 24,
+                seed: 1234,
+                tick_rate: 60,
+                rollback: false,
+                best_of: 1,
+                teams: 2,
+                split_garbage: true,
+                randomizer: RandomizerKind::History,
+                attack_table: AttackTable {
+                    combo: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+                    back_to_back: 2,
+                },
+            },
+            side: 1,
+            names: vec!["Malte".to_string(), "Isak".to_string()],
+            session: 42,
+        },
+        Message::Waiting {
+            joined: 2,
+            players: 4,
+        },
+        Message::Snapshot {
+            tick: 99,
+            players: vec![vec![1, 2, 3], vec![]],
+            frames: vec![vec![(100, vec![Move::Left])], vec![]],
+            attacks: vec![vec![], vec![(104, 0, attack)]],
+        },
+        Message::Attack {
+            tick: 104,
+            target: 1,
+            lines: 2,
+            hole: 7,
+            delay: 6,
+        },
+        Message::RelayAttack {
+            side: 0,
+            tick: 104,
+            target: 3,
+            lines: 2,
+            hole: 7,
+            delay: 6,
+        },
+        Message::Chat {
+            side: 1,
+            text: "gg 🎉".into(),
+        },
+        Message::Inputs {
+            ack: 98,
+            frames: vec![(103, vec![]), (104, vec![Move::SoftDrop])],
+            attacks: vec![(104, 0, attack)],
+        },
+        Message::Input {
+            tick: 1234,
+            moves: vec![Move::Left, Move::HardDrop, Move::Save, Move::Rotate180],
+        },
+        Message::Rematch { side: 2 },
+        Message::NewMatch {
+            tick: 300,
+            settings: Settings {
+                start_level: 5,
+                cols: 10,
+                rows: 24,
+                seed: 5678,
+                tick_rate: 60,
+                rollback: true,
+                best_of: 3,
+                teams: 0,
+                split_garbage: false,
+                randomizer: RandomizerKind::Bag7,
+                attack_table: AttackTable::default(),
+            },
+            wins: vec![1, 0],
+        },
+        Message::Spectating {
+            settings: Settings {
+                start_level: 0,
+                cols: 10,
+                rows: 24,
+                seed: 7,
+                tick_rate: 60,
+                rollback: false,
+                best_of: 1,
+                teams: 0,
+                split_garbage: false,
+                randomizer: RandomizerKind::Bag7,
+                attack_table: AttackTable::default(),
+            },
+            names: vec!["Isak".to_string(), "Malte".to_string()],
+        },
+        Message::Relay {
+            side: 1,
+            tick: 50,
+            moves: vec![Move::RotateCw],
+        },
+        Message::Beacon {
+            version: PROTOCOL_VERSION,
+            name: "Isak".to_string(),
+            port: 8787,
+            free_slots: 1,
+        },
+        Message::Seated {
+            settings: Settings {
+                start_level: 3,
+                cols: 10,
+                rows: 24,
+                seed: 9,
+                tick_rate: 60,
+                rollback: false,
+                best_of: 1,
+                teams: 0,
+                split_garbage: false,
+                randomizer: RandomizerKind::Bag7,
+                attack_table: AttackTable::default(),
+            },
+            side: 1,
+            names: vec!["Isak".to_string(), "Malte".to_string()],
+            session: 77,
+        },
+        Message::State {
+            tick: 8,
+            players: vec![vec![4, 5], vec![6]],
+        },
+        Message::Checksum {
+            tick: 120,
+            hashes: vec![u64::MAX, 0],
+        },
+        Message::Resync,
+        Message::Challenge { nonce: [7; 16] },
+        Message::Proof {
+            nonce: [1; 16],
+            mac: [2; 32],
+        },
+        Message::LobbyHello {
+            version: PROTOCOL_VERSION,
+            name: "Malte".to_string(),
+        },
+        Message::Rooms {
+            rooms: vec![Room {
+                id: 3,
+                owner: "Isak".to_string(),
+                settings: RoomSettings {
+                    name: "Isak's room".to_string(),
+                    mode: RoomMode::Server,
+                    port: 8787,
+                    start_level: 5,
+                    players: 4,
+                },
+                joined: 1,
+            }],
+        },
+        Message::JoinRoom { id: 3 },
+        Message::UpdateRoom { id: 3, joined: 2 },
+        Message::HandOff {
+            adress: "192.168.0.2:8787".to_string(),
+        },
+    ];
+    let mut buffer = Vec::new();
+    for message in &messages {
+        message.write_to(&mut buffer).unwrap();
+    }
+    let mut stream = buffer.as_slice();
+    for message in messages {
+        assert_eq!(message, Message::read_from(&mut stream).unwrap());
+    }
+}
+
+#[test]
+fn bad_magic() {
+    let mut bytes = Message::Hello {
+        version: PROTOCOL_VERSION,
+        name: String::new(),
+    }
+    .encode();
+    bytes[5] = b'X';
+    match Message::read_from(&mut bytes.as_slice()) {
+        Err(ProtocolError::BadMagic) => (),
+        other => panic!("expected BadMagic, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn long_strings_are_cut_between_characters() {
+    // 'ö' is two bytes, the limit falls in the middle of the last one
+    let reason = "ö".repeat(u16::MAX as usize / 2 + 1);
+    let bytes = Message::Reject { reason }.encode();
+    match Message::decode(&bytes[4..]) {
+        Ok(Message::Reject { reason }) => assert_eq!(reason, "ö".repeat(u16::MAX as usize / 2)),
+        other => panic!("expected Reject, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn settings_are_checked() {
+    let settings = Settings {
+        start_level: 5,
+        cols: 10,
+        rows: 24,
+        seed: 1234,
+        tick_rate: 60,
+        rollback: false,
+        best_of: 1,
+        teams: 0,
+        split_garbage: false,
+        randomizer: RandomizerKind::Bag7,
+        attack_table: AttackTable::default(),
+    };
+    assert!(settings.check().is_ok());
+    let bad = [
+        Settings {
+            start_level: 20,
+            ..settings.clone()
+        },
+        Settings {
+            tick_rate: 0,
+            ..settings.clone()
+        },
+        Settings {
+            best_of: 0,
+            ..settings.clone()
+        },
+    ];
+    for settings in bad.iter().cloned() {
+        let bytes = Message::Spectating {
+            settings,
+            names: vec!["Malte".to_string(), "Isak".to_string()],
+        }
+        .encode();
+        match Message::read_from(&mut bytes.as_slice()) {
+            Err(ProtocolError::Malformed) => (),
+            other => panic!("expected Malformed, got {:?}", other.map(|_| ())),
+        }
+    }
+    let wide = Settings {
+        cols: 12,
+        ..settings
+    };
+    assert!(matches!(wide.check(), Err(ProtocolError::Unsupported(_))));
+}
+
+#[test]
+fn attack_without_delay_is_malformed() {
+    let bytes = Message::Attack {
+        tick: 104,
+        target: 1,
+        lines: 2,
+        hole: 7,
+        delay: 0,
+    }
+    .encode();
+    match Message::read_from(&mut bytes.as_slice()) {
+        Err(ProtocolError::Malformed) => (),
+        other => panic!("expected Malformed, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn room_password() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        key: Some(auth::derive_key("hunter2")),
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(5, config);
+    let wrong = NetConfig {
+        key: Some(auth::derive_key("hunter3")),
+        ..config
+    };
+    match Game::connect(&adress, "Guess".into(), wrong) {
+        Err(ProtocolError::Rejected(_)) => (),
+        Err(err) => panic!("expected a rejection, got {}", err),
+        Ok(_) => panic!("joined with the wrong password"),
+    }
+    let none = NetConfig {
+        key: None,
+        ..config
+    };
+    match Game::connect(&adress, "Nobody".into(), none) {
+        Err(ProtocolError::Unauthorized(_)) => (),
+        Err(err) => panic!("expected to be asked for a password, got {}", err),
+        Ok(_) => panic!("joined without a password"),
+    }
+    let mut client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+    assert_eq!("Client", host.get_names()[1]);
+
+    // the sealed connection carries the match like any other
+    host.local_moves[0].push(Move::HardDrop);
+    let start = Instant::now();
+    while host.tick < 60 && start.elapsed() < Duration::from_secs(2) {
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(host.get_disconnected().is_none() && client.get_disconnected().is_none());
+    assert_eq!(host.get_boards()[0], client.get_boards()[1]);
+}
+
+#[test]
+fn sealed_messages_cannot_be_changed_or_replayed() {
+    let key = auth::derive_key("hunter2");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let adress = listener.local_addr().unwrap();
+    let host = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap().0;
+        auth::challenge(&mut stream, &key).unwrap()
+    });
+    let mut client = auth::answer(&mut TcpStream::connect(adress).unwrap(), &key).unwrap();
+    let mut host = host.join().unwrap();
+
+    let message = Message::Input {
+        tick: 5,
+        moves: vec![Move::Left],
+    };
+    let sealed = host.seal(&message);
+    assert_eq!(message, client.open(&sealed).unwrap());
+    // sent again
+    assert!(client.open(&sealed).is_err());
+    // changed on the way
+    let mut changed = host.seal(&message);
+    changed[9] ^= 1;
+    assert!(client.open(&changed).is_err());
+    // reflected back to the one who sent it
+    let reflected = host.seal(&message);
+    assert!(host.open(&reflected).is_err());
+}
+
+#[test]
+fn net_stats_are_counted() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        checksum_interval: 10,
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(5, config);
+    let mut client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(1200) {
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(5));
+    }
+    let stats = client.get_net_stats();
+    assert!(stats.ping.is_some());
+    assert!(stats.per_second.messages_sent > 0 && stats.per_second.bytes_received > 0);
+    assert_eq!(INPUT_DELAY, stats.input_delay);
+    assert!(matches!(stats.last_check, Some(check) if check.matched));
+}
+
+#[test]
+fn lobby_hands_players_off_to_rooms() {
+    let lobby = Lobby::bind("127.0.0.1:0").unwrap();
+    let adress = lobby.local_addr().unwrap().to_string();
+    thread::spawn(move || lobby.run());
+    let settings = RoomSettings {
+        name: "Isak's room".to_string(),
+        mode: RoomMode::Hosted,
+        port: 38726,
+        start_level: 5,
+        players: 3,
+    };
+    let mut owner = LobbyClient::connect(&adress, "Isak").unwrap();
+    let bad = RoomSettings {
+        players: 9,
+        ..settings.clone()
+    };
+    assert!(matches!(owner.create(bad), Err(ProtocolError::Rejected(_))));
+    let id = owner.create(settings.clone()).unwrap();
+    let server = RoomSettings {
+        mode: RoomMode::Server,
+        players: 2,
+        ..settings
+    };
+    let server_id = owner.create(server).unwrap();
+
+    let mut first = LobbyClient::connect(&adress, "Malte").unwrap();
+    assert_eq!(2, first.get_rooms().len());
+    assert_eq!(1, first.get_rooms()[0].joined);
+    assert_eq!("127.0.0.1:38726", first.join(id).unwrap());
+    let mut second = LobbyClient::connect(&adress, "Olle").unwrap();
+    assert_eq!(2, second.get_rooms()[0].joined);
+    second.join(id).unwrap();
+    // the room is full now
+    assert_eq!(1, second.list().unwrap().len());
+    assert!(matches!(second.join(id), Err(ProtocolError::Rejected(_))));
+
+    // a server's room stays open when it's full, the server says how many are in it
+    first.join(server_id).unwrap();
+    second.join(server_id).unwrap();
+    assert_eq!(2, second.list().unwrap()[0].joined);
+    assert!(matches!(
+        second.update(server_id, 0),
+        Err(ProtocolError::Rejected(_))
+    ));
+    owner.update(server_id, 0).unwrap();
+    assert_eq!(0, second.list().unwrap()[0].joined);
+
+    // the rooms of whoever leaves are closed
+    drop(owner);
+    let start = Instant::now();
+    while !second.list().unwrap().is_empty() && start.elapsed() < Duration::from_secs(2) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(second.get_rooms().is_empty());
+}
+
+#[test]
+fn disconnect_is_reported() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        grace_period: Duration::from_millis(200),
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(5, config);
+    let client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+    assert_eq!("Client", host.get_names()[1]);
+
+    drop(client);
+    let start = Instant::now();
+    while host.get_disconnected().is_none() && start.elapsed() < Duration::from_secs(2) {
+        host.update();
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(host.get_disconnected().is_some());
+}
+
+#[test]
+fn reconnect_resumes_match() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        grace_period: Duration::from_secs(5),
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(5, config);
+    let mut client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+
+    let run = |host: &mut Game, client: &mut Game, ms| {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(ms) {
+            host.update();
+            client.update();
+            thread::sleep(Duration::from_millis(5));
+        }
+    };
+    host.local_moves[0].push(Move::HardDrop);
+    run(&mut host, &mut client, 300);
+
+    // replace the client's connection with one that dies right away
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    drop(listener);
+    client.peers[0].connection = Connection::start(stream, config, None);
+    let start = Instant::now();
+    while !client.is_reconnecting() && start.elapsed() < Duration::from_secs(1) {
+        client.update();
+    }
+    assert!(client.is_reconnecting());
+
+    client.local_moves[0].push(Move::HardDrop);
+    let start = Instant::now();
+    while (host.is_reconnecting() || client.is_reconnecting() || host.tick < 60)
+        && start.elapsed() < Duration::from_secs(5)
+    {
+        run(&mut host, &mut client, 10);
+    }
+    assert!(!client.is_reconnecting());
+    assert!(host.get_disconnected().is_none());
+
+    // let both sides reach the same tick and compare
+    while host.tick != client.tick {
+        if host.tick < client.tick {
+            host.update();
+        } else {
+            client.update();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(host.get_boards()[0], client.get_boards()[1]);
+    assert_eq!(host.get_boards()[1], client.get_boards()[0]);
+}
+
+#[test]
+fn spectator_sees_the_match() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        grace_period: Duration::from_secs(5),
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(5, config);
+    let mut client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+
+    host.local_moves[0].push(Move::HardDrop);
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(200) {
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    // joins in the middle of the match
+    let spectator = thread::spawn(move || Game::spectate(&adress, "Viewer".into(), config));
+    while !spectator.is_finished() {
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(5));
+    }
+    let mut spectator = spectator.join().unwrap().unwrap();
+    assert!(spectator.is_spectator());
+    assert_eq!("Client", spectator.get_names()[1]);
+
+    client.local_moves[0].push(Move::Left);
+    client.local_moves[0].push(Move::HardDrop);
+    let start = Instant::now();
+    while (spectator.tick < 60 || spectator.tick != host.tick)
+        && start.elapsed() < Duration::from_secs(5)
+    {
+        if spectator.tick < host.tick {
+            spectator.update();
+        } else {
+            host.update();
+            client.update();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(host.tick, spectator.tick);
+    assert_eq!(host.get_boards(), spectator.get_boards());
+}
+
+#[test]
+fn server_runs_the_match() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        grace_period: Duration::from_secs(5),
+        ..NetConfig::default()
+    };
+    assert!(Server::bind("127.0.0.1:0", 20, config).is_err());
+    let mut server = Server::bind("127.0.0.1:0", 5, config).unwrap();
+    let adress = server.local_addr().to_string();
+    thread::spawn(move || server.run());
+    let first_adress = adress.clone();
+    let first = thread::spawn(move || Game::connect(&first_adress, "First".into(), config));
+    // the first client is seated first
+    thread::sleep(Duration::from_millis(100));
+    let mut second = Game::connect(&adress, "Second".into(), config).unwrap();
+    let mut first = first.join().unwrap().unwrap();
+    assert_eq!("Second", first.get_names()[1]);
+
+    first.key_down(KeyCode::Space);
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        first.update();
+        second.update();
+        let dropped = first.get_boards()[0]
+            .iter()
+            .flatten()
+            .any(|block| *block != 0);
+        if dropped && first.tick == second.tick && first.tick > 30 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(first.tick, second.tick);
+    assert_eq!(first.get_boards()[0], second.get_boards()[1]);
+    assert_eq!(first.get_boards()[1], second.get_boards()[0]);
+}
+
+#[test]
+fn desync_is_detected_and_resynced() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        grace_period: Duration::from_secs(5),
+        checksum_interval: 10,
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(5, config);
+    let mut client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+
+    // a move the host never hears about
+    client.players[0].apply_move(Move::HardDrop);
+    let start = Instant::now();
+    while (host.get_last_desync().is_none()
+        || client.get_last_desync().is_none()
+        || client.tick < 60)
+        && start.elapsed() < Duration::from_secs(5)
+    {
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(5));
+    }
+    let desync = client.get_last_desync().expect("the desync wasn't noticed");
+    assert_eq!(Some(desync), host.get_last_desync());
+
+    // no more desyncs after the client took the host's state
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(Some(desync), client.get_last_desync());
+    assert_eq!(Some(desync), host.get_last_desync());
+
+    for side in 0..2 {
+        std::fs::remove_file(format!("desync-{}-side{}.txt", desync, side)).unwrap();
+    }
+}
+
+#[test]
+fn rollback_matches_agree() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        grace_period: Duration::from_secs(5),
+        checksum_interval: 10,
+        rollback: true,
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(5, config);
+    let mut client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+
+    let start = Instant::now();
+    let mut next_move = 0;
+    while start.elapsed() < Duration::from_millis(1500) {
+        // both players move every now and then, often before the other side's moves have arrived
+        if start.elapsed() > Duration::from_millis(next_move) {
+            host.key_down(KeyCode::Q);
+            host.key_down(KeyCode::Space);
+            client.key_down(KeyCode::D);
+            client.key_down(KeyCode::Space);
+            next_move += 200;
+        }
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(3));
+    }
+    // nobody moves anymore, so every guess from here on is right
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(300) {
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(3));
+    }
+    while host.tick != client.tick {
+        if host.tick < client.tick {
+            host.update();
+        } else {
+            client.update();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(host.get_boards()[0]
+        .iter()
+        .flatten()
+        .any(|block| *block != 0));
+    assert_eq!(host.get_boards()[0], client.get_boards()[1]);
+    assert_eq!(host.get_boards()[1], client.get_boards()[0]);
+    assert_eq!(None, host.get_last_desync());
+    assert_eq!(None, client.get_last_desync());
+}
+
+#[test]
+fn attacks_land_with_the_senders_hole() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        grace_period: Duration::from_secs(5),
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(19, config);
+    let mut client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+
+    // as if the host's player had cleared three lines
+    let attack = Attack {
+        lines: 2,
+        hole: 3,
+        delay: 6,
+    };
+    let tick = host.sent_until;
+    host.send_attack(0, tick, 1, attack);
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(2));
+    }
+    while host.tick != client.tick {
+        if host.tick < client.tick {
+            host.update();
+        } else {
+            client.update();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let board = client.get_boards()[0];
+    for row in &board[..2] {
+        for (col, block) in row.iter().enumerate() {
+            assert_eq!(col == 3, *block == 0, "garbage row {:?}", row);
+        }
+    }
+    assert_eq!(board, host.get_boards()[1]);
+    assert_eq!(client.get_boards()[1], host.get_boards()[0]);
+    assert_eq!(None, client.get_last_desync());
+}
+
+#[test]
+fn free_for_all_sends_garbage_to_the_target() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        grace_period: Duration::from_secs(5),
+        players: 3,
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(19, config);
+    let first_adress = adress.clone();
+    let first = thread::spawn(move || Game::connect(&first_adress, "First".into(), config));
+    // the first client is seated first
+    thread::sleep(Duration::from_millis(100));
+    let mut second = Game::connect(&adress, "Second".into(), config).unwrap();
+    let mut first = first.join().unwrap().unwrap();
+    let mut host = host.join().unwrap().unwrap();
+    assert_eq!(["Host", "First", "Second"], host.get_names());
+    assert_eq!(["Second", "Host", "First"], second.get_names());
+
+    // the host's garbage goes to the second client, the first one's moves reach it through the host
+    let attack = Attack {
+        lines: 2,
+        hole: 3,
+        delay: 6,
+    };
+    let tick = host.sent_until;
+    host.send_attack(0, tick, 2, attack);
+    first.key_down(KeyCode::W);
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        for game in [&mut host, &mut first, &mut second].iter_mut() {
+            game.update();
+        }
+        thread::sleep(Duration::from_millis(2));
+    }
+    loop {
+        let mut games = [&mut host, &mut first, &mut second];
+        let newest = games.iter().map(|game| game.tick).max().unwrap();
+        if games.iter().all(|game| game.tick == newest) {
+            break;
+        }
+        for game in games.iter_mut().filter(|game| game.tick < newest) {
+            game.update();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let board = second.get_boards()[0];
+    for row in &board[..2] {
+        for (col, block) in row.iter().enumerate() {
+            assert_eq!(col == 3, *block == 0, "garbage row {:?}", row);
+        }
+    }
+    // a garbage row has one hole, the first client's bottom row only has its dropped piece
+    let holes = first.get_boards()[0][0]
+        .iter()
+        .filter(|block| **block == 0)
+        .count();
+    assert!(holes > 1, "the first client got garbage");
+    assert_eq!(board, host.get_boards()[2]);
+    assert_eq!(board, first.get_boards()[1]);
+    assert_eq!(first.get_boards()[0], host.get_boards()[1]);
+    assert_eq!(first.get_boards()[0], second.get_boards()[2]);
+    for game in &[&host, &first, &second] {
+        assert_eq!(None, game.get_last_desync());
+    }
+}
+
+#[test]
+fn targeting_strategies() {
+    let mut players: Vec<Player> = (0..4)
+        .map(|seed| Player::new(5, seed, 60, RandomizerKind::Bag7))
+        .collect();
+    let attack = Attack {
+        lines: 3,
+        hole: 0,
+        delay: 6,
+    };
+    players[2].add_incoming(attack);
+    players[1].add_knockout();
+    let teams = teams::assign(0, 4);
+    assert_eq!(
+        Some(2),
+        Targeting::MostGarbage.pick(0, &players, &teams, None, 0)
+    );
+    assert_eq!(
+        Some(1),
+        Targeting::Badges.pick(0, &players, &teams, None, 0)
+    );
+    assert_eq!(
+        Some(3),
+        Targeting::Attacker.pick(0, &players, &teams, Some(3), 0)
+    );
+    for roll in 0..10 {
+        assert_ne!(
+            Some(0),
+            Targeting::Random.pick(0, &players, &teams, None, roll)
+        );
+    }
+    assert_eq!(Some(Targeting::Badges), Targeting::parse("badges"));
+    assert_eq!(Targeting::Random, Targeting::Badges.next());
+}
+
+#[test]
+fn teams_share_garbage_and_lose_together() {
+    let teams = teams::assign(2, 4);
+    assert_eq!(vec![0, 1, 0, 1], teams);
+    assert!(teams::is_team_match(&teams));
+    assert!(!teams::is_team_match(&teams::assign(0, 4)));
+    let mut players: Vec<Player> = (0..4)
+        .map(|seed| Player::new(5, seed, 60, RandomizerKind::Bag7))
+        .collect();
+    for roll in 0..10 {
+        let target = Targeting::Random.pick(0, &players, &teams, None, roll);
+        assert!(
+            target == Some(1) || target == Some(3),
+            "a teammate was attacked"
+        );
+    }
+
+    // the player it was sent to gets the line left over
+    let attack = Attack {
+        lines: 3,
+        hole: 0,
+        delay: 6,
+    };
+    let receivers = teams::receivers(3, &players, &teams, true, |p| p);
+    assert_eq!(vec![3, 1], receivers);
+    let shares: Vec<Option<u8>> = teams::share(attack, 2)
+        .iter()
+        .map(|share| share.map(|attack| attack.lines))
+        .collect();
+    assert_eq!(vec![Some(2), Some(1)], shares);
+    assert_eq!(vec![3], teams::receivers(3, &players, &teams, false, |p| p));
+
+    // a team is out once all of its players are
+    let top_out = |player: &mut Player| {
+        while !player.get_lost() {
+            player.apply_move(Move::HardDrop);
+            player.update();
+        }
+    };
+    top_out(&mut players[1]);
+    assert!(!teams::decided(&players, &teams));
+    assert_eq!(vec![3], teams::receivers(3, &players, &teams, true, |p| p));
+    top_out(&mut players[3]);
+    assert!(teams::decided(&players, &teams));
+    assert_eq!(Some(0), teams::winner(&players, &teams));
+}
+
+#[test]
+fn rematch_needs_both_players() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        grace_period: Duration::from_secs(5),
+        best_of: 3,
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(19, config);
+    let mut client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+    let run = |host: &mut Game, client: &mut Game, ms| {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(ms) {
+            host.update();
+            client.update();
+            thread::sleep(Duration::from_millis(2));
+        }
+    };
+
+    // asking before the match is over does nothing
+    client.restart(19);
+    assert_eq!([false, false], client.get_rematch());
+
+    // the host stacks pieces until it tops out
+    let start = Instant::now();
+    while !(host.match_over() && client.match_over()) && start.elapsed() < Duration::from_secs(10) {
+        host.key_down(KeyCode::W);
+        run(&mut host, &mut client, 10);
+    }
+    assert_eq!(vec![true, false], host.get_losts());
+    assert_eq!(vec![false, true], client.get_losts());
+    assert_eq!([0, 1], host.get_wins());
+    assert_eq!([1, 0], client.get_wins());
+
+    let old_seed = host.settings.seed;
+    client.restart(19);
+    run(&mut host, &mut client, 100);
+    assert_eq!([false, true], host.get_rematch());
+    assert_eq!(
+        host.get_losts(),
+        [true, false],
+        "one player can't start the rematch"
+    );
+
+    host.restart(19);
+    run(&mut host, &mut client, 300);
+    assert_eq!(vec![false, false], host.get_losts());
+    assert_eq!(vec![false, false], client.get_losts());
+    assert_eq!(host.match_start, client.match_start);
+    assert_eq!(host.settings, client.settings);
+    assert_ne!(old_seed, host.settings.seed);
+    assert_eq!([0, 1], host.get_wins(), "the series goes on");
+    assert_eq!(None, host.get_series_winner());
+
+    while host.tick != client.tick {
+        if host.tick < client.tick {
+            host.update();
+        } else {
+            client.update();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(host.get_boards()[0], client.get_boards()[1]);
+    assert_eq!(host.get_boards()[1], client.get_boards()[0]);
+}
+
+#[test]
+fn chat_is_limited() {
+    let config = NetConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(500),
+        ..NetConfig::default()
+    };
+    let (adress, host) = spawn_host(5, config);
+    let mut client = Game::connect(&adress, "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+
+    client.send_chat(&"a".repeat(500)).unwrap();
+    client.send_chat("  gg\n").unwrap();
+    for _ in 0..chat::RATE_LIMIT - 2 {
+        client.send_chat("ez").unwrap();
+    }
+    assert!(client.send_chat("too fast").is_err());
+    let start = Instant::now();
+    while host.get_chat().count() < chat::RATE_LIMIT && start.elapsed() < Duration::from_secs(2) {
+        host.update();
+        thread::sleep(Duration::from_millis(5));
+    }
+    let lines: Vec<_> = host.get_chat().collect();
+    assert_eq!(chat::RATE_LIMIT, lines.len());
+    assert_eq!("Client", lines[0].name);
+    assert_eq!(chat::MAX_CHAT_LEN, lines[0].text.len());
+    assert_eq!("gg", lines[1].text);
+    assert_eq!(
+        client.get_chat().collect::<Vec<_>>(),
+        lines,
+        "we see what we sent"
+    );
+
+    // nobody to talk to
+    let mut local = Game::start_mode(Mode::Local, &Setup::default()).unwrap();
+    assert!(local.send_chat("hello?").is_err());
+}
+
+#[test]
+fn local_match_plays_both_keyboards() {
+    let mut game = Game::start_mode(Mode::Local, &Setup::default()).unwrap();
+    assert!(game.is_local());
+    game.key_down(KeyCode::W);
+    game.key_down(KeyCode::I);
+    // the dropped pieces lock on the next time they fall
+    for _ in 0..120 {
+        assert!(game.step());
+    }
+    for player in &game.players {
+        assert!(player.get_board().iter().flatten().any(|block| *block != 0));
+    }
+}
+
+#[test]
+fn ai_match_ignores_the_keyboard() {
+    let setup = Setup {
+        ai: [None, Some(DEFAULT_AI.to_string())],
+        ..Setup::default()
+    };
+    let mut game = Game::start_mode(Mode::Local, &setup).unwrap();
+    game.key_down(KeyCode::I);
+    assert!(game.local_moves[1].is_empty());
+    for _ in 0..100 {
+        assert!(game.step());
+    }
+
+    let setup = Setup {
+        ai: [Some("missing.so".to_string()), None],
+        ..Setup::default()
+    };
+    assert!(Game::start_mode(Mode::Local, &setup).is_err());
+}
+
+#[test]
+fn replay_plays_the_same_match() {
+    let path = "test-replay.ttr";
+    let setup = Setup {
+        ai: [Some(DEFAULT_AI.to_string()), None],
+        seed: Some(7),
+        record: Some(path.to_string()),
+        ..Setup::default()
+    };
+    let mut game = Game::start_mode(Mode::Local, &setup).unwrap();
+    // played like the app does, until someone loses
+    let mut ticks = 0;
+    while !game.get_losts().contains(&true) {
+        if ticks % 40 == 0 {
+            game.key_down(KeyCode::L);
+            game.key_down(KeyCode::I);
+        }
+        game.step();
+        ticks += 1;
+    }
+    let boards = game.get_boards();
+    // saved when the game is dropped
+    drop(game);
+
+    let mut replay = Game::start_mode(Mode::Replay(path.to_string()), &Setup::default()).unwrap();
+    for _ in 0..ticks {
+        replay.step();
+    }
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(boards, replay.get_boards());
+    assert_eq!(&["AI 1".to_string(), "P2".to_string()], replay.get_names());
+}
+
+#[test]
+fn key_bindings_file() {
+    let bindings =
+        KeyBindings::parse("# arrows for P1\np1.left = Left\np1.hard-drop=up\n").unwrap();
+    assert_eq!(Some((0, Move::Left)), bindings.get(KeyCode::Left));
+    assert_eq!(Some((0, Move::HardDrop)), bindings.get(KeyCode::Up));
+    assert_eq!(None, bindings.get(KeyCode::A));
+    assert_eq!(Some((1, Move::Left)), bindings.get(KeyCode::J));
+
+    assert!(KeyBindings::parse("p3.left = A").is_err());
+    assert!(KeyBindings::parse("p1.jump = A").is_err());
+    assert!(KeyBindings::parse("p1.left = Banana").is_err());
+    assert!(KeyBindings::parse("p1.left A").is_err());
 }
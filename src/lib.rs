@@ -0,0 +1,4 @@
+pub mod app;
+pub mod cli;
+pub mod game_data;
+pub mod game_state;
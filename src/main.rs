@@ -1,12 +1,36 @@
-mod app;
-mod game_data;
-mod game_state;
-
-use app::{AppState, SCREEN_SIZE};
 use ggez::event;
+use isaklar_maltebl_game::app::{AppState, SCREEN_SIZE};
+use isaklar_maltebl_game::cli;
+use isaklar_maltebl_game::game_state::Game;
+use std::env;
 use std::path;
+use std::process;
 
 fn main() {
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    if options.help {
+        print!("{}", cli::USAGE);
+        return;
+    }
+    cli::init_logging(options.log_level);
+    // the match is started before the window opens, so a host waiting for an opponent doesn't hang an empty window
+    let game = match options.mode {
+        Some(mode) => match Game::start_mode(mode, &options.setup) {
+            Ok(game) => Some(game),
+            Err(err) => {
+                eprintln!("error: {}", err);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     let resource_dir = path::PathBuf::from("./resources");
     let context_builder = ggez::ContextBuilder::new("tetris", "malte och isak")
         .add_resource_path(resource_dir)
@@ -19,7 +43,7 @@ fn main() {
 
     let (contex, event_loop) = &mut context_builder.build().expect("context builder error");
 
-    let state = &mut AppState::new(contex);
+    let state = &mut AppState::new(contex, options.setup, game);
 
     event::run(contex, event_loop, state);
 }
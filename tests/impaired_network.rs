@@ -0,0 +1,114 @@
+use ggez::event::KeyCode;
+use isaklar_maltebl_game::game_state::connection::NetConfig;
+use isaklar_maltebl_game::game_state::impairment::{Impairment, Proxy};
+use isaklar_maltebl_game::game_state::Game;
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Hosts on a port picked by the OS, connects the client through a proxy with the given impairment,
+/// plays for a while with both players making moves and checks that both games end up the same
+fn play_through(impairment: Impairment, config: NetConfig) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let adress = listener.local_addr().unwrap().to_string();
+    let host = thread::spawn(move || Game::host_listening(listener, 5, "Host".into(), config));
+    let proxy = Proxy::start(&adress, impairment).unwrap();
+    let mut client = Game::connect(&proxy.get_adress(), "Client".into(), config).unwrap();
+    let mut host = host.join().unwrap().unwrap();
+
+    let host_keys = [KeyCode::A, KeyCode::W, KeyCode::Space, KeyCode::E];
+    let client_keys = [KeyCode::D, KeyCode::Space, KeyCode::Q, KeyCode::D];
+    let start = Instant::now();
+    let mut presses = 0;
+    while start.elapsed() < Duration::from_secs(3) {
+        if start.elapsed() > Duration::from_millis(presses * 60) {
+            let n = presses as usize;
+            host.key_down(host_keys[n % host_keys.len()]);
+            client.key_down(client_keys[(n / 2) % client_keys.len()]);
+            presses += 1;
+        }
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(2));
+    }
+    // let every move arrive
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(1) {
+        host.update();
+        client.update();
+        thread::sleep(Duration::from_millis(2));
+    }
+    while host.get_tick() != client.get_tick() {
+        if host.get_tick() < client.get_tick() {
+            host.update();
+        } else {
+            client.update();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    assert!(host.get_tick() > 60, "the match barely moved");
+    assert_eq!(None, host.get_disconnected());
+    assert_eq!(None, client.get_disconnected());
+    assert_eq!(None, host.get_last_desync());
+    assert_eq!(None, client.get_last_desync());
+    let swap = |mut scores: Vec<u32>| {
+        scores.reverse();
+        scores
+    };
+    assert_eq!(host.get_boards()[0], client.get_boards()[1]);
+    assert_eq!(host.get_boards()[1], client.get_boards()[0]);
+    assert_eq!(host.get_next_pieces()[0], client.get_next_pieces()[1]);
+    assert_eq!(host.get_saved_pieces()[0], client.get_saved_pieces()[1]);
+    assert_eq!(host.get_scores(), swap(client.get_scores()));
+    assert_eq!(host.get_attackbars(), swap(client.get_attackbars()));
+}
+
+fn config(rollback: bool) -> NetConfig {
+    NetConfig {
+        heartbeat_interval: Duration::from_millis(100),
+        timeout: Duration::from_secs(2),
+        checksum_interval: 30,
+        resync_on_desync: false,
+        rollback,
+        ..NetConfig::default()
+    }
+}
+
+#[test]
+fn lockstep_over_a_slow_link() {
+    let impairment = Impairment {
+        latency: Duration::from_millis(40),
+        jitter: Duration::from_millis(30),
+        loss: 0.05,
+        bandwidth: Some(20_000),
+        seed: 1,
+        ..Impairment::default()
+    };
+    play_through(impairment, config(false));
+}
+
+#[test]
+fn rollback_over_a_lossy_link() {
+    let impairment = Impairment {
+        latency: Duration::from_millis(30),
+        jitter: Duration::from_millis(40),
+        loss: 0.2,
+        reorder: 0.1,
+        seed: 2,
+        ..Impairment::default()
+    };
+    play_through(impairment, config(true));
+}
+
+#[test]
+fn rollback_when_udp_is_lost_entirely() {
+    // every packet is lost, the moves only get through over tcp
+    let impairment = Impairment {
+        latency: Duration::from_millis(20),
+        loss: 1.0,
+        seed: 3,
+        ..Impairment::default()
+    };
+    play_through(impairment, config(true));
+}